@@ -0,0 +1,290 @@
+//! 连接池与写入、管理接口
+
+use crate::connection::Connection;
+use crate::consumer::Consumer;
+use crate::error::{ClientError, Result};
+use delay_message_rs::message::PROP_DELAY_MS;
+use delay_message_rs::protocol::{PutAck, PutMessage, Request, Response};
+use delay_message_rs::topic::{TopicConfig, TopicInfo};
+use log::warn;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::{sleep, timeout};
+
+/// 客户端设置
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    /// 服务端地址，如 127.0.0.1:9997
+    pub addr: String,
+    /// 连接池的连接数
+    pub pool_size: usize,
+    /// 建立连接的超时时间
+    pub connect_timeout: Duration,
+    /// 等待响应的超时时间，超时后连接将被关闭
+    pub request_timeout: Duration,
+    /// 重新连接的初始间隔，每次失败后翻倍
+    pub reconnect_backoff: Duration,
+    /// 重新连接的最大间隔
+    pub max_reconnect_backoff: Duration,
+    /// 连接异常时请求的重试次数
+    pub retries: u32,
+    /// 消费者空闲时发送心跳的间隔，需要小于服务端的 heartbeat_timeout
+    pub heartbeat_interval: Duration,
+}
+
+impl ClientOptions {
+    /// 使用默认设置连接 addr
+    pub fn new(addr: impl Into<String>) -> Self {
+        ClientOptions {
+            addr: addr.into(),
+            pool_size: 4,
+            connect_timeout: Duration::from_secs(3),
+            request_timeout: Duration::from_secs(10),
+            reconnect_backoff: Duration::from_millis(100),
+            max_reconnect_backoff: Duration::from_secs(5),
+            retries: 3,
+            heartbeat_interval: Duration::from_secs(10),
+        }
+    }
+
+    /// 第 attempt 次重试前等待的时间
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        self.reconnect_backoff
+            .saturating_mul(1 << attempt.min(16))
+            .min(self.max_reconnect_backoff)
+    }
+}
+
+/// 客户端，可以廉价地克隆并在多个任务间共享
+#[derive(Clone)]
+pub struct Client {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    options: ClientOptions,
+    /// 连接池，连接在第一次使用时建立，异常后置空
+    pool: Vec<Mutex<Option<Connection>>>,
+    /// 下一个使用的连接
+    next: AtomicUsize,
+}
+
+impl Client {
+    /// 创建客户端并建立一个连接，用于确认服务端可用
+    pub async fn connect(options: ClientOptions) -> Result<Client> {
+        let client = Client::new(options);
+        let connection = Connection::connect(
+            &client.inner.options.addr,
+            client.inner.options.connect_timeout,
+        )
+        .await?;
+        *client.inner.pool[0].lock().await = Some(connection);
+        Ok(client)
+    }
+
+    /// 创建客户端，连接在第一次请求时建立
+    pub fn new(options: ClientOptions) -> Client {
+        let pool = (0..options.pool_size.max(1))
+            .map(|_| Mutex::new(None))
+            .collect();
+        Client {
+            inner: Arc::new(Inner {
+                options,
+                pool,
+                next: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// 客户端设置
+    pub fn options(&self) -> &ClientOptions {
+        &self.inner.options
+    }
+
+    /// 写入一条消息，延迟时间通过 prop 中的延迟属性指定，返回消息的物理偏移量
+    pub async fn put(&self, topic: &str, body: &str, prop: &str) -> Result<u64> {
+        let request = Request::Put {
+            topic: topic.to_string(),
+            body: body.to_string(),
+            prop: prop.to_string(),
+        };
+        match self.request(request).await? {
+            Response::Put { physical_offset } => Ok(physical_offset),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// 写入一条延迟 delay 后投递的消息，返回消息的物理偏移量，用于取消
+    pub async fn put_delayed(&self, topic: &str, body: &str, delay: Duration) -> Result<u64> {
+        let prop = format!("{PROP_DELAY_MS}-{}", delay.as_millis());
+        self.put(topic, body, &prop).await
+    }
+
+    /// 批量写入消息，返回每条消息的写入结果
+    pub async fn put_batch(&self, messages: Vec<PutMessage>) -> Result<Vec<PutAck>> {
+        match self.request(Request::PutBatch { messages }).await? {
+            Response::PutBatch { acks } => Ok(acks),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// 取消一条未到期的延迟消息
+    pub async fn cancel(&self, physical_offset: u64) -> Result<()> {
+        self.request_ok(Request::Cancel { physical_offset }).await
+    }
+
+    /// 创建 topic
+    pub async fn create_topic(&self, topic: &str, config: TopicConfig) -> Result<()> {
+        let topic = topic.to_string();
+        self.request_ok(Request::CreateTopic { topic, config })
+            .await
+    }
+
+    /// 查看 topic 的设置与消息数量
+    pub async fn describe_topic(&self, topic: &str) -> Result<TopicInfo> {
+        let topic = topic.to_string();
+        match self.request(Request::DescribeTopic { topic }).await? {
+            Response::Topics { mut topics } if topics.len() == 1 => Ok(topics.remove(0)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// 列出所有 topic
+    pub async fn list_topics(&self) -> Result<Vec<TopicInfo>> {
+        match self.request(Request::ListTopics).await? {
+            Response::Topics { topics } => Ok(topics),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// 删除 topic
+    pub async fn delete_topic(&self, topic: &str) -> Result<()> {
+        let topic = topic.to_string();
+        self.request_ok(Request::DeleteTopic { topic }).await
+    }
+
+    /// 创建消费者组 group 中的消费者，消费者使用独立的连接
+    pub fn consumer(&self, group: &str) -> Consumer {
+        Consumer::new(self.inner.options.clone(), group)
+    }
+
+    /// 发送响应为 ok 的请求
+    async fn request_ok(&self, request: Request) -> Result<()> {
+        match self.request(request).await? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// 轮询选择连接池中的连接发送请求，连接异常时重新连接并重试
+    pub(crate) async fn request(&self, request: Request) -> Result<Response> {
+        let inner = &self.inner;
+        let index = inner.next.fetch_add(1, Ordering::Relaxed) % inner.pool.len();
+        let mut slot = inner.pool[index].lock().await;
+        let mut attempt = 0;
+        loop {
+            match request_on(&mut slot, &inner.options, &request).await {
+                Err(err) if err.is_connection_error() && attempt < inner.options.retries => {
+                    *slot = None;
+                    let backoff = inner.options.backoff(attempt);
+                    warn!(
+                        "连接[{}]异常，{backoff:?} 后重试：{err}",
+                        inner.options.addr
+                    );
+                    attempt += 1;
+                    sleep(backoff).await;
+                }
+                Err(err) if err.is_connection_error() => {
+                    *slot = None;
+                    return Err(err);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// 在连接上发送请求，连接不存在时先建立连接
+pub(crate) async fn request_on(
+    slot: &mut Option<Connection>,
+    options: &ClientOptions,
+    request: &Request,
+) -> Result<Response> {
+    if slot.is_none() {
+        *slot = Some(Connection::connect(&options.addr, options.connect_timeout).await?);
+    }
+    let connection = slot.as_mut().ok_or(ClientError::Closed)?;
+    timeout(options.request_timeout, connection.request(request))
+        .await
+        .map_err(|_| ClientError::Timeout)?
+}
+
+/// 与请求不对应的响应
+pub(crate) fn unexpected(response: Response) -> ClientError {
+    ClientError::Protocol(format!("意外的响应：{response:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::{Client, ClientOptions};
+    use crate::error::ClientError;
+    use crate::tests::start_server;
+    use delay_message_rs::protocol::{PutAck, PutMessage};
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_put_batch_cancel() {
+        let addr = start_server("put_batch_cancel", "127.0.0.1:0").await;
+        let client = Client::connect(ClientOptions::new(addr)).await.unwrap();
+
+        let id = client
+            .put_delayed("topic_client", "hello", Duration::from_secs(60))
+            .await
+            .unwrap();
+        client.cancel(id).await.unwrap();
+        assert!(matches!(
+            client.cancel(id).await,
+            Err(ClientError::Server(_))
+        ));
+
+        let message = |prop: &str| PutMessage {
+            topic: "topic_client".to_string(),
+            body: "batch".to_string(),
+            prop: prop.to_string(),
+        };
+        let acks = client
+            .put_batch(vec![message("_delay-1"), message(""), message("_delay-1")])
+            .await
+            .unwrap();
+        assert_eq!(acks.len(), 3);
+        assert!(matches!(acks[0], PutAck::Ok { .. }));
+        assert!(matches!(acks[1], PutAck::Error { .. }));
+        assert!(matches!(acks[2], PutAck::Ok { .. }));
+
+        let info = client.describe_topic("topic_client").await.unwrap();
+        assert_eq!(info.pending, 2);
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() {
+        // 先占用一个空闲端口，客户端开始请求后才启动服务端
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let client = Client::new(ClientOptions::new(&addr));
+        let server = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            start_server("reconnect", &addr).await
+        });
+        client
+            .put("topic_client", "hello", "_delay-1")
+            .await
+            .unwrap();
+        server.await.unwrap();
+    }
+}
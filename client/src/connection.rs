@@ -0,0 +1,65 @@
+//! 与服务端的一个连接，按行发送请求并读取响应
+
+use crate::error::{ClientError, Result};
+use delay_message_rs::protocol::{Request, Response};
+use delay_message_rs::ready_queue::Delivery;
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+/// 与服务端的一个连接
+pub(crate) struct Connection {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+    /// 等待响应时收到的订阅推送
+    pushed: VecDeque<Vec<Delivery>>,
+}
+
+impl Connection {
+    /// 连接服务端
+    pub(crate) async fn connect(addr: &str, connect_timeout: Duration) -> Result<Self> {
+        let socket = timeout(connect_timeout, TcpStream::connect(addr))
+            .await
+            .map_err(|_| ClientError::Timeout)??;
+        socket.set_nodelay(true)?;
+        let (reader, writer) = socket.into_split();
+        Ok(Connection {
+            lines: BufReader::new(reader).lines(),
+            writer,
+            pushed: VecDeque::new(),
+        })
+    }
+
+    /// 发送请求并等待响应，等待期间收到的推送暂存，服务端返回的错误转换为 [`ClientError::Server`]
+    pub(crate) async fn request(&mut self, request: &Request) -> Result<Response> {
+        self.writer.write_all(request.to_line().as_bytes()).await?;
+        loop {
+            match self.read().await? {
+                Response::Push { deliveries } => self.pushed.push_back(deliveries),
+                Response::Error { message } => return Err(ClientError::Server(message)),
+                response => return Ok(response),
+            }
+        }
+    }
+
+    /// 等待下一批订阅推送的消息，可以安全地取消
+    pub(crate) async fn push(&mut self) -> Result<Vec<Delivery>> {
+        if let Some(deliveries) = self.pushed.pop_front() {
+            return Ok(deliveries);
+        }
+        match self.read().await? {
+            Response::Push { deliveries } => Ok(deliveries),
+            response => Err(ClientError::Protocol(format!("意外的响应：{response:?}"))),
+        }
+    }
+
+    /// 读取一行响应
+    async fn read(&mut self) -> Result<Response> {
+        let line = self.lines.next_line().await?.ok_or(ClientError::Closed)?;
+        serde_json::from_str(&line)
+            .map_err(|err| ClientError::Protocol(format!("无法解析响应 {line}：{err}")))
+    }
+}
@@ -0,0 +1,317 @@
+//! 消费者，独占一个连接加入消费者组，拉取或订阅到期消息并确认
+
+use crate::client::{request_on, unexpected, ClientOptions};
+use crate::connection::Connection;
+use crate::error::{ClientError, Result};
+use delay_message_rs::consumer_group::ConsumeMode;
+use delay_message_rs::protocol::{Request, Response};
+use delay_message_rs::ready_queue::Delivery;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::time::Instant;
+use tokio::time::{sleep, timeout};
+
+/// 订阅的设置，重新连接后恢复
+#[derive(Debug, Clone)]
+struct Subscription {
+    credit: usize,
+    tags: String,
+}
+
+/// 消费者组中的一个消费者
+///
+/// 连接断开后在下一次操作时重新连接，使用第一次注册时的 client_id 重新加入消费者组并恢复订阅
+///
+/// 断线前未确认的消息由服务端释放给组内的其他消费者，确认这些消息依然有效
+pub struct Consumer {
+    options: ClientOptions,
+    group: String,
+    mode: ConsumeMode,
+    /// 未指定时使用服务端分配的 client_id
+    client_id: Option<String>,
+    connection: Option<Connection>,
+    /// 订阅的 topic -> 订阅
+    subscriptions: HashMap<String, Subscription>,
+    /// 最后一次请求的时间，空闲超过心跳间隔时先发送心跳
+    last_active: Instant,
+}
+
+impl Consumer {
+    /// 创建消费者，连接在第一次操作时建立，默认为集群模式
+    pub fn new(options: ClientOptions, group: &str) -> Self {
+        Consumer {
+            options,
+            group: group.to_string(),
+            mode: ConsumeMode::default(),
+            client_id: None,
+            connection: None,
+            subscriptions: HashMap::new(),
+            last_active: Instant::now(),
+        }
+    }
+
+    /// 指定消费模式
+    pub fn with_mode(mut self, mode: ConsumeMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// 指定 client_id，广播模式下必须指定，重新启动时需要使用相同的 client_id
+    pub fn with_client_id(mut self, client_id: &str) -> Self {
+        self.client_id = Some(client_id.to_string());
+        self
+    }
+
+    /// 在消费者组中的 client_id，加入消费者组之前为 None
+    pub fn client_id(&self) -> Option<&str> {
+        self.client_id.as_deref()
+    }
+
+    /// 拉取 topic 最多 max 条到期消息，tags 为 tag 表达式，为空时不过滤
+    pub async fn pull(&mut self, topic: &str, max: usize, tags: &str) -> Result<Vec<Delivery>> {
+        let request = Request::Pull {
+            topic: topic.to_string(),
+            max,
+            tags: tags.to_string(),
+        };
+        match self.request(request).await? {
+            Response::Messages { deliveries } => Ok(deliveries),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// 订阅 topic，服务端最多推送 credit 条未确认的消息，推送的消息通过 [`Consumer::next`] 获取
+    pub async fn subscribe(&mut self, topic: &str, credit: usize, tags: &str) -> Result<()> {
+        let subscription = Subscription {
+            credit,
+            tags: tags.to_string(),
+        };
+        self.request_ok(subscribe_request(topic, &subscription))
+            .await?;
+        self.subscriptions.insert(topic.to_string(), subscription);
+        Ok(())
+    }
+
+    /// 取消订阅
+    pub async fn unsubscribe(&mut self, topic: &str) -> Result<()> {
+        self.subscriptions.remove(topic);
+        let topic = topic.to_string();
+        self.request_ok(Request::Unsubscribe { topic }).await
+    }
+
+    /// 等待下一批订阅推送的消息，等待期间定时发送心跳，连接断开时自动重连
+    pub async fn next(&mut self) -> Result<Vec<Delivery>> {
+        let mut attempt = 0;
+        loop {
+            if let Err(err) = self.ensure_member().await {
+                self.reconnect_wait(err, &mut attempt).await?;
+                continue;
+            }
+            let Some(connection) = self.connection.as_mut() else {
+                continue;
+            };
+            match timeout(self.options.heartbeat_interval, connection.push()).await {
+                Ok(Ok(deliveries)) => return Ok(deliveries),
+                Ok(Err(err)) => self.reconnect_wait(err, &mut attempt).await?,
+                // 空闲时间达到心跳间隔，下一次循环发送心跳
+                Err(_) => {}
+            }
+        }
+    }
+
+    /// 确认消息
+    pub async fn ack(&mut self, delivery: &Delivery) -> Result<()> {
+        self.request_ok(Request::Ack {
+            topic: delivery.topic.clone(),
+            queue_id: delivery.queue_id,
+            queue_offset: delivery.queue_offset,
+        })
+        .await
+    }
+
+    /// 拒绝消息，消息将延迟后重新投递，reason 作为失败原因记录
+    pub async fn nack(&mut self, delivery: &Delivery, reason: &str) -> Result<()> {
+        self.request_ok(Request::Nack {
+            topic: delivery.topic.clone(),
+            queue_id: delivery.queue_id,
+            queue_offset: delivery.queue_offset,
+            reason: reason.to_string(),
+        })
+        .await
+    }
+
+    /// 关闭连接，服务端将释放未确认的消息
+    pub fn close(&mut self) {
+        self.connection = None;
+    }
+
+    /// 发送响应为 ok 的请求
+    async fn request_ok(&mut self, request: Request) -> Result<()> {
+        match self.request(request).await? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// 以消费者组成员的身份发送请求，连接异常时重新连接并重试
+    async fn request(&mut self, request: Request) -> Result<Response> {
+        let mut attempt = 0;
+        loop {
+            let result = match self.ensure_member().await {
+                Ok(_) => request_on(&mut self.connection, &self.options, &request).await,
+                Err(err) => Err(err),
+            };
+            match result {
+                Err(err) if err.is_connection_error() => {
+                    self.reconnect_wait(err, &mut attempt).await?;
+                }
+                result => {
+                    self.last_active = Instant::now();
+                    return result;
+                }
+            }
+        }
+    }
+
+    /// 连接异常后关闭连接并等待退避间隔，超过重试次数时返回错误
+    async fn reconnect_wait(&mut self, err: ClientError, attempt: &mut u32) -> Result<()> {
+        self.connection = None;
+        if *attempt >= self.options.retries {
+            return Err(err);
+        }
+        let backoff = self.options.backoff(*attempt);
+        warn!(
+            "消费者连接[{}]异常，{backoff:?} 后重连：{err}",
+            self.options.addr
+        );
+        *attempt += 1;
+        sleep(backoff).await;
+        Ok(())
+    }
+
+    /// 保证连接已加入消费者组，空闲超过心跳间隔时发送心跳，已被移出消费者组时重新加入
+    async fn ensure_member(&mut self) -> Result<()> {
+        if self.connection.is_none() {
+            return self.join().await;
+        }
+        if self.last_active.elapsed() < self.options.heartbeat_interval {
+            return Ok(());
+        }
+        match request_on(&mut self.connection, &self.options, &Request::Heartbeat).await {
+            Ok(_) => {
+                self.last_active = Instant::now();
+                Ok(())
+            }
+            Err(ClientError::Server(message)) => {
+                warn!(
+                    "消费者已被移出消费者组[{}]，重新加入：{message}",
+                    self.group
+                );
+                self.join().await
+            }
+            Err(err) => {
+                self.connection = None;
+                Err(err)
+            }
+        }
+    }
+
+    /// 加入消费者组并恢复订阅
+    async fn join(&mut self) -> Result<()> {
+        let result = async {
+            let register = Request::Register {
+                group: self.group.clone(),
+                client_id: self.client_id.clone(),
+                mode: self.mode,
+            };
+            let client_id = match request_on(&mut self.connection, &self.options, &register).await?
+            {
+                Response::Registered { client_id } => client_id,
+                response => return Err(unexpected(response)),
+            };
+            info!("消费者[{client_id}]加入消费者组[{}]", self.group);
+            self.client_id = Some(client_id);
+            for (topic, subscription) in &self.subscriptions {
+                let request = subscribe_request(topic, subscription);
+                request_on(&mut self.connection, &self.options, &request).await?;
+            }
+            self.last_active = Instant::now();
+            Ok(())
+        }
+        .await;
+        if result.is_err() {
+            self.connection = None;
+        }
+        result
+    }
+}
+
+/// 订阅请求
+fn subscribe_request(topic: &str, subscription: &Subscription) -> Request {
+    Request::Subscribe {
+        topic: topic.to_string(),
+        credit: subscription.credit,
+        tags: subscription.tags.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::{Client, ClientOptions};
+    use crate::tests::start_server;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_pull_ack() {
+        let addr = start_server("pull_ack", "127.0.0.1:0").await;
+        let client = Client::new(ClientOptions::new(addr));
+        let id = client
+            .put_delayed("topic_pull", "hello", Duration::from_millis(100))
+            .await
+            .unwrap();
+
+        let mut consumer = client.consumer("group_pull");
+        let deliveries = loop {
+            let deliveries = consumer.pull("topic_pull", 10, "").await.unwrap();
+            if !deliveries.is_empty() {
+                break deliveries;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        };
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].message.physical_offset, id);
+        consumer.ack(&deliveries[0]).await.unwrap();
+
+        // 重新连接后使用相同的 client_id 加入消费者组，已确认的消息不再投递
+        let client_id = consumer.client_id().unwrap().to_string();
+        consumer.close();
+        assert!(consumer
+            .pull("topic_pull", 10, "")
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(consumer.client_id(), Some(client_id.as_str()));
+    }
+
+    #[tokio::test]
+    async fn test_subscribe() {
+        let addr = start_server("subscribe", "127.0.0.1:0").await;
+        let client = Client::new(ClientOptions::new(addr));
+        let mut consumer = client.consumer("group_subscribe");
+        consumer.subscribe("topic_push", 8, "").await.unwrap();
+        // 断线后恢复订阅
+        consumer.close();
+        client
+            .put_delayed("topic_push", "hello", Duration::from_millis(100))
+            .await
+            .unwrap();
+
+        let deliveries = tokio::time::timeout(Duration::from_secs(3), consumer.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].message.body(), "hello");
+        consumer.ack(&deliveries[0]).await.unwrap();
+    }
+}
@@ -0,0 +1,32 @@
+//! 客户端错误类型
+
+use std::io;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("I/O 异常: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("服务端返回错误: {0}")]
+    Server(String),
+
+    #[error("协议错误: {0}")]
+    Protocol(String),
+
+    #[error("连接已关闭")]
+    Closed,
+
+    #[error("请求超时")]
+    Timeout,
+}
+
+impl ClientError {
+    /// 是否是连接异常，连接异常时需要重新连接
+    pub fn is_connection_error(&self) -> bool {
+        !matches!(self, ClientError::Server(_))
+    }
+}
+
+/// 使用客户端错误类型的 Result
+pub type Result<T, E = ClientError> = std::result::Result<T, E>;
@@ -0,0 +1,44 @@
+//! delay-message-rs 的异步客户端，使用服务端按行 JSON 的协议
+//!
+//! [`Client`] 持有连接池，用于写入、批量写入、取消延迟消息与管理 topic，连接异常时按退避间隔重新连接
+//!
+//! [`Consumer`] 独占一个连接加入消费者组，支持拉取与订阅推送，断线后自动重连并恢复注册与订阅
+//!
+//! 请求因连接异常重试时，写入请求可能已被服务端处理，消息保证至少写入一次
+
+mod client;
+mod connection;
+mod consumer;
+mod error;
+
+pub use client::{Client, ClientOptions};
+pub use consumer::Consumer;
+pub use delay_message_rs::consumer_group::ConsumeMode;
+pub use delay_message_rs::protocol::{PutAck, PutMessage};
+pub use delay_message_rs::ready_queue::Delivery;
+pub use delay_message_rs::topic::{TopicConfig, TopicInfo};
+pub use error::{ClientError, Result};
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use delay_message_rs::config::Config;
+    use delay_message_rs::connection::serve_listener;
+    use delay_message_rs::store::Store;
+    use delay_message_rs::Broker;
+    use tokio::net::TcpListener;
+
+    /// 在 addr 上启动进程内的服务端，数据目录为临时目录，返回监听的地址
+    pub(crate) async fn start_server(name: &str, addr: &str) -> String {
+        let config = Config::load(concat!(env!("CARGO_MANIFEST_DIR"), "/../conf.yaml")).unwrap();
+        let dir = std::env::temp_dir().join(format!(
+            "delay_message_client_{name}_{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = Store::open(config, &dir).await.unwrap();
+        let listener = TcpListener::bind(addr).await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(serve_listener(Broker::new(store), listener));
+        addr
+    }
+}
@@ -0,0 +1,216 @@
+use delay_message_rs::config::Config;
+use delay_message_rs::message::PROP_DELAY_MS;
+use delay_message_rs::protocol::{Request, Response};
+use delay_message_rs::time_util::{format_millis, now_millis, parse_millis};
+use delay_message_rs::topic::TopicInfo;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::process::exit;
+
+/// 启动参数说明
+const USAGE: &str = "用法：admin [-a|--addr <地址>] <命令> [参数]
+
+  -a, --addr  服务端地址，默认为 127.0.0.1 与配置文件中的 port
+
+命令：
+  send <topic> <延迟> [消息体]  发送一条测试延迟消息，延迟支持 ms s m h d 单位，如 500ms 10s
+  topics                      列出所有 topic
+  pending [topic]             查看 topic 未到期的消息数量与最早的到期时间
+  lookup <物理偏移量>         根据物理偏移量查看消息
+  cancel <物理偏移量>         取消未到期的延迟消息
+  stats                       查看 broker 的统计信息";
+
+/// 配置文件不可用时连接的端口
+const DEFAULT_PORT: u32 = 9997;
+/// 测试消息默认的消息体
+const TEST_BODY: &str = "test";
+/// 响应与请求不对应
+const UNEXPECTED: &str = "意外的响应";
+
+/// 启动参数
+#[derive(Debug)]
+struct Args {
+    addr: String,
+    command: Vec<String>,
+}
+
+impl Args {
+    /// 解析启动参数，参数错误时打印用法并退出
+    fn parse() -> Args {
+        let mut addr = None;
+        let mut command = Vec::new();
+        let mut iter = std::env::args().skip(1);
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "-a" | "--addr" => match iter.next() {
+                    Some(value) => addr = Some(value),
+                    None => usage_exit(&format!("参数 {arg} 缺少值")),
+                },
+                "-h" | "--help" => {
+                    println!("{USAGE}");
+                    exit(0);
+                }
+                _ => command.push(arg),
+            }
+        }
+        let addr = addr.unwrap_or_else(|| {
+            let port = Config::new().map_or(DEFAULT_PORT, |config| config.port);
+            format!("127.0.0.1:{port}")
+        });
+        Args { addr, command }
+    }
+}
+
+/// 打印错误与用法并退出
+fn usage_exit(message: &str) -> ! {
+    eprintln!("{message}\n{USAGE}");
+    exit(2);
+}
+
+/// 解析物理偏移量参数
+fn parse_offset(arg: Option<&String>) -> u64 {
+    match arg.map(|arg| arg.parse::<u64>()) {
+        Some(Ok(offset)) => offset,
+        _ => usage_exit("缺少物理偏移量或格式错误"),
+    }
+}
+
+/// 与服务端的连接，一次请求对应一行响应
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    fn connect(addr: &str) -> std::io::Result<Self> {
+        let writer = TcpStream::connect(addr)?;
+        Ok(Connection {
+            reader: BufReader::new(writer.try_clone()?),
+            writer,
+        })
+    }
+
+    /// 发送请求并读取响应，服务端返回错误时打印并退出
+    fn request(&mut self, request: &Request) -> Result<Response, Box<dyn std::error::Error>> {
+        self.writer.write_all(request.to_line().as_bytes())?;
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err("服务端关闭了连接".into());
+        }
+        match serde_json::from_str::<Response>(&line)? {
+            Response::Error { message } => {
+                eprintln!("请求失败：{message}");
+                exit(1);
+            }
+            response => Ok(response),
+        }
+    }
+}
+
+/// 最早到期时间的描述，包括距离现在的时间
+fn due_text(next_due: Option<u64>) -> String {
+    match next_due {
+        Some(due) => {
+            let remain = due.saturating_sub(now_millis());
+            format!("{}（{}ms 后）", format_millis(due), remain)
+        }
+        None => "-".to_string(),
+    }
+}
+
+/// 打印 topic 列表
+fn print_topics(topics: &[TopicInfo]) {
+    println!(
+        "{:<32} {:>6} {:>10} {:>10}  最早到期",
+        "topic", "队列", "未到期", "已到期"
+    );
+    for info in topics {
+        println!(
+            "{:<32} {:>6} {:>10} {:>10}  {}",
+            info.topic,
+            info.config.queue_count.unwrap_or_default(),
+            info.pending,
+            info.delivered,
+            due_text(info.next_due)
+        );
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    let Some((command, params)) = args.command.split_first() else {
+        usage_exit("缺少命令");
+    };
+    // 参数校验通过后再连接服务端，命令错误时不需要服务端可用
+    let connect = || {
+        Connection::connect(&args.addr)
+            .map_err(|err| format!("连接服务端[{}]失败：{err}", args.addr))
+    };
+    match command.as_str() {
+        "send" => {
+            let (Some(topic), Some(delay)) = (params.first(), params.get(1)) else {
+                usage_exit("send 需要 topic 与延迟");
+            };
+            let Some(delay) = parse_millis(delay) else {
+                usage_exit(&format!("延迟格式错误：{delay}"));
+            };
+            let body = params.get(2).map_or(TEST_BODY, String::as_str);
+            let request = Request::Put {
+                topic: topic.clone(),
+                body: body.to_string(),
+                prop: format!("{PROP_DELAY_MS}-{delay}"),
+            };
+            let mut connection = connect()?;
+            let Response::Put { physical_offset } = connection.request(&request)? else {
+                return Err(UNEXPECTED.into());
+            };
+            println!("已发送，物理偏移量：{physical_offset}");
+            println!("预计到期：{}", format_millis(now_millis() + delay));
+        }
+        "topics" | "pending" => {
+            let request = match params.first() {
+                Some(topic) if command == "pending" => Request::DescribeTopic {
+                    topic: topic.clone(),
+                },
+                _ => Request::ListTopics,
+            };
+            let mut connection = connect()?;
+            let Response::Topics { mut topics } = connection.request(&request)? else {
+                return Err(UNEXPECTED.into());
+            };
+            if command == "pending" {
+                topics.retain(|info| info.pending > 0 || !params.is_empty());
+                topics.sort_by_key(|info| info.next_due.unwrap_or(u64::MAX));
+            }
+            print_topics(&topics);
+        }
+        "lookup" => {
+            let physical_offset = parse_offset(params.first());
+            let request = Request::Lookup { physical_offset };
+            let mut connection = connect()?;
+            let Response::Message { message } = connection.request(&request)? else {
+                return Err(UNEXPECTED.into());
+            };
+            println!("{}", serde_json::to_string_pretty(&message)?);
+            println!("存储时间：{}", format_millis(message.store_timestamp()));
+        }
+        "cancel" => {
+            let physical_offset = parse_offset(params.first());
+            connect()?.request(&Request::Cancel { physical_offset })?;
+            println!("已取消：{physical_offset}");
+        }
+        "stats" => {
+            let Response::Stats { stats } = connect()?.request(&Request::Stats)? else {
+                return Err(UNEXPECTED.into());
+            };
+            println!("commit_log 写入位置：{}", stats.commit_log_offset);
+            println!("topic 数量：{}", stats.topics);
+            println!("未到期消息：{}", stats.pending);
+            println!("已到期消息：{}", stats.delivered);
+            println!("消费者组：{}", stats.groups);
+            println!("在线消费者：{}", stats.consumers);
+        }
+        _ => usage_exit(&format!("未知命令：{command}")),
+    }
+    Ok(())
+}
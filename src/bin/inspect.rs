@@ -0,0 +1,163 @@
+use delay_message_rs::config::Config;
+use delay_message_rs::consume_queue;
+use delay_message_rs::inspect::{self, RecordStatus};
+use std::path::Path;
+use std::process::exit;
+
+/// 启动参数说明
+const USAGE: &str = "用法：inspect [-c|--config <配置文件>] [-d|--data-dir <数据目录>] <命令> [参数]
+
+  -c, --config    配置文件路径，默认读取环境变量 DELAY_MESSAGE_CONF，未设置时为 ./conf.yaml
+  -d, --data-dir  数据目录，默认读取环境变量 DELAY_MESSAGE_DATA_DIR，未设置时使用配置文件中的 data_dir
+
+直接读取数据目录中的文件，每行输出一个 JSON，统计信息输出到标准错误
+
+命令：
+  log [物理偏移量]  输出 commit_log 中从物理偏移量开始的记录与 CRC 校验状态
+  queue <topic>     输出 topic 的 consume_queue 索引
+  check <topic>     检查 topic 的索引与 commit_log 是否一致，存在不一致时退出码为 1
+  verify [--repair] 检查所有记录的 CRC 与长度链以及所有队列的索引，存在问题时退出码为 1
+                    --repair 截断 commit_log 损坏的尾部并根据 commit_log 重新生成存在问题的队列，需要先停止服务端
+  rebuild [物理偏移量]
+                    根据 commit_log 中从物理偏移量开始的记录重新生成所有队列的索引，之前的索引保留，需要先停止服务端";
+
+/// 启动参数
+#[derive(Debug, Default)]
+struct Args {
+    config: Option<String>,
+    data_dir: Option<String>,
+    command: Vec<String>,
+}
+
+impl Args {
+    /// 解析启动参数，参数错误时打印用法并退出
+    fn parse() -> Args {
+        let mut args = Args::default();
+        let mut iter = std::env::args().skip(1);
+        while let Some(arg) = iter.next() {
+            let value = match arg.as_str() {
+                "-c" | "--config" => &mut args.config,
+                "-d" | "--data-dir" => &mut args.data_dir,
+                "-h" | "--help" => {
+                    println!("{USAGE}");
+                    exit(0);
+                }
+                _ => {
+                    args.command.push(arg);
+                    continue;
+                }
+            };
+            match iter.next() {
+                Some(v) => *value = Some(v),
+                None => usage_exit(&format!("参数 {arg} 缺少值")),
+            }
+        }
+        args
+    }
+}
+
+/// 打印错误与用法并退出
+fn usage_exit(message: &str) -> ! {
+    eprintln!("{message}\n{USAGE}");
+    exit(2);
+}
+
+/// 解析可选的物理偏移量参数，未指定时为 0
+fn parse_offset(arg: Option<&String>) -> u64 {
+    match arg.map(|arg| arg.parse::<u64>()) {
+        None => 0,
+        Some(Ok(offset)) => offset,
+        Some(Err(_)) => usage_exit("物理偏移量格式错误"),
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    let mut config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::new()?,
+    };
+    if let Some(data_dir) = args.data_dir {
+        config.data_dir = data_dir;
+    }
+    let dir = Path::new(&config.data_dir);
+    if !dir.exists() {
+        return Err(format!("数据目录不存在：{}", dir.display()).into());
+    }
+    let Some((command, params)) = args.command.split_first() else {
+        usage_exit("缺少命令");
+    };
+    match command.as_str() {
+        "log" => {
+            let from = parse_offset(params.first());
+            let (mut total, mut crc, mut corrupt) = (0, 0, 0);
+            inspect::walk_commit_log(dir, from, |record| {
+                total += 1;
+                match record.status {
+                    RecordStatus::Ok => {}
+                    RecordStatus::CrcMismatch => crc += 1,
+                    RecordStatus::Corrupt => corrupt += 1,
+                }
+                println!("{}", serde_json::to_string(&record).unwrap());
+            })?;
+            eprintln!("共 {total} 条记录，CRC 校验失败 {crc} 条，损坏 {corrupt} 条");
+        }
+        "queue" => {
+            let Some(topic) = params.first() else {
+                usage_exit("queue 需要 topic");
+            };
+            let entries = inspect::topic_entries(dir, topic)?;
+            for entry in &entries {
+                println!("{}", serde_json::to_string(entry)?);
+            }
+            let fired = entries.iter().filter(|entry| entry.fired).count();
+            eprintln!("共 {} 条索引，已到期或已取消 {fired} 条", entries.len());
+        }
+        "check" => {
+            let Some(topic) = params.first() else {
+                usage_exit("check 需要 topic");
+            };
+            let issues = inspect::check_topic(dir, topic)?;
+            for issue in &issues {
+                println!("{}", serde_json::to_string(issue)?);
+            }
+            eprintln!("共发现 {} 处不一致", issues.len());
+            if !issues.is_empty() {
+                exit(1);
+            }
+        }
+        "verify" => {
+            match params.first().map(String::as_str) {
+                None => {}
+                Some("--repair") => {
+                    let repair = inspect::repair(dir, &config)?;
+                    if let Some(offset) = repair.truncated {
+                        eprintln!("已截断 commit_log 尾部，物理偏移量：{offset}");
+                    }
+                    for queue in &repair.rebuilt {
+                        eprintln!("已重新生成队列：{queue}");
+                    }
+                }
+                Some(param) => usage_exit(&format!("未知参数：{param}")),
+            }
+            let issues = inspect::verify(dir)?;
+            for issue in &issues {
+                println!("{}", serde_json::to_string(issue)?);
+            }
+            eprintln!("共发现 {} 处问题", issues.len());
+            if !issues.is_empty() {
+                exit(1);
+            }
+        }
+        "rebuild" => {
+            let from = parse_offset(params.first());
+            let rebuilt = consume_queue::rebuild(dir, &config, from)?;
+            for (queue, count) in &rebuilt {
+                eprintln!("已重新生成队列：{queue}，索引 {count} 条");
+            }
+            eprintln!("共重新生成 {} 个队列", rebuilt.len());
+        }
+        _ => usage_exit(&format!("未知命令：{command}")),
+    }
+    Ok(())
+}
@@ -1,53 +1,71 @@
-use delay_message_rs::commit_log;
-use delay_message_rs::consume_queue;
+use delay_message_rs::config::Config;
+use delay_message_rs::connection;
 use delay_message_rs::log_util::log_init;
-use delay_message_rs::message::Message;
+use delay_message_rs::store::Store;
+use delay_message_rs::Broker;
 use log::info;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpListener;
+use std::process::exit;
+
+/// 启动参数说明
+const USAGE: &str = "用法：server [-c|--config <配置文件>] [-d|--data-dir <数据目录>]
+
+  -c, --config    配置文件路径，默认读取环境变量 DELAY_MESSAGE_CONF，未设置时为 ./conf.yaml
+  -d, --data-dir  数据目录，默认读取环境变量 DELAY_MESSAGE_DATA_DIR，未设置时使用配置文件中的 data_dir";
+
+/// 启动参数，优先于环境变量与配置文件
+#[derive(Debug, Default)]
+struct Args {
+    config: Option<String>,
+    data_dir: Option<String>,
+}
+
+impl Args {
+    /// 解析启动参数，参数错误时打印用法并退出
+    fn parse() -> Args {
+        let mut args = Args::default();
+        let mut iter = std::env::args().skip(1);
+        while let Some(arg) = iter.next() {
+            let value = match arg.as_str() {
+                "-c" | "--config" => &mut args.config,
+                "-d" | "--data-dir" => &mut args.data_dir,
+                "-h" | "--help" => {
+                    println!("{USAGE}");
+                    exit(0);
+                }
+                _ => {
+                    eprintln!("未知参数：{arg}\n{USAGE}");
+                    exit(2);
+                }
+            };
+            match iter.next() {
+                Some(v) => *value = Some(v),
+                None => {
+                    eprintln!("参数 {arg} 缺少值\n{USAGE}");
+                    exit(2);
+                }
+            }
+        }
+        args
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
     log_init();
+    let mut config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::new()?,
+    };
+    if let Some(data_dir) = args.data_dir {
+        config.data_dir = data_dir;
+    }
     // 开始初始化延迟消息
     info!("开始初始化延迟消息-->");
-    consume_queue::init().await;
-
-    let commit_log_rx = commit_log::mpsc_channel();
-    let first = commit_log_rx.clone();
-    tokio::spawn(async move {
-        let json = String::from("{\"msg_len\":66,\"body_crc\":342342,\"physical_offset\":0,\"send_timestamp\":1232432443,\"store_timestamp\":1232432999,\"body_len\":21,\"body\":\"此情可待成追忆\",\"topic_len\":9,\"topic\":\"topic_oms\",\"prop_len\":0,\"prop\":\"\"}");
-        let message = Message::deserialize_json(&json);
-        first.send(message).expect("消息发送通道失败");
-    });
+    let store = Store::open(config.clone(), &config.data_dir).await?;
+    let broker = Broker::new(store);
 
     info!("开始监听-->");
-    let listener = TcpListener::bind("127.0.0.1:9999").await?;
-
-    loop {
-        let (mut socket, _) = listener.accept().await?;
-
-        tokio::spawn(async move {
-            let mut buf = [0; 1024];
-
-            // In a loop, read data from the socket and write the data back.
-            loop {
-                let n = match socket.read(&mut buf).await {
-                    // socket closed
-                    Ok(n) if n == 0 => return,
-                    Ok(n) => n,
-                    Err(e) => {
-                        eprintln!("failed to read from socket; err = {e}");
-                        return;
-                    }
-                };
-
-                // Write the data back
-                if let Err(e) = socket.write_all(&buf[0..n]).await {
-                    eprintln!("failed to write to socket; err = {e}");
-                    return;
-                }
-            }
-        });
-    }
+    connection::serve(broker, &format!("127.0.0.1:{}", config.port)).await?;
+    Ok(())
 }
@@ -0,0 +1,70 @@
+//! broker，在存储实例之上提供消费者组与网络服务
+
+pub mod connection;
+pub mod consumer_group;
+pub mod protocol;
+
+use crate::storage::commit_log;
+use crate::storage::store::Store;
+use crate::storage::topic;
+use consumer_group::ConsumerGroups;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// broker 实例，持有存储实例与消费者组
+pub struct Broker {
+    pub(crate) store: Arc<Store>,
+    pub(crate) groups: ConsumerGroups,
+}
+
+impl Broker {
+    /// 基于存储实例创建 broker 并启动心跳超时检测任务
+    pub fn new(store: Arc<Store>) -> Arc<Broker> {
+        let broker = Arc::new(Broker {
+            groups: ConsumerGroups::new(&store),
+            store,
+        });
+        consumer_group::expire_task(&broker);
+        broker
+    }
+
+    /// broker 使用的存储实例
+    pub fn store(&self) -> &Arc<Store> {
+        &self.store
+    }
+}
+
+/// broker 的统计信息
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BrokerStats {
+    /// 下一条消息写入 commit_log 的物理偏移量
+    pub commit_log_offset: u64,
+    /// topic 数量，不包括系统 topic
+    pub topics: usize,
+    /// 所有非系统 topic 尚未到期的消息数量
+    pub pending: u64,
+    /// 所有非系统 topic 已到期写入 ready_queue 的消息数量
+    pub delivered: u64,
+    /// 有在线消费者的消费者组数量
+    pub groups: usize,
+    /// 在线的消费者数量
+    pub consumers: usize,
+}
+
+/// 统计 broker 当前的状态
+pub fn stats(broker: &Broker) -> BrokerStats {
+    let store = &broker.store;
+    let topics = topic::list(store);
+    let groups = consumer_group::groups(broker);
+    BrokerStats {
+        commit_log_offset: commit_log::max_offset(store),
+        topics: topics.len(),
+        pending: topics.iter().map(|info| info.pending).sum(),
+        delivered: topics.iter().map(|info| info.delivered).sum(),
+        consumers: groups
+            .iter()
+            .map(|group| consumer_group::members(broker, group).len())
+            .sum(),
+        groups: groups.len(),
+    }
+}
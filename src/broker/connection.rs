@@ -0,0 +1,762 @@
+//! 客户端连接，按行读取请求并返回响应
+//!
+//! 订阅的 topic 有消息可消费时主动推送，推送数量受订阅的 credit 限制，credit 不超过配置的 max_credit
+//!
+//! 连接断开时，连接上加入的消费者离开消费者组
+//!
+//! 单行请求不超过 [`MAX_LINE_LEN`] 字节，超出时返回错误并断开连接
+
+use crate::broker::consumer_group::{self, ConsumeMode};
+use crate::broker::protocol::{PutAck, PutMessage, Request, Response};
+use crate::broker::{self, Broker};
+use crate::cust_error::GroupError;
+use crate::storage::commit_log;
+use crate::storage::consume_queue;
+use crate::storage::filter::MessageFilter;
+use crate::storage::message::Message;
+use crate::storage::ready_queue;
+use crate::storage::topic;
+use futures::StreamExt;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::codec::{FramedRead, LinesCodec, LinesCodecError};
+
+/// 单行请求的最大字节数
+pub const MAX_LINE_LEN: usize = 16 * 1024 * 1024;
+
+/// 监听地址并处理客户端连接
+pub async fn serve(broker: Arc<Broker>, addr: &str) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("开始监听：{addr}");
+    serve_listener(broker, listener).await
+}
+
+/// 在已绑定的 listener 上处理客户端连接
+pub async fn serve_listener(broker: Arc<Broker>, listener: TcpListener) -> io::Result<()> {
+    loop {
+        let (socket, peer) = listener.accept().await?;
+        let broker = broker.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle(broker, socket, peer).await {
+                warn!("连接[{peer}]异常断开：{err}");
+            }
+        });
+    }
+}
+
+/// 处理一个连接上的所有请求
+async fn handle(broker: Arc<Broker>, socket: TcpStream, peer: SocketAddr) -> io::Result<()> {
+    info!("客户端连接：{peer}");
+    let (reader, mut writer) = socket.into_split();
+    let mut lines = FramedRead::new(reader, LinesCodec::new_with_max_length(MAX_LINE_LEN));
+    let mut notified = ready_queue::notified(&broker.store);
+    let mut session = Session {
+        broker,
+        peer,
+        member: None,
+        subscriptions: HashMap::new(),
+        pushed: HashMap::new(),
+    };
+
+    let result = async {
+        loop {
+            tokio::select! {
+                line = lines.next() => {
+                    let line = match line {
+                        Some(Ok(line)) => line,
+                        None => break,
+                        Some(Err(LinesCodecError::MaxLineLengthExceeded)) => {
+                            warn!("客户端[{peer}]请求超过 {MAX_LINE_LEN} 字节");
+                            let response = Response::error(format!("请求超过 {MAX_LINE_LEN} 字节"));
+                            writer.write_all(response.to_line().as_bytes()).await?;
+                            break;
+                        }
+                        Some(Err(LinesCodecError::Io(err))) => return Err(err),
+                    };
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let response = match serde_json::from_str::<Request>(&line) {
+                        Ok(request) => session.process(request).await,
+                        Err(err) => Response::error(format!("请求解析失败：{err}")),
+                    };
+                    writer.write_all(response.to_line().as_bytes()).await?;
+                }
+                // 落后时丢失的通知无需处理，推送时会拉取所有可消费的消息
+                _ = notified.recv() => {}
+            }
+            // 订阅、追加 credit、确认消息或有新消息时都可能需要推送
+            if let Some(push) = session.push() {
+                writer.write_all(push.to_line().as_bytes()).await?;
+            }
+        }
+        Ok(())
+    }
+    .await;
+
+    session.close();
+    info!("客户端断开：{peer}");
+    result
+}
+
+/// 连接的状态
+struct Session {
+    broker: Arc<Broker>,
+    peer: SocketAddr,
+    /// 连接上加入的消费者组
+    member: Option<SessionMember>,
+    /// 订阅的 topic -> 订阅
+    subscriptions: HashMap<String, Subscription>,
+    /// 已推送未确认的消息 (ready_queue topic, queue_id, queue_offset) -> 订阅的 topic，确认后归还 credit
+    pushed: HashMap<(String, u32, u64), String>,
+}
+
+/// 连接上加入的消费者组
+#[derive(Debug, Clone)]
+struct SessionMember {
+    group: String,
+    client_id: String,
+    mode: ConsumeMode,
+    /// 加入消费者组时分配的令牌
+    token: u64,
+}
+
+/// 推送模式的订阅
+#[derive(Debug)]
+struct Subscription {
+    /// 剩余可推送的数量
+    credit: usize,
+    /// tag 过滤条件，推送时追加消费者组的属性过滤表达式
+    filter: MessageFilter,
+}
+
+impl SessionMember {
+    /// 拉取与确认消息时使用的消费进度名称
+    fn consume_group(&self) -> String {
+        consumer_group::consume_group(&self.group, &self.client_id, self.mode)
+    }
+
+    /// 记录在 topic 上使用的 tag 过滤条件，集群模式下需要与组内其他消费者一致
+    fn subscribe_tags(
+        &self,
+        broker: &Broker,
+        topic: &str,
+        tags: &MessageFilter,
+    ) -> Result<(), GroupError> {
+        consumer_group::subscribe_tags(broker, &self.group, &self.client_id, topic, tags)
+    }
+
+    /// 在 tag 过滤条件上追加消费者组注册的属性过滤表达式
+    fn filter(&self, broker: &Broker, topic: &str, tags: &MessageFilter) -> MessageFilter {
+        tags.clone()
+            .with_sql(consumer_group::filter(broker, &self.group, topic))
+    }
+}
+
+impl Session {
+    /// 处理一个请求
+    async fn process(&mut self, request: Request) -> Response {
+        let broker = self.broker.clone();
+        let store = &broker.store;
+        match request {
+            Request::Put { topic, body, prop } => {
+                let message = Message::new(&topic, &body, &prop);
+                match commit_log::put_message(store, message).await {
+                    Ok(physical_offset) => Response::Put { physical_offset },
+                    Err(err) => Response::error(err),
+                }
+            }
+            Request::PutBatch { messages } => {
+                let mut acks = Vec::with_capacity(messages.len());
+                for PutMessage { topic, body, prop } in messages {
+                    let message = Message::new(&topic, &body, &prop);
+                    acks.push(match commit_log::put_message(store, message).await {
+                        Ok(physical_offset) => PutAck::Ok { physical_offset },
+                        Err(err) => PutAck::Error {
+                            message: err.to_string(),
+                        },
+                    });
+                }
+                Response::PutBatch { acks }
+            }
+            Request::Cancel { physical_offset } => {
+                match consume_queue::cancel(store, physical_offset).await {
+                    Ok(_) => Response::Ok,
+                    Err(err) => Response::error(err),
+                }
+            }
+            Request::Lookup { physical_offset } => {
+                match commit_log::lookup(store, physical_offset) {
+                    Ok(message) => Response::Message { message },
+                    Err(err) => Response::error(err),
+                }
+            }
+            Request::Stats => Response::Stats {
+                stats: broker::stats(&broker),
+            },
+            Request::Register {
+                group,
+                client_id,
+                mode,
+            } => {
+                // 广播模式的消费进度按 client_id 记录，连接地址在重新连接后会变化
+                let client_id = match (client_id, mode) {
+                    (Some(client_id), _) => client_id,
+                    (None, ConsumeMode::Clustering) => self.peer.to_string(),
+                    (None, ConsumeMode::Broadcasting) => {
+                        return Response::error(GroupError::MissingClientId(group));
+                    }
+                };
+                self.close();
+                let token = match consumer_group::register(&broker, &group, &client_id, mode) {
+                    Ok(token) => token,
+                    Err(err) => return Response::error(err),
+                };
+                if mode == ConsumeMode::Broadcasting {
+                    let group = consumer_group::consume_group(&group, &client_id, mode);
+                    ready_queue::start_at_tail(store, &group).await;
+                }
+                self.member = Some(SessionMember {
+                    group,
+                    client_id: client_id.clone(),
+                    mode,
+                    token,
+                });
+                Response::Registered { client_id }
+            }
+            Request::CreateTopic { topic, config } => match topic::create(store, &topic, config) {
+                Ok(_) => Response::Ok,
+                Err(err) => Response::error(err),
+            },
+            Request::DescribeTopic { topic } => match topic::describe(store, &topic) {
+                Some(info) => Response::Topics { topics: vec![info] },
+                None => Response::error(format!("topic 不存在：{topic}")),
+            },
+            Request::ListTopics => Response::Topics {
+                topics: topic::list(store),
+            },
+            Request::DeleteTopic { topic } => match topic::delete(store, &topic).await {
+                Ok(_) => Response::Ok,
+                Err(err) => Response::error(err),
+            },
+            request => {
+                let Some(member) = self.alive_member() else {
+                    return Response::error("未加入消费者组");
+                };
+                let client_id = &member.client_id;
+                let group = member.consume_group();
+                match request {
+                    Request::Pull { topic, max, tags } => {
+                        let tags = MessageFilter::tags(&tags);
+                        if let Err(err) = member.subscribe_tags(&broker, &topic, &tags) {
+                            return Response::error(err);
+                        }
+                        let filter = member.filter(&broker, &topic, &tags);
+                        Response::Messages {
+                            deliveries: ready_queue::pull(
+                                store, &topic, &group, client_id, max, &filter,
+                            ),
+                        }
+                    }
+                    Request::Filter { topic, sql } => {
+                        match consumer_group::set_filter(&broker, &member.group, &topic, &sql) {
+                            Ok(_) => Response::Ok,
+                            Err(err) => Response::error(err),
+                        }
+                    }
+                    Request::Subscribe {
+                        topic,
+                        credit,
+                        tags,
+                    } => {
+                        let filter = MessageFilter::tags(&tags);
+                        if let Err(err) = member.subscribe_tags(&broker, &topic, &filter) {
+                            return Response::error(err);
+                        }
+                        let credit = credit.min(self.max_credit());
+                        self.subscriptions
+                            .insert(topic, Subscription { credit, filter });
+                        Response::Ok
+                    }
+                    Request::Credit { topic, credit } => {
+                        let max_credit = self.max_credit();
+                        match self.subscriptions.get_mut(&topic) {
+                            Some(subscription) => {
+                                let credit = subscription.credit.saturating_add(credit);
+                                subscription.credit = credit.min(max_credit);
+                                Response::Ok
+                            }
+                            None => Response::error(format!("未订阅 topic：{topic}")),
+                        }
+                    }
+                    Request::Unsubscribe { topic } => {
+                        self.subscriptions.remove(&topic);
+                        Response::Ok
+                    }
+                    Request::Ack {
+                        topic,
+                        queue_id,
+                        queue_offset,
+                    } => {
+                        ready_queue::ack(store, &topic, queue_id, &group, queue_offset);
+                        self.restore_credit(topic, queue_id, queue_offset);
+                        Response::Ok
+                    }
+                    Request::Nack {
+                        topic,
+                        queue_id,
+                        queue_offset,
+                        reason,
+                    } => {
+                        ready_queue::nack(store, &topic, queue_id, &group, queue_offset, &reason)
+                            .await;
+                        self.restore_credit(topic, queue_id, queue_offset);
+                        Response::Ok
+                    }
+                    _ => Response::Ok,
+                }
+            }
+        }
+    }
+
+    /// 推送订阅的 topic 中可消费的消息，每条消息消耗一个 credit
+    fn push(&mut self) -> Option<Response> {
+        let member = self.member.clone()?;
+        if !consumer_group::contains(&self.broker, &member.group, &member.client_id, member.token) {
+            return None;
+        }
+        let group = member.consume_group();
+        self.reclaim_credit(&group, &member.client_id);
+        let broker = &self.broker;
+        let mut deliveries = Vec::new();
+        for (topic, subscription) in self.subscriptions.iter_mut() {
+            if subscription.credit == 0 {
+                continue;
+            }
+            let filter = member.filter(broker, topic, &subscription.filter);
+            let pulled = ready_queue::pull(
+                &broker.store,
+                topic,
+                &group,
+                &member.client_id,
+                subscription.credit,
+                &filter,
+            );
+            subscription.credit -= pulled.len();
+            pulled.iter().for_each(|delivery| {
+                self.pushed.insert(
+                    (
+                        delivery.topic.clone(),
+                        delivery.queue_id,
+                        delivery.queue_offset,
+                    ),
+                    topic.clone(),
+                );
+            });
+            deliveries.extend(pulled);
+        }
+        (!deliveries.is_empty()).then_some(Response::Push { deliveries })
+    }
+
+    /// 确认超时重新投递或被释放的推送消息不会再被确认，归还其 credit
+    fn reclaim_credit(&mut self, group: &str, client_id: &str) {
+        let store = &self.broker.store;
+        let expired = self
+            .pushed
+            .keys()
+            .filter(|(topic, queue_id, queue_offset)| {
+                !ready_queue::is_inflight(store, topic, *queue_id, group, client_id, *queue_offset)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        for (topic, queue_id, queue_offset) in expired {
+            self.restore_credit(topic, queue_id, queue_offset);
+        }
+    }
+
+    /// 推送的消息确认或拒绝后归还 credit
+    fn restore_credit(&mut self, topic: String, queue_id: u32, queue_offset: u64) {
+        let max_credit = self.max_credit();
+        if let Some(subscription) = self.pushed.remove(&(topic, queue_id, queue_offset)) {
+            if let Some(subscription) = self.subscriptions.get_mut(&subscription) {
+                subscription.credit = (subscription.credit + 1).min(max_credit);
+            }
+        }
+    }
+
+    /// 订阅的 credit 上限
+    fn max_credit(&self) -> usize {
+        self.broker.store.config().max_credit as usize
+    }
+
+    /// 刷新心跳，返回仍在消费者组内的成员信息，心跳超时被移出或被其它连接替换时返回 None
+    fn alive_member(&mut self) -> Option<SessionMember> {
+        let member = self.member.clone()?;
+        if consumer_group::heartbeat(&self.broker, &member.group, &member.client_id, member.token) {
+            Some(member)
+        } else {
+            self.member = None;
+            None
+        }
+    }
+
+    /// 离开已加入的消费者组，取消所有订阅
+    fn close(&mut self) {
+        self.subscriptions.clear();
+        self.pushed.clear();
+        if let Some(member) = self.member.take() {
+            consumer_group::unregister(
+                &self.broker,
+                &member.group,
+                &member.client_id,
+                member.token,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::broker::connection::{serve_listener, Session, MAX_LINE_LEN};
+    use crate::broker::consumer_group::ConsumeMode;
+    use crate::broker::protocol::{PutAck, PutMessage, Request, Response};
+    use crate::broker::Broker;
+    use crate::common::config::Config;
+    use crate::file_util::temp_dir;
+    use crate::storage::ready_queue::Delivery;
+    use crate::storage::store::Store;
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn broker(name: &str, config: Config) -> Arc<Broker> {
+        Broker::new(Store::open(config, temp_dir(name)).await.unwrap())
+    }
+
+    fn session(broker: &Arc<Broker>, port: u16) -> Session {
+        Session {
+            broker: broker.clone(),
+            peer: SocketAddr::from(([127, 0, 0, 1], port)),
+            member: None,
+            subscriptions: HashMap::new(),
+            pushed: HashMap::new(),
+        }
+    }
+
+    async fn register(session: &mut Session, group: &str, client_id: &str) {
+        register_mode(session, group, client_id, ConsumeMode::Clustering).await;
+    }
+
+    async fn register_mode(session: &mut Session, group: &str, client_id: &str, mode: ConsumeMode) {
+        let request = Request::Register {
+            group: group.to_string(),
+            client_id: Some(client_id.to_string()),
+            mode,
+        };
+        assert!(matches!(
+            session.process(request).await,
+            Response::Registered { .. }
+        ));
+    }
+
+    async fn put(session: &mut Session, topic: &str) {
+        let request = Request::Put {
+            topic: topic.to_string(),
+            body: "hello".to_string(),
+            prop: "_delay_ms-1".to_string(),
+        };
+        assert!(matches!(
+            session.process(request).await,
+            Response::Put { .. }
+        ));
+    }
+
+    async fn pull(session: &mut Session, topic: &str) -> Vec<Delivery> {
+        let request = Request::Pull {
+            topic: topic.to_string(),
+            max: 10,
+            tags: String::new(),
+        };
+        match session.process(request).await {
+            Response::Messages { deliveries } => deliveries,
+            response => panic!("拉取失败：{response:?}"),
+        }
+    }
+
+    /// 等待消息到期后拉取
+    async fn pull_due(session: &mut Session, topic: &str) -> Vec<Delivery> {
+        for _ in 0..100 {
+            let deliveries = pull(session, topic).await;
+            if !deliveries.is_empty() {
+                return deliveries;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("消息未到期：{topic}");
+    }
+
+    /// 等待消息到期后推送
+    async fn push_due(session: &mut Session) -> Vec<Delivery> {
+        for _ in 0..150 {
+            if let Some(response) = session.push() {
+                match response {
+                    Response::Push { deliveries } => return deliveries,
+                    response => panic!("推送失败：{response:?}"),
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("没有可推送的消息");
+    }
+
+    async fn subscribe(session: &mut Session, topic: &str, credit: usize) {
+        let request = Request::Subscribe {
+            topic: topic.to_string(),
+            credit,
+            tags: String::new(),
+        };
+        assert!(matches!(session.process(request).await, Response::Ok));
+    }
+
+    fn ack(delivery: &Delivery) -> Request {
+        Request::Ack {
+            topic: delivery.topic.clone(),
+            queue_id: delivery.queue_id,
+            queue_offset: delivery.queue_offset,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_put_oversized() {
+        let config = Config::new().unwrap();
+        let body = "a".repeat(config.commit_log_file_size as usize);
+        let broker = broker("put_oversized", config).await;
+        let mut session = session(&broker, 1);
+        let request = Request::Put {
+            topic: "topic_put_oversized".to_string(),
+            body: body.clone(),
+            prop: "_delay_ms-1".to_string(),
+        };
+        assert!(matches!(
+            session.process(request).await,
+            Response::Error { .. }
+        ));
+        let request = Request::PutBatch {
+            messages: vec![
+                PutMessage {
+                    topic: "topic_put_oversized".to_string(),
+                    body,
+                    prop: "_delay_ms-1".to_string(),
+                },
+                PutMessage {
+                    topic: "topic_put_oversized".to_string(),
+                    body: "hello".to_string(),
+                    prop: "_delay_ms-1".to_string(),
+                },
+            ],
+        };
+        match session.process(request).await {
+            Response::PutBatch { acks } => {
+                assert!(matches!(acks[0], PutAck::Error { .. }));
+                assert!(matches!(acks[1], PutAck::Ok { .. }));
+            }
+            response => panic!("批量写入失败：{response:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_push_credit() {
+        let broker = broker("connection_push_credit", Config::new().unwrap()).await;
+        let topic = "topic_push_credit";
+        let mut session = session(&broker, 1);
+        register(&mut session, "group_push_credit", "c1").await;
+        subscribe(&mut session, topic, 1).await;
+        put(&mut session, topic).await;
+        put(&mut session, topic).await;
+
+        let first = push_due(&mut session).await;
+        assert_eq!(first.len(), 1);
+        assert_eq!(session.subscriptions[topic].credit, 0);
+        // credit 用完后不再推送
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(session.push().is_none());
+
+        // 确认后归还 credit，继续推送
+        assert!(matches!(
+            session.process(ack(&first[0])).await,
+            Response::Ok
+        ));
+        let second = push_due(&mut session).await;
+        assert_eq!(second.len(), 1);
+        assert_ne!(
+            second[0].message.physical_offset,
+            first[0].message.physical_offset
+        );
+        assert!(matches!(
+            session.process(ack(&second[0])).await,
+            Response::Ok
+        ));
+        assert_eq!(session.subscriptions[topic].credit, 1);
+        assert!(session.pushed.is_empty());
+
+        // 追加 credit
+        let request = Request::Credit {
+            topic: topic.to_string(),
+            credit: 2,
+        };
+        assert!(matches!(session.process(request).await, Response::Ok));
+        assert_eq!(session.subscriptions[topic].credit, 3);
+
+        // credit 不超过配置的上限
+        let max_credit = broker.store.config().max_credit as usize;
+        let request = Request::Credit {
+            topic: topic.to_string(),
+            credit: usize::MAX,
+        };
+        assert!(matches!(session.process(request).await, Response::Ok));
+        assert_eq!(session.subscriptions[topic].credit, max_credit);
+        subscribe(&mut session, topic, usize::MAX).await;
+        assert_eq!(session.subscriptions[topic].credit, max_credit);
+    }
+
+    #[tokio::test]
+    async fn test_push_timeout() {
+        let mut config = Config::new().unwrap();
+        config.visibility_timeout = 100;
+        let broker = broker("connection_push_timeout", config).await;
+        let topic = "topic_push_timeout";
+        let mut session = session(&broker, 1);
+        register(&mut session, "group_push_timeout", "c1").await;
+        subscribe(&mut session, topic, 1).await;
+        put(&mut session, topic).await;
+        put(&mut session, topic).await;
+
+        let first = push_due(&mut session).await;
+        assert_eq!(first.len(), 1);
+        // 确认超时的消息重新投递后归还 credit，推送下一条消息
+        let second = push_due(&mut session).await;
+        assert_eq!(second.len(), 1);
+        assert_ne!(
+            second[0].message.physical_offset,
+            first[0].message.physical_offset
+        );
+        assert_eq!(session.pushed.len(), 1);
+        assert_eq!(session.subscriptions[topic].credit, 0);
+    }
+
+    #[tokio::test]
+    async fn test_broadcasting() {
+        let broker = broker("connection_broadcasting", Config::new().unwrap()).await;
+        let (group, topic) = ("group_broadcasting", "topic_broadcasting");
+        // 另一个消费者组用于等待消息到期
+        let mut clustering = session(&broker, 1);
+        register(&mut clustering, "group_broadcasting_clustering", "c1").await;
+        put(&mut clustering, topic).await;
+        pull_due(&mut clustering, topic).await;
+
+        // 广播模式必须指定 client_id
+        let mut member = session(&broker, 2);
+        let request = Request::Register {
+            group: group.to_string(),
+            client_id: None,
+            mode: ConsumeMode::Broadcasting,
+        };
+        assert!(matches!(
+            member.process(request).await,
+            Response::Error { .. }
+        ));
+
+        // 第一次加入时从队列末尾开始消费，之前到期的消息不再投递
+        register_mode(&mut member, group, "b1", ConsumeMode::Broadcasting).await;
+        assert!(pull(&mut member, topic).await.is_empty());
+        put(&mut clustering, topic).await;
+        let deliveries = pull_due(&mut member, topic).await;
+        assert_eq!(deliveries.len(), 1);
+        assert!(matches!(
+            member.process(ack(&deliveries[0])).await,
+            Response::Ok
+        ));
+        member.close();
+
+        // 使用相同的 client_id 重新加入时从已确认的位置继续消费
+        put(&mut clustering, topic).await;
+        let mut member = session(&broker, 3);
+        register_mode(&mut member, group, "b1", ConsumeMode::Broadcasting).await;
+        let redelivered = pull_due(&mut member, topic).await;
+        assert_eq!(redelivered.len(), 1);
+        assert_ne!(
+            redelivered[0].message.physical_offset,
+            deliveries[0].message.physical_offset
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() {
+        let broker = broker("connection_reconnect", Config::new().unwrap()).await;
+        let (group, topic) = ("group_reconnect", "topic_reconnect");
+        let mut old = session(&broker, 1);
+        register(&mut old, group, "c1").await;
+        put(&mut old, topic).await;
+
+        // 旧连接尚未断开时使用相同的 client_id 重新连接
+        let mut new = session(&broker, 2);
+        register(&mut new, group, "c1").await;
+        let deliveries = pull_due(&mut new, topic).await;
+        assert_eq!(deliveries.len(), 1);
+
+        // 之后才发现旧连接断开，不影响新连接及其未确认的消息
+        old.close();
+        assert!(matches!(
+            old.process(Request::Heartbeat).await,
+            Response::Error { .. }
+        ));
+        assert!(pull(&mut new, topic).await.is_empty());
+        assert!(matches!(
+            new.process(ack(&deliveries[0])).await,
+            Response::Ok
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_max_line_len() {
+        let broker = broker("connection_max_line_len", Config::new().unwrap()).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_listener(broker, listener));
+        let (reader, mut writer) = TcpStream::connect(addr).await.unwrap().into_split();
+        let mut lines = BufReader::new(reader).lines();
+
+        let request = Request::Put {
+            topic: "topic_max_line_len".to_string(),
+            body: "hello".to_string(),
+            prop: "_delay_ms-1".to_string(),
+        };
+        writer
+            .write_all(request.to_line().as_bytes())
+            .await
+            .unwrap();
+        let response = lines.next_line().await.unwrap().unwrap();
+        assert!(matches!(
+            serde_json::from_str(&response).unwrap(),
+            Response::Put { .. }
+        ));
+
+        // 超长的请求返回错误并断开连接，不必等到换行符
+        let line = "a".repeat(MAX_LINE_LEN + 1);
+        writer.write_all(line.as_bytes()).await.unwrap();
+        let response = lines.next_line().await.unwrap().unwrap();
+        assert!(matches!(
+            serde_json::from_str(&response).unwrap(),
+            Response::Error { .. }
+        ));
+        assert!(lines.next_line().await.unwrap().is_none());
+    }
+}
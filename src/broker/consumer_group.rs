@@ -0,0 +1,565 @@
+//! 消费者组，记录组内在线的消费者
+//!
+//! 集群模式下同组的消费者竞争消费同一个 topic 的到期消息，每条消息只投递给其中一个
+//!
+//! 广播模式下每个消费者单独记录消费进度，都会收到 topic 的所有到期消息
+//!
+//! 消费者断开连接或心跳超时后移出消费者组，其未确认的消息释放给组内其他消费者
+//!
+//! 每次加入消费者组分配一个新的令牌，消费者使用相同的 client_id 重新连接后，旧连接不能再移出新加入的消费者
+//!
+//! 消费者组可以为每个 topic 注册属性过滤表达式，组内所有消费者共用
+//!
+//! 不满足过滤条件的消息对整个消费者组视为已消费，集群模式下同组的消费者必须使用相同的 tag 表达式
+//!
+//! 过滤表达式注册时写入数据目录下的 config/consumer_filter.json，与消费进度一起在重启后恢复
+
+use crate::broker::Broker;
+use crate::cust_error::{FilterError, GroupError};
+use crate::storage::filter::sql::Expr;
+use crate::storage::filter::MessageFilter;
+use crate::storage::ready_queue;
+use crate::storage::store::{Store, CONFIG_DIR_NAME};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// 广播模式下消费者独立消费进度的分隔符，完整名称为 group@client_id
+const BROADCAST_SEPARATOR: &str = "@";
+
+/// 过滤表达式的存储文件名
+const FILTER_FILE: &str = "consumer_filter.json";
+
+/// group -> topic -> 过滤表达式原文
+type FilterTable = BTreeMap<String, BTreeMap<String, String>>;
+
+/// (group, topic) -> (表达式原文, 属性过滤表达式)
+type Filters = HashMap<(String, String), (String, Arc<Expr>)>;
+
+/// broker 的消费者组与过滤表达式
+pub(crate) struct ConsumerGroups {
+    /// group -> client_id -> 消费者
+    groups: Mutex<HashMap<String, HashMap<String, Member>>>,
+    /// 各消费者组在 topic 上的属性过滤表达式
+    filters: Mutex<Filters>,
+    /// 过滤表达式的存储文件路径
+    filter_path: PathBuf,
+    /// 心跳超时时间
+    heartbeat_timeout: Duration,
+    /// 下一个分配的加入令牌
+    next_token: AtomicU64,
+}
+
+impl ConsumerGroups {
+    /// 创建消费者组并加载已注册的过滤表达式
+    pub(crate) fn new(store: &Store) -> Self {
+        let filter_path = store.dir().join(CONFIG_DIR_NAME).join(FILTER_FILE);
+        ConsumerGroups {
+            groups: Mutex::new(HashMap::new()),
+            filters: Mutex::new(load_filters(&filter_path)),
+            filter_path,
+            heartbeat_timeout: Duration::from_millis(store.config().heartbeat_timeout),
+            next_token: AtomicU64::new(0),
+        }
+    }
+}
+
+/// 消费模式
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConsumeMode {
+    /// 集群模式，组内的消费者共享消费进度
+    #[default]
+    Clustering,
+    /// 广播模式，每个消费者独立记录消费进度
+    Broadcasting,
+}
+
+/// 消费者组内的消费者
+#[derive(Debug)]
+struct Member {
+    mode: ConsumeMode,
+    /// 加入时分配的令牌，区分使用相同 client_id 的不同连接
+    token: u64,
+    /// 最近一次心跳时间
+    last_heartbeat: Instant,
+    /// 拉取或订阅的 topic -> tag 过滤条件
+    tags: HashMap<String, MessageFilter>,
+}
+
+/// 消费者拉取与确认消息时使用的消费进度名称
+///
+/// 集群模式为组名，广播模式下每个消费者单独记录消费进度，为 group@client_id
+pub fn consume_group(group: &str, client_id: &str, mode: ConsumeMode) -> String {
+    match mode {
+        ConsumeMode::Clustering => group.to_string(),
+        ConsumeMode::Broadcasting => format!("{group}{BROADCAST_SEPARATOR}{client_id}"),
+    }
+}
+
+/// 消费者加入消费者组，同组的消费者必须使用相同的消费模式
+///
+/// 返回本次加入的令牌，已在组内的 client_id 重新加入时替换原有的成员
+pub fn register(
+    broker: &Broker,
+    group: &str,
+    client_id: &str,
+    mode: ConsumeMode,
+) -> Result<u64, GroupError> {
+    let mut groups = broker.groups.groups.lock().unwrap();
+    let members = groups.entry(group.to_string()).or_default();
+    if members
+        .iter()
+        .any(|(id, member)| id != client_id && member.mode != mode)
+    {
+        return Err(GroupError::ModeConflict(group.to_string()));
+    }
+    let token = broker.groups.next_token.fetch_add(1, Ordering::Relaxed);
+    members.insert(
+        client_id.to_string(),
+        Member {
+            mode,
+            token,
+            last_heartbeat: Instant::now(),
+            tags: HashMap::new(),
+        },
+    );
+    info!("消费者[{client_id}]以{mode:?}模式加入消费者组[{group}]");
+    Ok(token)
+}
+
+/// 刷新消费者的心跳时间，消费者不在组内或已使用其它令牌重新加入时返回 false
+pub fn heartbeat(broker: &Broker, group: &str, client_id: &str, token: u64) -> bool {
+    broker
+        .groups
+        .groups
+        .lock()
+        .unwrap()
+        .get_mut(group)
+        .and_then(|members| members.get_mut(client_id))
+        .filter(|member| member.token == token)
+        .map(|member| member.last_heartbeat = Instant::now())
+        .is_some()
+}
+
+/// 使用 token 加入的消费者是否仍在组内
+pub fn contains(broker: &Broker, group: &str, client_id: &str, token: u64) -> bool {
+    broker
+        .groups
+        .groups
+        .lock()
+        .unwrap()
+        .get(group)
+        .and_then(|members| members.get(client_id))
+        .is_some_and(|member| member.token == token)
+}
+
+/// 使用 token 加入的消费者离开消费者组，释放其未确认的消息
+///
+/// 消费者已使用其它令牌重新加入时不做处理，广播模式下释放的消息在消费者重新加入后再次投递给它
+pub fn unregister(broker: &Broker, group: &str, client_id: &str, token: u64) {
+    let removed = {
+        let mut groups = broker.groups.groups.lock().unwrap();
+        let removed = groups
+            .get_mut(group)
+            .and_then(|members| match members.get(client_id) {
+                Some(member) if member.token == token => members.remove(client_id),
+                _ => None,
+            });
+        if groups.get(group).is_some_and(HashMap::is_empty) {
+            groups.remove(group);
+        }
+        removed
+    };
+    if let Some(member) = removed {
+        info!("消费者[{client_id}]离开消费者组[{group}]");
+        ready_queue::release(
+            &broker.store,
+            &consume_group(group, client_id, member.mode),
+            client_id,
+        );
+    }
+}
+
+/// 记录消费者在 topic 上使用的 tag 过滤条件
+///
+/// 集群模式下组内其他消费者已使用不同的 tag 表达式时返回 [`GroupError::TagsConflict`]，
+/// 否则不满足该消费者过滤条件的消息会被整个消费者组确认，其他消费者无法再收到
+pub fn subscribe_tags(
+    broker: &Broker,
+    group: &str,
+    client_id: &str,
+    topic: &str,
+    tags: &MessageFilter,
+) -> Result<(), GroupError> {
+    let mut groups = broker.groups.groups.lock().unwrap();
+    let Some(members) = groups.get_mut(group) else {
+        return Ok(());
+    };
+    let conflict = members.iter().any(|(id, member)| {
+        id != client_id
+            && member.mode == ConsumeMode::Clustering
+            && member.tags.get(topic).is_some_and(|other| other != tags)
+    });
+    if conflict {
+        return Err(GroupError::TagsConflict(
+            group.to_string(),
+            topic.to_string(),
+        ));
+    }
+    if let Some(member) = members.get_mut(client_id) {
+        member.tags.insert(topic.to_string(), tags.clone());
+    }
+    Ok(())
+}
+
+/// 注册消费者组在 topic 上的属性过滤表达式，表达式为空时取消过滤
+///
+/// 先写入磁盘再生效，写入失败时返回错误，已注册的表达式不变
+pub fn set_filter(broker: &Broker, group: &str, topic: &str, sql: &str) -> Result<(), FilterError> {
+    let key = (group.to_string(), topic.to_string());
+    let expr = if sql.trim().is_empty() {
+        None
+    } else {
+        Some(Arc::new(Expr::parse(sql)?))
+    };
+    let mut filters = broker.groups.filters.lock().unwrap();
+    let mut table = filter_table(&filters);
+    match expr {
+        Some(_) => {
+            let topics = table.entry(group.to_string()).or_default();
+            topics.insert(topic.to_string(), sql.to_string());
+        }
+        None => {
+            if let Some(topics) = table.get_mut(group) {
+                topics.remove(topic);
+            }
+            table.retain(|_, topics| !topics.is_empty());
+        }
+    }
+    write_filters(&broker.groups.filter_path, &table)
+        .map_err(|err| FilterError::Persist(err.to_string()))?;
+    match expr {
+        Some(expr) => {
+            info!("消费者组[{group}]注册 topic[{topic}]的过滤表达式：{sql}");
+            filters.insert(key, (sql.to_string(), expr));
+        }
+        None => {
+            filters.remove(&key);
+        }
+    }
+    Ok(())
+}
+
+/// 内存中的过滤表达式转换为存储格式
+fn filter_table(filters: &Filters) -> FilterTable {
+    let mut table = FilterTable::new();
+    for ((group, topic), (sql, _)) in filters {
+        let topics = table.entry(group.clone()).or_default();
+        topics.insert(topic.clone(), sql.clone());
+    }
+    table
+}
+
+/// 从磁盘加载过滤表达式，无法解析的表达式跳过
+fn load_filters(path: &Path) -> Filters {
+    let table = match fs::read_to_string(path) {
+        Ok(json) => serde_json::from_str::<FilterTable>(&json).unwrap_or_else(|err| {
+            error!("解析过滤表达式文件错误 \n{:?},返回默认值", err);
+            FilterTable::new()
+        }),
+        Err(_) => FilterTable::new(),
+    };
+    let mut filters = HashMap::new();
+    for (group, topics) in table {
+        for (topic, sql) in topics {
+            match Expr::parse(&sql) {
+                Ok(expr) => {
+                    filters.insert((group.clone(), topic), (sql, Arc::new(expr)));
+                }
+                Err(err) => error!("消费者组[{group}]在 topic[{topic}]上的过滤表达式无效：{err}"),
+            }
+        }
+    }
+    filters
+}
+
+/// 先写临时文件再替换
+fn write_filters(path: &Path, table: &FilterTable) -> std::io::Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_string_pretty(table).unwrap())
+        .and_then(|_| fs::rename(&tmp, path))
+}
+
+/// 消费者组在 topic 上的属性过滤表达式
+pub fn filter(broker: &Broker, group: &str, topic: &str) -> Option<Arc<Expr>> {
+    broker
+        .groups
+        .filters
+        .lock()
+        .unwrap()
+        .get(&(group.to_string(), topic.to_string()))
+        .map(|(_, expr)| expr.clone())
+}
+
+/// 消费者组内在线的消费者
+pub fn members(broker: &Broker, group: &str) -> Vec<String> {
+    let mut members = broker
+        .groups
+        .groups
+        .lock()
+        .unwrap()
+        .get(group)
+        .map(|members| members.keys().cloned().collect::<Vec<_>>())
+        .unwrap_or_default();
+    members.sort();
+    members
+}
+
+/// 有在线消费者的消费者组
+pub fn groups(broker: &Broker) -> Vec<String> {
+    let mut groups = broker
+        .groups
+        .groups
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, members)| !members.is_empty())
+        .map(|(group, _)| group.clone())
+        .collect::<Vec<_>>();
+    groups.sort();
+    groups
+}
+
+/// 启动心跳超时检测任务，broker 释放后任务退出
+pub(crate) fn expire_task(broker: &Arc<Broker>) {
+    let broker = Arc::downgrade(broker);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            ticker.tick().await;
+            let Some(broker) = broker.upgrade() else {
+                break;
+            };
+            for (group, client_id, token) in expired_members(&broker, Instant::now()) {
+                warn!("消费者[{client_id}]心跳超时，移出消费者组[{group}]");
+                unregister(&broker, &group, &client_id, token);
+            }
+        }
+    });
+}
+
+/// 在 now 时刻心跳已超时的消费者，(group, client_id, token)
+fn expired_members(broker: &Broker, now: Instant) -> Vec<(String, String, u64)> {
+    let timeout = broker.groups.heartbeat_timeout;
+    broker
+        .groups
+        .groups
+        .lock()
+        .unwrap()
+        .iter()
+        .flat_map(|(group, members)| {
+            members
+                .iter()
+                .filter(|(_, member)| now.duration_since(member.last_heartbeat) > timeout)
+                .map(|(client_id, member)| (group.clone(), client_id.clone(), member.token))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::broker::consumer_group::{
+        consume_group, contains, expired_members, filter, heartbeat, members, register, set_filter,
+        subscribe_tags, unregister, ConsumeMode,
+    };
+    use crate::broker::Broker;
+    use crate::common::config::Config;
+    use crate::cust_error::{FilterError, GroupError};
+    use crate::file_util::temp_dir;
+    use crate::storage::filter::MessageFilter;
+    use crate::storage::message::Message;
+    use crate::storage::store::Store;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    async fn broker(name: &str) -> Arc<Broker> {
+        Broker::new(
+            Store::open(Config::new().unwrap(), temp_dir(name))
+                .await
+                .unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_membership() {
+        let broker = broker("membership").await;
+        let group = "test_membership";
+        let c2 = register(&broker, group, "c2", ConsumeMode::Clustering).unwrap();
+        let c1 = register(&broker, group, "c1", ConsumeMode::Clustering).unwrap();
+        assert_eq!(members(&broker, group), vec!["c1", "c2"]);
+        assert!(heartbeat(&broker, group, "c1", c1));
+        assert!(!heartbeat(&broker, group, "c3", c1));
+        assert!(contains(&broker, group, "c2", c2));
+
+        let timeout = broker.store.config().heartbeat_timeout;
+        let later = Instant::now() + Duration::from_millis(timeout + 1);
+        assert!(expired_members(&broker, later).contains(&(
+            group.to_string(),
+            "c2".to_string(),
+            c2
+        )));
+
+        unregister(&broker, group, "c1", c1);
+        unregister(&broker, group, "c2", c2);
+        assert!(members(&broker, group).is_empty());
+        assert!(!heartbeat(&broker, group, "c1", c1));
+    }
+
+    #[tokio::test]
+    async fn test_rejoin() {
+        let broker = broker("rejoin").await;
+        let group = "test_rejoin";
+        let old = register(&broker, group, "c1", ConsumeMode::Clustering).unwrap();
+        let new = register(&broker, group, "c1", ConsumeMode::Clustering).unwrap();
+        assert_ne!(old, new);
+        // 旧连接的令牌已失效，不能刷新心跳，也不能移出重新加入的消费者
+        assert!(!heartbeat(&broker, group, "c1", old));
+        assert!(!contains(&broker, group, "c1", old));
+        unregister(&broker, group, "c1", old);
+        assert!(contains(&broker, group, "c1", new));
+        unregister(&broker, group, "c1", new);
+        assert!(members(&broker, group).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_filter() {
+        let broker = broker("filter").await;
+        let group = "test_filter";
+        assert!(set_filter(&broker, group, "topic_oms", "region = ").is_err());
+        assert!(filter(&broker, group, "topic_oms").is_none());
+        set_filter(&broker, group, "topic_oms", "region = 'eu'").unwrap();
+        assert!(filter(&broker, group, "topic_oms").is_some());
+        assert!(filter(&broker, group, "topic_other").is_none());
+        set_filter(&broker, group, "topic_oms", "").unwrap();
+        assert!(filter(&broker, group, "topic_oms").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_filter_restart() {
+        let dir = temp_dir("filter_restart");
+        let store = Store::open(Config::new().unwrap(), &dir).await.unwrap();
+        let broker = Broker::new(store);
+        let group = "test_filter_restart";
+        set_filter(&broker, group, "topic_oms", "region = 'eu'").unwrap();
+        set_filter(&broker, group, "topic_other", "priority > 3").unwrap();
+        set_filter(&broker, group, "topic_other", "").unwrap();
+        drop(broker);
+
+        // 重启后恢复已注册的过滤表达式，已取消的不再恢复
+        let store = Store::open(Config::new().unwrap(), &dir).await.unwrap();
+        let broker = Broker::new(store);
+        let expr = filter(&broker, group, "topic_oms").unwrap();
+        assert!(expr.is_match(&Message::new("topic_oms", "", "region-eu")));
+        assert!(filter(&broker, group, "topic_other").is_none());
+
+        // 写入失败时返回错误，已注册的表达式不变
+        std::fs::create_dir(broker.groups.filter_path.with_extension("json.tmp")).unwrap();
+        assert!(matches!(
+            set_filter(&broker, group, "topic_oms", "region = 'us'"),
+            Err(FilterError::Persist(_))
+        ));
+        let expr = filter(&broker, group, "topic_oms").unwrap();
+        assert!(expr.is_match(&Message::new("topic_oms", "", "region-eu")));
+    }
+
+    #[tokio::test]
+    async fn test_tags_conflict() {
+        let broker = broker("tags_conflict").await;
+        let group = "test_tags_conflict";
+        let tags = MessageFilter::tags("TagA || TagB");
+        let c1 = register(&broker, group, "c1", ConsumeMode::Clustering).unwrap();
+        register(&broker, group, "c2", ConsumeMode::Clustering).unwrap();
+        subscribe_tags(&broker, group, "c1", "topic_oms", &tags).unwrap();
+        subscribe_tags(
+            &broker,
+            group,
+            "c2",
+            "topic_oms",
+            &MessageFilter::tags("TagB || TagA"),
+        )
+        .unwrap();
+        assert_eq!(
+            subscribe_tags(
+                &broker,
+                group,
+                "c2",
+                "topic_oms",
+                &MessageFilter::tags("TagA")
+            ),
+            Err(GroupError::TagsConflict(
+                group.to_string(),
+                "topic_oms".to_string()
+            ))
+        );
+        subscribe_tags(
+            &broker,
+            group,
+            "c2",
+            "topic_other",
+            &MessageFilter::tags("TagA"),
+        )
+        .unwrap();
+        // 使用其他表达式的消费者离开后可以使用新的表达式
+        unregister(&broker, group, "c1", c1);
+        subscribe_tags(
+            &broker,
+            group,
+            "c2",
+            "topic_oms",
+            &MessageFilter::tags("TagA"),
+        )
+        .unwrap();
+
+        // 广播模式下每个消费者独立确认消息，可以使用不同的表达式
+        let group = "test_tags_broadcasting";
+        register(&broker, group, "c1", ConsumeMode::Broadcasting).unwrap();
+        register(&broker, group, "c2", ConsumeMode::Broadcasting).unwrap();
+        subscribe_tags(&broker, group, "c1", "topic_oms", &tags).unwrap();
+        subscribe_tags(
+            &broker,
+            group,
+            "c2",
+            "topic_oms",
+            &MessageFilter::tags("TagA"),
+        )
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_broadcasting() {
+        let broker = broker("broadcasting").await;
+        let group = "test_broadcasting";
+        let c1 = register(&broker, group, "c1", ConsumeMode::Broadcasting).unwrap();
+        assert_eq!(
+            register(&broker, group, "c2", ConsumeMode::Clustering),
+            Err(GroupError::ModeConflict(group.to_string()))
+        );
+        assert_eq!(
+            consume_group(group, "c1", ConsumeMode::Broadcasting),
+            "test_broadcasting@c1"
+        );
+        assert_eq!(
+            consume_group(group, "c1", ConsumeMode::Clustering),
+            "test_broadcasting"
+        );
+        unregister(&broker, group, "c1", c1);
+        let c2 = register(&broker, group, "c2", ConsumeMode::Clustering).unwrap();
+        unregister(&broker, group, "c2", c2);
+    }
+}
@@ -0,0 +1,268 @@
+//! 客户端与服务端之间的协议，请求与响应均为一行 JSON
+//!
+//! 请求通过 cmd 字段区分，响应通过 type 字段区分，例如：
+//!
+//! {"cmd":"put","topic":"topic_oms","body":"hello","prop":"_delay-10"}
+//!
+//! {"type":"put","physical_offset":0}
+//!
+//! 客户端可以连续发送多个请求，响应按请求的顺序返回，订阅推送的消息可能穿插在响应之间
+
+use crate::broker::consumer_group::ConsumeMode;
+use crate::broker::BrokerStats;
+use crate::storage::message::Message;
+use crate::storage::ready_queue::Delivery;
+use crate::storage::topic::{TopicConfig, TopicInfo};
+use serde::{Deserialize, Serialize};
+
+/// 默认每次拉取的最大消息数
+const DEFAULT_PULL_MAX: usize = 32;
+
+/// 客户端请求
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum Request {
+    /// 写入一条消息，延迟时间通过 prop 中的延迟属性指定
+    Put {
+        topic: String,
+        body: String,
+        #[serde(default)]
+        prop: String,
+    },
+    /// 批量写入消息，按顺序写入，每条消息分别返回写入结果
+    PutBatch { messages: Vec<PutMessage> },
+    /// 取消未到期的延迟消息，physical_offset 为写入时返回的物理偏移量
+    Cancel { physical_offset: u64 },
+    /// 根据物理偏移量查看消息
+    Lookup { physical_offset: u64 },
+    /// 查看 broker 的统计信息
+    Stats,
+    /// 加入消费者组，集群模式下未指定 client_id 时使用连接的地址，默认为集群模式
+    ///
+    /// 广播模式下消费进度按 client_id 记录，必须指定 client_id，重新连接时需要使用相同的 client_id，
+    /// 第一次加入时从队列末尾开始消费
+    Register {
+        group: String,
+        #[serde(default)]
+        client_id: Option<String>,
+        #[serde(default)]
+        mode: ConsumeMode,
+    },
+    /// 消费者心跳
+    Heartbeat,
+    /// 拉取到期消息，tags 为 tag 表达式，如 TagA || TagB，默认不过滤
+    ///
+    /// 集群模式下同组的消费者在同一个 topic 上需要使用相同的 tag 表达式
+    Pull {
+        topic: String,
+        #[serde(default = "default_pull_max")]
+        max: usize,
+        #[serde(default)]
+        tags: String,
+    },
+    /// 注册消费者组在 topic 上的属性过滤表达式，如 region = 'eu' AND priority > 3，为空时取消过滤
+    Filter { topic: String, sql: String },
+    /// 订阅 topic，到期消息将推送给消费者，最多推送 credit 条未确认的消息
+    ///
+    /// 推送的消息确认或拒绝后归还一个 credit，也可以通过 credit 请求追加
+    Subscribe {
+        topic: String,
+        #[serde(default = "default_pull_max")]
+        credit: usize,
+        #[serde(default)]
+        tags: String,
+    },
+    /// 追加订阅的 credit
+    Credit { topic: String, credit: usize },
+    /// 取消订阅
+    Unsubscribe { topic: String },
+    /// 确认消息，topic 与 queue_id 为投递消息中的 topic 与 queue_id
+    Ack {
+        topic: String,
+        #[serde(default)]
+        queue_id: u32,
+        queue_offset: u64,
+    },
+    /// 拒绝消息，消息将延迟后重新投递
+    Nack {
+        topic: String,
+        #[serde(default)]
+        queue_id: u32,
+        queue_offset: u64,
+        #[serde(default)]
+        reason: String,
+    },
+    /// 创建 topic，未指定的设置使用配置文件中的默认值
+    CreateTopic {
+        topic: String,
+        #[serde(flatten)]
+        config: TopicConfig,
+    },
+    /// 查看 topic 的设置与消息数量
+    DescribeTopic { topic: String },
+    /// 列出所有 topic
+    ListTopics,
+    /// 删除 topic 及其索引文件
+    DeleteTopic { topic: String },
+}
+
+fn default_pull_max() -> usize {
+    DEFAULT_PULL_MAX
+}
+
+impl Request {
+    /// 序列化为一行 JSON，包含换行符
+    pub fn to_line(&self) -> String {
+        let mut line = serde_json::to_string(self).unwrap();
+        line.push('\n');
+        line
+    }
+}
+
+/// 批量写入中的一条消息
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PutMessage {
+    pub topic: String,
+    pub body: String,
+    #[serde(default)]
+    pub prop: String,
+}
+
+/// 批量写入中一条消息的写入结果
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum PutAck {
+    Ok { physical_offset: u64 },
+    Error { message: String },
+}
+
+/// 服务端响应
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    /// 请求处理成功
+    Ok,
+    /// 消息写入成功，返回消息的物理偏移量
+    Put { physical_offset: u64 },
+    /// 批量写入的结果，与请求中的消息一一对应
+    PutBatch { acks: Vec<PutAck> },
+    /// 已加入消费者组
+    Registered { client_id: String },
+    /// 拉取到的消息
+    Messages { deliveries: Vec<Delivery> },
+    /// 订阅推送的消息，不对应任何请求
+    Push { deliveries: Vec<Delivery> },
+    /// topic 的设置与消息数量
+    Topics { topics: Vec<TopicInfo> },
+    /// 根据物理偏移量查看的消息
+    Message { message: Message },
+    /// broker 的统计信息
+    Stats { stats: BrokerStats },
+    /// 请求处理失败
+    Error { message: String },
+}
+
+impl Response {
+    /// 请求处理失败
+    pub fn error(message: impl ToString) -> Self {
+        Response::Error {
+            message: message.to_string(),
+        }
+    }
+
+    /// 序列化为一行 JSON，包含换行符
+    pub fn to_line(&self) -> String {
+        let mut line = serde_json::to_string(self).unwrap();
+        line.push('\n');
+        line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::broker::protocol::{PutAck, PutMessage, Request, Response, DEFAULT_PULL_MAX};
+    use crate::storage::topic::TopicConfig;
+
+    #[test]
+    fn test_request() {
+        let request = serde_json::from_str::<Request>(
+            "{\"cmd\":\"put\",\"topic\":\"topic_oms\",\"body\":\"hello\",\"prop\":\"_delay-10\"}",
+        )
+        .unwrap();
+        assert_eq!(
+            request,
+            Request::Put {
+                topic: "topic_oms".to_string(),
+                body: "hello".to_string(),
+                prop: "_delay-10".to_string(),
+            }
+        );
+
+        let request =
+            serde_json::from_str::<Request>("{\"cmd\":\"pull\",\"topic\":\"topic_oms\"}").unwrap();
+        assert_eq!(
+            request,
+            Request::Pull {
+                topic: "topic_oms".to_string(),
+                max: DEFAULT_PULL_MAX,
+                tags: String::new(),
+            }
+        );
+
+        let request = serde_json::from_str::<Request>(
+            "{\"cmd\":\"create_topic\",\"topic\":\"topic_oms\",\"queue_count\":2}",
+        )
+        .unwrap();
+        assert_eq!(
+            request,
+            Request::CreateTopic {
+                topic: "topic_oms".to_string(),
+                config: TopicConfig {
+                    queue_count: Some(2),
+                    ..TopicConfig::default()
+                },
+            }
+        );
+        let request = serde_json::from_str::<Request>(
+            "{\"cmd\":\"put_batch\",\"messages\":[{\"topic\":\"topic_oms\",\"body\":\"hello\"}]}",
+        )
+        .unwrap();
+        assert_eq!(
+            request,
+            Request::PutBatch {
+                messages: vec![PutMessage {
+                    topic: "topic_oms".to_string(),
+                    body: "hello".to_string(),
+                    prop: String::new(),
+                }],
+            }
+        );
+        assert_eq!(
+            Request::Cancel {
+                physical_offset: 64
+            }
+            .to_line(),
+            "{\"cmd\":\"cancel\",\"physical_offset\":64}\n"
+        );
+        assert!(serde_json::from_str::<Request>("{\"cmd\":\"unknown\"}").is_err());
+    }
+
+    #[test]
+    fn test_response() {
+        assert_eq!(Response::Ok.to_line(), "{\"type\":\"ok\"}\n");
+        assert_eq!(
+            Response::Put { physical_offset: 0 }.to_line(),
+            "{\"type\":\"put\",\"physical_offset\":0}\n"
+        );
+        let acks = vec![
+            PutAck::Ok { physical_offset: 0 },
+            PutAck::Error {
+                message: "err".to_string(),
+            },
+        ];
+        assert_eq!(
+            Response::PutBatch { acks }.to_line(),
+            "{\"type\":\"put_batch\",\"acks\":[{\"status\":\"ok\",\"physical_offset\":0},{\"status\":\"error\",\"message\":\"err\"}]}\n"
+        );
+    }
+}
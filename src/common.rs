@@ -3,3 +3,4 @@ pub mod cust_error;
 pub mod data_process_util;
 pub mod file_util;
 pub mod log_util;
+pub mod time_util;
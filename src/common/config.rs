@@ -1,42 +1,262 @@
 //! 配置文件
 
-use lazy_static::lazy_static;
+use crate::common::cust_error::Error;
+use crate::common::time_util::parse_millis;
 use serde::{Deserialize, Serialize};
+use std::env;
 use std::fs::File;
+use std::path::Path;
 
-/// 配置文件路径
+/// 默认的配置文件路径，相对工作目录
 const CONF_PATH: &str = "conf.yaml";
+/// 指定配置文件路径的环境变量
+pub const CONF_PATH_ENV: &str = "DELAY_MESSAGE_CONF";
+/// 指定数据目录的环境变量，优先于配置文件中的 data_dir
+pub const DATA_DIR_ENV: &str = "DELAY_MESSAGE_DATA_DIR";
 
-lazy_static! {
-    /// 配置文件
-    pub static ref CONFIG: Config = Config::new();
-}
-
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+/// 配置，创建存储实例时传入
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Config {
     pub port: u32,
+    /// 数据目录，相对路径基于工作目录
+    #[serde(default = "default_data_dir")]
+    pub data_dir: String,
     /// commit_log 每个file的大小
     pub commit_log_file_size: u64,
-    /// 最大延迟时间
+    /// 最大延迟时间 秒
     pub max_delay_time: u32,
     /// consume_queue 每个file的大小
     pub consume_queue_file_size: u64,
+    /// 固定延迟级别，以空格分隔，消息属性 _level 的 1..N 依次对应
+    #[serde(default = "default_delay_levels")]
+    pub delay_levels: String,
+    /// 指定的投递时间已过去时的处理策略
+    #[serde(default)]
+    pub past_deliver_policy: PastDeliverPolicy,
+    /// 消费进度持久化的间隔 毫秒
+    #[serde(default = "default_consumer_offset_flush_interval")]
+    pub consumer_offset_flush_interval: u64,
+    /// 消息投递后等待确认的超时时间 毫秒，超时未确认的消息将重新投递
+    #[serde(default = "default_visibility_timeout")]
+    pub visibility_timeout: u64,
+    /// 最大重试次数，超过后消息写入死信 topic
+    #[serde(default = "default_max_reconsume_times")]
+    pub max_reconsume_times: u32,
+    /// 每个 topic 的队列数量
+    #[serde(default = "default_queue_count")]
+    pub queue_count: u32,
+    /// 消费者心跳超时时间 毫秒，超时的消费者被移出消费者组
+    #[serde(default = "default_heartbeat_timeout")]
+    pub heartbeat_timeout: u64,
+    /// 推送订阅的 credit 上限，订阅与追加的 credit 超出时按上限处理
+    #[serde(default = "default_max_credit")]
+    pub max_credit: u32,
+    /// consume_queue 与 ready_queue 写入位置持久化的间隔 毫秒
+    #[serde(default = "default_checkpoint_interval")]
+    pub checkpoint_interval: u64,
+    /// topic 默认的消息保留时长 秒，到期超过保留时长的索引文件会被删除，0 表示永久保留
+    #[serde(default)]
+    pub retention_time: u32,
+    /// 检查并删除过期索引文件的间隔 毫秒
+    #[serde(default = "default_retention_check_interval")]
+    pub retention_check_interval: u64,
+}
+
+/// 默认使用工作目录下的 store 目录，与之前的版本一致
+fn default_data_dir() -> String {
+    String::from("store")
+}
+
+/// 默认每 5 秒持久化一次消费进度
+fn default_consumer_offset_flush_interval() -> u64 {
+    5000
+}
+
+/// 默认 30 秒未确认则重新投递
+fn default_visibility_timeout() -> u64 {
+    30_000
+}
+
+/// 默认最多重试 16 次，与 RocketMQ 一致
+fn default_max_reconsume_times() -> u32 {
+    16
+}
+
+/// 默认每个 topic 4 个队列，与 RocketMQ 一致
+fn default_queue_count() -> u32 {
+    4
+}
+
+/// 默认 30 秒没有心跳则移出消费者组
+fn default_heartbeat_timeout() -> u64 {
+    30_000
+}
+
+/// 默认每个订阅最多 1024 条已推送未确认的消息
+fn default_max_credit() -> u32 {
+    1024
+}
+
+/// 默认每秒持久化一次写入位置，重启时从该位置向后扫描
+fn default_checkpoint_interval() -> u64 {
+    1000
+}
+
+/// 默认每 10 分钟检查一次过期的索引文件
+fn default_retention_check_interval() -> u64 {
+    600_000
+}
+
+/// 投递时间已过去时的处理策略
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PastDeliverPolicy {
+    /// 立即投递
+    #[default]
+    Immediate,
+    /// 拒绝写入
+    Reject,
+}
+
+/// 默认的延迟级别，与 RocketMQ 一致
+fn default_delay_levels() -> String {
+    String::from("1s 5s 10s 30s 1m 2m 3m 4m 5m 6m 7m 8m 9m 10m 20m 30m 1h 2h")
 }
 
 impl Config {
-    pub fn new() -> Self {
-        let file = File::options().read(true).open(CONF_PATH).unwrap();
-        serde_yaml::from_reader(&file).expect("初始化配置文件失败")
+    /// 读取环境变量 DELAY_MESSAGE_CONF 指定的配置文件，未指定时读取工作目录下的 conf.yaml
+    pub fn new() -> Result<Self, Error> {
+        let path = env::var(CONF_PATH_ENV).unwrap_or_else(|_| CONF_PATH.to_string());
+        Self::load(path)
+    }
+
+    /// 读取指定路径的配置文件，环境变量 DELAY_MESSAGE_DATA_DIR 覆盖其中的数据目录
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let file = File::options()
+            .read(true)
+            .open(path)
+            .map_err(|err| Error::Config(format!("打开配置文件[{}]失败：{err}", path.display())))?;
+        let mut config: Config = serde_yaml::from_reader(&file)
+            .map_err(|err| Error::Config(format!("解析配置文件[{}]失败：{err}", path.display())))?;
+        if let Ok(data_dir) = env::var(DATA_DIR_ENV) {
+            config.data_dir = data_dir;
+        }
+        config
+            .validate()
+            .map_err(|err| Error::Config(format!("配置文件[{}]不合法：{err}", path.display())))?;
+        Ok(config)
+    }
+
+    /// 校验延迟级别：每个级别都能解析为正的毫秒数，级别数量不超过 255
+    ///
+    /// 超过 topic 最大延迟时间的级别在写入消息时拒绝
+    fn validate(&self) -> Result<(), String> {
+        let levels = self.delay_levels.split_whitespace().collect::<Vec<_>>();
+        if levels.len() > u8::MAX as usize {
+            return Err(format!("延迟级别数量 {} 超过 {}", levels.len(), u8::MAX));
+        }
+        for level in levels {
+            match parse_millis(level) {
+                Some(0) | None => return Err(format!("延迟级别格式错误：{level}")),
+                Some(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// 最大延迟时间 毫秒
+    pub fn max_delay_millis(&self) -> u64 {
+        self.max_delay_time as u64 * 1000
+    }
+
+    /// 延迟级别的数量，加载配置时已校验不超过 255，超出的级别不会使用
+    pub fn delay_level_count(&self) -> u8 {
+        let count = self.delay_levels.split_whitespace().count();
+        count.min(u8::MAX as usize) as u8
+    }
+
+    /// 获取延迟级别对应的延迟毫秒数，级别从 1 开始
+    pub fn delay_level_millis(&self, level: u8) -> Option<u64> {
+        let index = level.checked_sub(1)? as usize;
+        self.delay_levels
+            .split_whitespace()
+            .nth(index)
+            .and_then(parse_millis)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::common::config::Config;
+    use crate::common::cust_error::Error;
+    use crate::file_util::temp_dir;
 
     #[test]
     fn test_config() {
-        let config = Config::new();
+        let config = Config::new().unwrap();
         println!("{config:?}");
     }
+
+    #[test]
+    fn test_delay_level_millis() {
+        let config = Config::new().unwrap();
+        assert_eq!(config.delay_level_millis(0), None);
+        assert_eq!(config.delay_level_millis(1), Some(1000));
+        assert_eq!(config.delay_level_millis(5), Some(60_000));
+        assert_eq!(config.delay_level_millis(18), Some(7_200_000));
+        assert_eq!(config.delay_level_millis(19), None);
+    }
+
+    #[test]
+    fn test_load() {
+        let path = temp_dir("config").join("conf.yaml");
+        std::fs::write(
+            &path,
+            "port: 9998\ncommit_log_file_size: 200\nmax_delay_time: 60\nconsume_queue_file_size: 200\n",
+        )
+        .unwrap();
+        let config = Config::load(&path).unwrap();
+        assert_eq!(config.port, 9998);
+        assert_eq!(config.data_dir, "store");
+        assert_eq!(config.queue_count, 4);
+        assert_eq!(config.max_credit, 1024);
+    }
+
+    #[test]
+    fn test_load_error() {
+        let dir = temp_dir("config_error");
+        assert!(matches!(
+            Config::load(dir.join("missing.yaml")),
+            Err(Error::Config(_))
+        ));
+        let path = dir.join("conf.yaml");
+        std::fs::write(&path, "port: abc\n").unwrap();
+        assert!(matches!(Config::load(&path), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn test_load_delay_levels() {
+        let path = temp_dir("config_delay_levels").join("conf.yaml");
+        let load = |delay_levels: &str| {
+            std::fs::write(
+                &path,
+                format!(
+                    "port: 9998\ncommit_log_file_size: 200\nmax_delay_time: 60\n\
+                     consume_queue_file_size: 200\ndelay_levels: \"{delay_levels}\"\n"
+                ),
+            )
+            .unwrap();
+            Config::load(&path)
+        };
+        assert_eq!(load("1s 30s 1m").unwrap().delay_level_count(), 3);
+        assert_eq!(load("").unwrap().delay_level_count(), 0);
+        assert!(matches!(load("1s 5x"), Err(Error::Config(_))));
+        assert!(matches!(load("0s"), Err(Error::Config(_))));
+        assert!(matches!(
+            load("99999999999999999999d"),
+            Err(Error::Config(_))
+        ));
+        assert!(matches!(load(&"1s ".repeat(256)), Err(Error::Config(_))));
+    }
 }
@@ -1,14 +1,52 @@
 //! 自定义异常
-use log::error;
+//!
+//! 各模块的错误统一归入 Error，公开接口返回 Result，单条损坏的数据或错误的请求不会使进程退出
+use std::io;
 use thiserror::Error;
 
-const COMMIT_LOG: &str = "commit_log";
+/// 统一的错误类型
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("I/O 异常: {0}")]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    Mmap(#[from] MmapError),
+
+    #[error("数据损坏: {0}")]
+    Corrupt(String),
+
+    #[error("配置错误: {0}")]
+    Config(String),
+
+    #[error("协议错误: {0}")]
+    Protocol(String),
+
+    #[error("不存在: {0}")]
+    NotFound(String),
+
+    #[error("存储实例已关闭")]
+    Closed,
+
+    #[error(transparent)]
+    Delay(#[from] DelayError),
+
+    #[error(transparent)]
+    Schedule(#[from] ScheduleError),
 
-pub fn panic(err: &str) -> ! {
-    error!("{err}");
-    panic!("{err}")
+    #[error(transparent)]
+    Topic(#[from] TopicError),
+
+    #[error(transparent)]
+    Group(#[from] GroupError),
+
+    #[error(transparent)]
+    Filter(#[from] FilterError),
 }
 
+/// 使用统一错误类型的 Result
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
 #[derive(Error, Debug)]
 pub enum MmapError {
     // #[error("Invalid header (expected {expected:?}, got {found:?})")]
@@ -27,3 +65,72 @@ pub enum MmapError {
     #[error("虚拟内存映射初始化异常: {0}")]
     MmapErr(String),
 }
+
+#[derive(Error, Debug, PartialEq)]
+pub enum DelayError {
+    #[error("消息缺少延迟属性")]
+    Missing,
+
+    #[error("延迟属性格式错误: {0}")]
+    Invalid(String),
+
+    #[error("延迟级别不存在: {0}")]
+    InvalidLevel(u8),
+
+    #[error("延迟时间必须大于0: {0}")]
+    NotPositive(i64),
+
+    #[error("延迟时间 {0} 毫秒超过最大延迟 {1} 毫秒")]
+    TooLong(u64, u64),
+
+    #[error("投递时间已过去: {0}")]
+    InPast(u64),
+}
+
+#[derive(Error, Debug)]
+pub enum ScheduleError {
+    #[error("延迟消息不存在或已到期: {0}")]
+    NotFound(u64),
+
+    #[error("延迟队列调度通道已关闭")]
+    Closed,
+
+    #[error("延迟消息持久化失败: {0}")]
+    Persist(String),
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum GroupError {
+    #[error("消费者组 {0} 已有其他消费模式的消费者")]
+    ModeConflict(String),
+
+    #[error("消费者组 {0} 以广播模式加入时需要指定 client_id")]
+    MissingClientId(String),
+
+    #[error("消费者组 {0} 中已有消费者使用其他 tag 表达式订阅 topic {1}")]
+    TagsConflict(String, String),
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum FilterError {
+    #[error("过滤表达式错误: {0}")]
+    Syntax(String),
+
+    #[error("过滤表达式持久化失败: {0}")]
+    Persist(String),
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum TopicError {
+    #[error("topic 名称不合法: {0}")]
+    InvalidName(String),
+
+    #[error("topic 设置不合法: {0}")]
+    InvalidConfig(String),
+
+    #[error("topic 已存在: {0}")]
+    Exists(String),
+
+    #[error("topic 不存在: {0}")]
+    NotFound(String),
+}
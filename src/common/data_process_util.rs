@@ -1,17 +1,21 @@
 //! crc 32 校验和工具
 
+use crate::common::cust_error::Error;
 use crc::{Crc, CRC_32_CKSUM};
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
 
 const CRC_CKSUM: Crc<u32> = Crc::<u32>::new(&CRC_32_CKSUM);
 
-/// 数据正确性校验
-pub fn crc_check(save_crc: u32, data: &[u8]) {
+/// 数据正确性校验，校验失败时返回 Error::Corrupt
+pub fn crc_check(save_crc: u32, data: &[u8]) -> Result<(), Error> {
     let ck_sum = CRC_CKSUM.checksum(data);
     if ck_sum != save_crc {
-        panic!("CRC check failed: curr: {ck_sum}, old: {save_crc}");
+        return Err(Error::Corrupt(format!(
+            "CRC 校验失败: 计算值 {ck_sum}, 存储值 {save_crc}"
+        )));
     }
+    Ok(())
 }
 
 /// 获取数据的crc
@@ -25,3 +29,12 @@ pub fn hashcode<T: Hash>(t: &T) -> u64 {
     t.hash(&mut s);
     s.finish()
 }
+
+/// 获取字符串的 hash_code，使用 FNV-1a 算法
+///
+/// 结果会持久化到文件中，不能使用随 Rust 版本变化的 DefaultHasher
+pub fn str_hashcode(s: &str) -> u64 {
+    s.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
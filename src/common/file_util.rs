@@ -1,66 +1,74 @@
 //! 操作file 的快捷工具类
 
-use crate::cust_error::panic;
 use log::error;
 use std::fs::{create_dir_all, read_dir, DirEntry};
+use std::io;
 use std::path::PathBuf;
 
 /// 获取指定 PathBuf 下的所有文件
 ///
 /// return Vec<DirEntry>
-pub fn get_all_files(dir: &PathBuf) -> Vec<DirEntry> {
-    read_dir(dir)
-        .unwrap()
-        .map(|f| f.unwrap())
-        .collect::<Vec<_>>()
+pub fn get_all_files(dir: &PathBuf) -> io::Result<Vec<DirEntry>> {
+    read_dir(dir)?.collect()
 }
 
 /// 获取指定 PathBuf 下的所有文件夹
 ///
 /// return Vec<DirEntry>
-pub fn get_all_dirs(dir: &PathBuf) -> Vec<DirEntry> {
-    read_dir(dir)
-        .unwrap()
-        .map(|f| f.unwrap())
-        .filter(|f| f.metadata().unwrap().is_dir())
-        .collect::<Vec<_>>()
+pub fn get_all_dirs(dir: &PathBuf) -> io::Result<Vec<DirEntry>> {
+    let mut dirs = Vec::new();
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            dirs.push(entry);
+        }
+    }
+    Ok(dirs)
 }
 
-/// 获取工作目录的文件夹
-pub fn file_path(dir_name: &str) -> PathBuf {
-    let path = std::env::current_dir()
-        .expect("获取应用目录异常")
-        .join(dir_name);
+/// 获取文件夹路径，相对路径基于工作目录，不存在时创建
+pub fn file_path(dir_name: &str) -> io::Result<PathBuf> {
+    let path = std::env::current_dir()?.join(dir_name);
     if !path.exists() {
-        if let Err(e) = create_dir_all(&path) {
-            error!("创建文件路径失败：{:?}", e);
-            panic(e.to_string().as_str())
-        }
+        create_dir_all(&path).inspect_err(|e| error!("创建文件路径失败：{:?}", e))?;
     }
-    path
+    Ok(path)
 }
 
 /// 获取 排序后的 files
-pub fn sorted_commit_log_files(dir_name: &str) -> Vec<DirEntry> {
-    let mut files = get_all_files(&file_path(dir_name));
+pub fn sorted_commit_log_files(dir_name: &str) -> io::Result<Vec<DirEntry>> {
+    let mut files = get_all_files(&file_path(dir_name)?)?;
     files.sort_by_key(|file| file.file_name());
-    files
+    Ok(files)
 }
 
+/// 创建测试使用的临时目录，每次调用得到不同的目录
 #[cfg(test)]
+pub(crate) fn temp_dir(name: &str) -> PathBuf {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    static SEQ: AtomicU32 = AtomicU32::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "delay-message-{name}-{}-{}",
+        std::process::id(),
+        SEQ.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    create_dir_all(&dir).unwrap();
+    dir
+}
 
+#[cfg(test)]
 mod tests {
     use crate::file_util;
-    use crate::file_util::get_all_dirs;
-    use std::path::PathBuf;
+    use crate::file_util::{get_all_dirs, temp_dir};
     use std::str::FromStr;
 
     #[test]
     fn test_get_all_files() {
-        let path = std::env::current_dir()
-            .expect("获取应用程序目录异常")
-            .join("store/commit_log");
+        let dir = temp_dir("get_all_files");
+        let path = file_util::file_path(dir.join("store/commit_log").to_str().unwrap()).unwrap();
         let sort = file_util::get_all_files(&path)
+            .unwrap()
             .iter()
             .map(|ele| u64::from_str(ele.file_name().to_str().unwrap()).unwrap())
             .collect::<Vec<_>>();
@@ -71,8 +79,9 @@ mod tests {
 
     #[test]
     fn trans_test() {
-        let path = PathBuf::from_str("store/consume_queue").unwrap();
-        get_all_dirs(&path).iter().for_each(|e| {
+        let dir = temp_dir("trans_test");
+        let path = file_util::file_path(dir.join("store/consume_queue").to_str().unwrap()).unwrap();
+        get_all_dirs(&path).unwrap().iter().for_each(|e| {
             println!("{:?}", e.file_name().as_os_str());
         });
     }
@@ -17,5 +17,6 @@ pub fn log_init() {
                 record.args()
             )
         })
-        .init();
+        .try_init()
+        .unwrap_or_default();
 }
@@ -0,0 +1,52 @@
+//! 时间util
+
+use chrono::{Local, TimeZone};
+use std::time::SystemTime;
+
+/// 当前时间戳 毫秒
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// 毫秒时间戳格式化为本地时间，如 2024-01-01 08:00:00.000
+pub fn format_millis(millis: u64) -> String {
+    match Local.timestamp_millis_opt(millis as i64).single() {
+        Some(time) => time.format("%Y-%m-%d %H:%M:%S%.3f").to_string(),
+        None => millis.to_string(),
+    }
+}
+
+/// 解析带单位的时长为毫秒，支持 ms s m h d，例如 500ms 1s 5m 2h
+pub fn parse_millis(text: &str) -> Option<u64> {
+    let text = text.trim();
+    let split = text.find(|c: char| !c.is_ascii_digit())?;
+    let (number, unit) = text.split_at(split);
+    let number = number.parse::<u64>().ok()?;
+    let unit = match unit {
+        "ms" => 1,
+        "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        "d" => 24 * 60 * 60 * 1000,
+        _ => return None,
+    };
+    number.checked_mul(unit)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::time_util::parse_millis;
+
+    #[test]
+    fn test_parse_millis() {
+        assert_eq!(parse_millis("500ms"), Some(500));
+        assert_eq!(parse_millis("5s"), Some(5000));
+        assert_eq!(parse_millis("2m"), Some(120_000));
+        assert_eq!(parse_millis("1h"), Some(3_600_000));
+        assert_eq!(parse_millis("10"), None);
+        assert_eq!(parse_millis("1y"), None);
+    }
+}
@@ -0,0 +1,198 @@
+//! 嵌入式使用的延迟消息接口，无需启动独立的服务端，在进程内写入、订阅与取消延迟消息
+//!
+//! 嵌入式的订阅者属于同一个消费者组，同一 topic 的多个订阅共同消费，重新打开后从已确认的位置继续
+//!
+//! ```no_run
+//! # async fn example() -> delay_message_rs::cust_error::Result<()> {
+//! use delay_message_rs::config::Config;
+//! use futures::StreamExt;
+//! use std::time::Duration;
+//!
+//! let scheduler = delay_message_rs::open(Config::new()?).await?;
+//! let id = scheduler.schedule("topic_oms", "hello", Duration::from_secs(10)).await?;
+//! let mut due = scheduler.subscribe("topic_oms");
+//! while let Some(delivery) = due.next().await {
+//!     println!("{} {}", delivery.message.physical_offset, delivery.message.body());
+//! }
+//! scheduler.cancel(id).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::common::config::Config;
+use crate::common::cust_error::Error;
+use crate::storage::commit_log;
+use crate::storage::consume_queue;
+use crate::storage::filter::MessageFilter;
+use crate::storage::message::{Message, PROP_DELAY_MS};
+use crate::storage::ready_queue::{self, Delivery};
+use crate::storage::store::Store;
+use futures::stream::{self, Stream};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// 嵌入式订阅者所属的消费者组
+pub const EMBEDDED_GROUP: &str = "%EMBEDDED%";
+/// 订阅每次拉取的最大消息数
+const SUBSCRIBE_BATCH: usize = 32;
+
+/// 打开配置中 data_dir 指定的数据目录，恢复未到期的延迟消息
+///
+/// 需要在 tokio 运行时中调用
+pub async fn open(config: Config) -> Result<Scheduler, Error> {
+    let dir = config.data_dir.clone();
+    Ok(Scheduler::new(Store::open(config, dir).await?))
+}
+
+/// 进程内的延迟消息调度器，释放后后台任务随之退出
+#[derive(Clone)]
+pub struct Scheduler {
+    store: Arc<Store>,
+    /// 用于生成订阅者的 client_id
+    subscribers: Arc<AtomicU32>,
+}
+
+impl Scheduler {
+    /// 基于已打开的存储实例创建调度器，可以与 broker 共用同一个存储实例
+    pub fn new(store: Arc<Store>) -> Self {
+        Scheduler {
+            store,
+            subscribers: Arc::new(AtomicU32::new(0)),
+        }
+    }
+
+    /// 调度器使用的存储实例
+    pub fn store(&self) -> &Arc<Store> {
+        &self.store
+    }
+
+    /// 写入一条延迟消息，返回消息的 id，即 commit_log 物理偏移量，用于取消
+    pub async fn schedule(&self, topic: &str, body: &str, delay: Duration) -> Result<u64, Error> {
+        let prop = format!("{PROP_DELAY_MS}-{}", delay.as_millis());
+        commit_log::put_message(&self.store, Message::new(topic, body, &prop)).await
+    }
+
+    /// 取消一条未到期的延迟消息，消息不存在或已到期时返回错误
+    pub async fn cancel(&self, id: u64) -> Result<(), Error> {
+        Ok(consume_queue::cancel(&self.store, id).await?)
+    }
+
+    /// 订阅 topic 的到期消息，消息在被取出时确认
+    ///
+    /// 已拉取但未被取出的消息在订阅释放后由其他订阅者重新消费
+    pub fn subscribe(&self, topic: &str) -> impl Stream<Item = Delivery> + Send + Unpin + 'static {
+        let id = self.subscribers.fetch_add(1, Ordering::Relaxed);
+        let subscription = Subscription {
+            notified: ready_queue::notified(&self.store),
+            store: self.store.clone(),
+            topic: topic.to_string(),
+            client_id: format!("{EMBEDDED_GROUP}{id}"),
+            buffer: VecDeque::new(),
+        };
+        Box::pin(stream::unfold(
+            subscription,
+            |mut subscription| async move {
+                let delivery = subscription.next().await?;
+                Some((delivery, subscription))
+            },
+        ))
+    }
+}
+
+/// 一个订阅的拉取状态
+struct Subscription {
+    store: Arc<Store>,
+    topic: String,
+    client_id: String,
+    /// 先订阅通知再拉取，拉取之后写入的消息不会错过通知
+    notified: broadcast::Receiver<String>,
+    /// 已拉取未取出的消息
+    buffer: VecDeque<Delivery>,
+}
+
+impl Subscription {
+    /// 取出下一条到期消息，没有可消费的消息时等待通知
+    async fn next(&mut self) -> Option<Delivery> {
+        loop {
+            if let Some(delivery) = self.buffer.pop_front() {
+                ready_queue::ack(
+                    &self.store,
+                    &delivery.topic,
+                    delivery.queue_id,
+                    EMBEDDED_GROUP,
+                    delivery.queue_offset,
+                );
+                return Some(delivery);
+            }
+            self.buffer.extend(ready_queue::pull(
+                &self.store,
+                &self.topic,
+                EMBEDDED_GROUP,
+                &self.client_id,
+                SUBSCRIBE_BATCH,
+                &MessageFilter::default(),
+            ));
+            if !self.buffer.is_empty() {
+                continue;
+            }
+            // 落后时丢失的通知无需处理，下次拉取时会拉取所有可消费的消息
+            loop {
+                match self.notified.recv().await {
+                    Ok(topic) if !ready_queue::belongs_to_topic(&topic, &self.topic) => {}
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => break,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        ready_queue::release(&self.store, EMBEDDED_GROUP, &self.client_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::config::Config;
+    use crate::common::cust_error::{Error, ScheduleError};
+    use crate::embedded::open;
+    use crate::file_util::temp_dir;
+    use futures::StreamExt;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_schedule_subscribe_cancel() {
+        let mut config = Config::new().unwrap();
+        config.data_dir = temp_dir("embedded").to_string_lossy().to_string();
+        let scheduler = open(config).await.unwrap();
+        let mut due = scheduler.subscribe("topic_embedded");
+
+        let cancelled = scheduler
+            .schedule("topic_embedded", "cancelled", Duration::from_millis(200))
+            .await
+            .unwrap();
+        let id = scheduler
+            .schedule("topic_embedded", "hello", Duration::from_millis(300))
+            .await
+            .unwrap();
+        scheduler.cancel(cancelled).await.unwrap();
+        assert!(matches!(
+            scheduler.cancel(cancelled).await,
+            Err(Error::Schedule(ScheduleError::NotFound(_)))
+        ));
+
+        let delivery = tokio::time::timeout(Duration::from_secs(3), due.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(delivery.message.physical_offset, id);
+        assert_eq!(delivery.message.body(), "hello");
+        // 已到期的消息无法取消
+        assert!(scheduler.cancel(id).await.is_err());
+    }
+}
@@ -1,7 +1,13 @@
 #![allow(dead_code)]
 
+mod broker;
 mod common;
+mod embedded;
 mod storage;
 
-pub use common::{cust_error, data_process_util, file_util, log_util};
-pub use storage::{commit_log, consume_queue, message};
+pub use broker::{connection, consumer_group, protocol, Broker, BrokerStats};
+pub use common::{config, cust_error, data_process_util, file_util, log_util, time_util};
+pub use embedded::{open, Scheduler, EMBEDDED_GROUP};
+pub use storage::{
+    commit_log, consume_queue, consumer_offset, filter, inspect, message, ready_queue, store, topic,
+};
@@ -1,5 +1,12 @@
+mod checkpoint;
 pub mod commit_log;
 pub mod consume_queue;
+pub mod consumer_offset;
+pub mod filter;
+pub mod inspect;
 pub mod message;
 mod mmap;
+pub mod ready_queue;
 mod start_offset;
+pub mod store;
+pub mod topic;
@@ -0,0 +1,226 @@
+//! 持久化 consume_queue 与 ready_queue 每个 topic 的写入位置
+//!
+//! 存储在 topic 目录下的 checkpoint 文件中，小端编码：
+//!
+//! [crc 4][commit_log 物理偏移量 8][队列数量 4]{[queue_id 4][下一条索引写入的逻辑偏移量 8]}
+//!
+//! crc 为其后所有数据的校验和，先写临时文件再替换，校验失败时视为不存在
+//!
+//! consume_queue 目录下的 topics 文件记录 checkpoint 时所有的 topic，用于发现整个目录丢失的 topic：
+//!
+//! [crc 4][commit_log 物理偏移量 8][topic 数量 4]{[名称长度 2][名称]}
+
+use crate::common::data_process_util::crc32;
+use crate::cust_error::Error;
+use byteorder::{LittleEndian, ReadBytesExt};
+use log::warn;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{Cursor, Read};
+use std::path::Path;
+
+/// 存储文件名
+pub(crate) const CHECKPOINT_FILE: &str = "checkpoint";
+/// topic 记录的存储文件名
+pub(crate) const TOPICS_FILE: &str = "topics";
+
+/// 一个 topic 的写入位置
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct Checkpoint {
+    /// 此位置之前的 commit_log 消息均已写入索引，ready_queue 中只用于记录
+    pub(crate) physical_offset: u64,
+    /// queue_id -> 下一条索引写入的逻辑偏移量
+    pub(crate) queues: BTreeMap<u32, u64>,
+}
+
+impl Checkpoint {
+    /// 读取 topic_dir 目录下的 checkpoint，不存在或校验失败时返回 None
+    pub(crate) fn load(topic_dir: &Path) -> Option<Self> {
+        let path = topic_dir.join(CHECKPOINT_FILE);
+        let data = fs::read(&path).ok()?;
+        Self::deserialize_binary(&data)
+            .inspect_err(|err| warn!("checkpoint 无效，忽略：{} {err}", path.display()))
+            .ok()
+    }
+
+    /// 持久化到 topic_dir 目录下，先写临时文件再替换，避免写入中途宕机损坏文件
+    pub(crate) fn persist(&self, topic_dir: &Path) -> Result<(), Error> {
+        write(&topic_dir.join(CHECKPOINT_FILE), &self.serialize_binary())
+    }
+
+    /// topic_dir 目录下是否存在 checkpoint 文件
+    pub(crate) fn exists(topic_dir: &Path) -> bool {
+        topic_dir.join(CHECKPOINT_FILE).exists()
+    }
+
+    /// 序列化为存储的字节编码
+    fn serialize_binary(&self) -> Vec<u8> {
+        let mut body = Vec::with_capacity(12 + self.queues.len() * 12);
+        body.extend(self.physical_offset.to_le_bytes());
+        body.extend((self.queues.len() as u32).to_le_bytes());
+        for (queue_id, queue_offset) in &self.queues {
+            body.extend(queue_id.to_le_bytes());
+            body.extend(queue_offset.to_le_bytes());
+        }
+        with_crc(body)
+    }
+
+    /// 从存储的字节编码反序列化，校验失败或长度不符时返回 Error::Corrupt
+    fn deserialize_binary(data: &[u8]) -> Result<Self, Error> {
+        let corrupt = |_| Error::Corrupt(format!("checkpoint 长度不足：{}", data.len()));
+        let mut reader = check_crc(data)?;
+        let physical_offset = reader.read_u64::<LittleEndian>().map_err(corrupt)?;
+        let count = reader.read_u32::<LittleEndian>().map_err(corrupt)?;
+        let mut queues = BTreeMap::new();
+        for _ in 0..count {
+            let queue_id = reader.read_u32::<LittleEndian>().map_err(corrupt)?;
+            queues.insert(
+                queue_id,
+                reader.read_u64::<LittleEndian>().map_err(corrupt)?,
+            );
+        }
+        Ok(Checkpoint {
+            physical_offset,
+            queues,
+        })
+    }
+}
+
+/// checkpoint 时 consume_queue 目录下所有的 topic
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct TopicsCheckpoint {
+    /// 此位置之前的 commit_log 消息均已写入索引，之后出现的 topic 不在记录中
+    pub(crate) physical_offset: u64,
+    pub(crate) topics: BTreeSet<String>,
+}
+
+impl TopicsCheckpoint {
+    /// 读取 base_dir 目录下的 topic 记录，不存在或校验失败时返回 None
+    pub(crate) fn load(base_dir: &Path) -> Option<Self> {
+        let path = base_dir.join(TOPICS_FILE);
+        let data = fs::read(&path).ok()?;
+        Self::deserialize_binary(&data)
+            .inspect_err(|err| warn!("topic 记录无效，忽略：{} {err}", path.display()))
+            .ok()
+    }
+
+    /// 持久化到 base_dir 目录下，先写临时文件再替换
+    pub(crate) fn persist(&self, base_dir: &Path) -> Result<(), Error> {
+        write(&base_dir.join(TOPICS_FILE), &self.serialize_binary())
+    }
+
+    /// 序列化为存储的字节编码，名称长度超过 u16 的 topic 无法写入，不会出现在记录中
+    fn serialize_binary(&self) -> Vec<u8> {
+        let topics = self
+            .topics
+            .iter()
+            .filter_map(|topic| Some((u16::try_from(topic.len()).ok()?, topic)))
+            .collect::<Vec<_>>();
+        let mut body = Vec::new();
+        body.extend(self.physical_offset.to_le_bytes());
+        body.extend((topics.len() as u32).to_le_bytes());
+        for (len, topic) in topics {
+            body.extend(len.to_le_bytes());
+            body.extend(topic.as_bytes());
+        }
+        with_crc(body)
+    }
+
+    /// 从存储的字节编码反序列化，校验失败或长度不符时返回 Error::Corrupt
+    fn deserialize_binary(data: &[u8]) -> Result<Self, Error> {
+        let corrupt = |_| Error::Corrupt(format!("topic 记录长度不足：{}", data.len()));
+        let mut reader = check_crc(data)?;
+        let physical_offset = reader.read_u64::<LittleEndian>().map_err(corrupt)?;
+        let count = reader.read_u32::<LittleEndian>().map_err(corrupt)?;
+        let mut topics = BTreeSet::new();
+        for _ in 0..count {
+            let len = reader.read_u16::<LittleEndian>().map_err(corrupt)? as usize;
+            let mut name = vec![0; len];
+            reader.read_exact(&mut name).map_err(corrupt)?;
+            let name = String::from_utf8(name)
+                .map_err(|_| Error::Corrupt(String::from("topic 记录中的名称不是 UTF-8")))?;
+            topics.insert(name);
+        }
+        Ok(TopicsCheckpoint {
+            physical_offset,
+            topics,
+        })
+    }
+}
+
+/// 在数据前加上 crc
+fn with_crc(body: Vec<u8>) -> Vec<u8> {
+    let mut data = crc32(&body).to_le_bytes().to_vec();
+    data.extend(body);
+    data
+}
+
+/// 校验 crc，返回从 crc 之后开始读取的 reader
+fn check_crc(data: &[u8]) -> Result<Cursor<&[u8]>, Error> {
+    let mut reader = Cursor::new(data);
+    let crc = reader
+        .read_u32::<LittleEndian>()
+        .map_err(|_| Error::Corrupt(format!("数据长度不足：{}", data.len())))?;
+    if crc32(&data[4..]) != crc {
+        return Err(Error::Corrupt(String::from("CRC 校验失败")));
+    }
+    Ok(reader)
+}
+
+/// 先写临时文件再替换，避免写入中途宕机损坏文件
+fn write(path: &Path, data: &[u8]) -> Result<(), Error> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::file_util::temp_dir;
+    use crate::storage::checkpoint::{Checkpoint, TopicsCheckpoint, CHECKPOINT_FILE, TOPICS_FILE};
+    use std::collections::{BTreeMap, BTreeSet};
+
+    #[test]
+    fn test_checkpoint() {
+        let dir = temp_dir("checkpoint");
+        assert!(!Checkpoint::exists(&dir));
+        assert_eq!(Checkpoint::load(&dir), None);
+
+        let checkpoint = Checkpoint {
+            physical_offset: 1024,
+            queues: BTreeMap::from([(0, 56), (3, 200)]),
+        };
+        checkpoint.persist(&dir).unwrap();
+        assert!(Checkpoint::exists(&dir));
+        assert_eq!(Checkpoint::load(&dir), Some(checkpoint));
+
+        // 数据损坏时视为不存在
+        let path = dir.join(CHECKPOINT_FILE);
+        let mut data = std::fs::read(&path).unwrap();
+        data[6] ^= 1;
+        std::fs::write(&path, &data).unwrap();
+        assert_eq!(Checkpoint::load(&dir), None);
+        std::fs::write(&path, &data[..10]).unwrap();
+        assert_eq!(Checkpoint::load(&dir), None);
+    }
+
+    #[test]
+    fn test_topics_checkpoint() {
+        let dir = temp_dir("topics_checkpoint");
+        assert_eq!(TopicsCheckpoint::load(&dir), None);
+
+        let checkpoint = TopicsCheckpoint {
+            physical_offset: 1024,
+            topics: BTreeSet::from(["%LEVEL%1".to_string(), "topic_oms".to_string()]),
+        };
+        checkpoint.persist(&dir).unwrap();
+        assert_eq!(TopicsCheckpoint::load(&dir), Some(checkpoint));
+
+        let path = dir.join(TOPICS_FILE);
+        let mut data = std::fs::read(&path).unwrap();
+        data[20] ^= 1;
+        std::fs::write(&path, &data).unwrap();
+        assert_eq!(TopicsCheckpoint::load(&dir), None);
+    }
+}
@@ -1,109 +1,342 @@
 //! commit_log 文件模块
 
-use crate::cust_error::{panic, MmapError};
-use crate::storage::start_offset;
+use crate::common::config::Config;
+use crate::cust_error::{Error, MmapError};
+use crate::storage::start_offset::StartOffset;
+use crate::storage::store::Store;
+use crate::storage::{consume_queue, topic};
 use memmap2::{Mmap, MmapOptions};
-use std::fs::{DirEntry, OpenOptions};
+use std::fs::{DirEntry, File, OpenOptions};
 use std::io::Write;
+use std::path::Path;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{RwLock, Weak};
 
-use crate::common::config::CONFIG;
 use crate::file_util::{file_path, sorted_commit_log_files};
-use crate::storage::message::Message;
+use crate::storage::message::{Message, PROP_QUEUE_ID};
 use crate::storage::mmap::MmapWriter;
-use lazy_static::lazy_static;
-use log::info;
-use tokio::sync::mpsc;
-use tokio::sync::mpsc::UnboundedSender;
+use log::{error, info, warn};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::sync::{mpsc, oneshot};
 
 /// 第一个存储文件的名称
 const INIT_LOG_FILE_NAME: &str = "00000000000000000000";
-/// 文件存储目录
-const DIR_NAME: &str = "store/commit_log";
-/// 存储映射引用
-static mut MMAP_WRITER: Option<CommitLogWriter> = None;
-
-lazy_static! {
-    static ref MMAP_READERS: Vec<MmapReader> = MmapReader::init_readers();
-}
-
-/// 创建 mpsc 写入通道，返回发送者
-pub fn mpsc_channel() -> UnboundedSender<Message> {
-    let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
-    tokio::spawn(async move {
-        info!("commit_log write 监听初始化");
-        while let Some(ele) = rx.recv().await {
-            info!("收到 写入消息 {ele:?}");
-            // 返回请求成功
-            CommitLogWriter::instance().commit_log_write(ele.serialize_binary().as_slice());
-            // todo 发送到consume_queue进行索引存储
+/// 文件存储目录，相对数据目录
+pub(crate) const DIR_NAME: &str = "commit_log";
+
+/// 写入结果，成功时返回消息的物理偏移量
+pub type PutResult = Result<u64, Error>;
+
+/// commit_log 写入请求
+#[derive(Debug)]
+pub struct PutRequest {
+    message: Message,
+    /// 已确定的延迟毫秒数，None 时根据消息的延迟属性校验
+    delay_time: Option<u64>,
+    resp: oneshot::Sender<PutResult>,
+}
+
+/// 存储实例的 commit_log
+///
+/// 写对象由写入任务独占，这里只保留写入通道与读取对象
+pub(crate) struct CommitLog {
+    /// 存储目录
+    dir_name: String,
+    /// 每个文件的大小
+    file_size: u64,
+    /// 新的 commit_log 文件创建后需要重新加载，因此使用 RwLock
+    readers: RwLock<Vec<MmapReader>>,
+    /// 写入通道，每个存储实例只有一个写入任务
+    sender: UnboundedSender<PutRequest>,
+    /// 下一条消息写入的物理偏移量，由写入任务更新
+    max_offset: AtomicU64,
+    /// 此位置之前的消息均已写入索引，由写入任务更新，写入 checkpoint 用于重启时恢复未写入索引的消息
+    dispatched_offset: AtomicU64,
+}
+
+/// 尚未启动的写入任务，存储实例创建后启动
+pub(crate) struct PutTask {
+    writer: CommitLogWriter,
+    rx: UnboundedReceiver<PutRequest>,
+}
+
+impl PutTask {
+    /// 启动写入任务
+    pub(crate) fn spawn(self, store: Weak<Store>) {
+        tokio::spawn(put_task(self.writer, self.rx, store));
+    }
+}
+
+impl CommitLog {
+    /// 打开数据目录下的 commit_log，返回的写入任务需要在存储实例创建后启动
+    pub(crate) fn open(config: &Config, dir: &Path) -> Result<(Self, PutTask), Error> {
+        let dir_name = dir.join(DIR_NAME).to_string_lossy().to_string();
+        let file_size = config.commit_log_file_size;
+        let writer = CommitLogWriter::open(&dir_name, file_size, StartOffset::open(dir)?)?;
+        let max_offset = writer.file_offset()? + writer.mmap.prev_write_size as u64;
+        let (sender, rx) = mpsc::unbounded_channel::<PutRequest>();
+        let commit_log = CommitLog {
+            readers: RwLock::new(MmapReader::init_readers(&dir_name)?),
+            dir_name,
+            file_size,
+            sender,
+            max_offset: AtomicU64::new(max_offset),
+            dispatched_offset: AtomicU64::new(max_offset),
+        };
+        Ok((commit_log, PutTask { writer, rx }))
+    }
+
+    /// 根据queue_consume 读取一个消息
+    ///
+    /// offset  log 文件物理位置偏移
+    ///
+    /// size    读取的长度
+    ///
+    /// 文件不存在或超出文件范围时返回错误
+    fn read(&self, offset: u64, size: u32) -> Result<Vec<u8>, Error> {
+        // commit log 文件索引
+        let index = (offset / self.file_size) as usize;
+        let start = (offset % self.file_size) as usize;
+        let end = start + size as usize;
+
+        let loaded = self
+            .readers
+            .read()
+            .unwrap()
+            .get(index)
+            .is_some_and(|reader| reader.reader.len() >= end);
+        if !loaded {
+            *self.readers.write().unwrap() = MmapReader::init_readers(&self.dir_name)?;
         }
-    });
-    tx
+
+        let readers = self.readers.read().unwrap();
+        let reader = readers
+            .get(index)
+            .ok_or_else(|| Error::NotFound(format!("commit_log 物理偏移量 {offset}")))?;
+        reader
+            .reader
+            .get(start..end)
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| {
+                Error::Corrupt(format!(
+                    "commit_log 物理偏移量 {offset} 长度 {size} 超出文件范围"
+                ))
+            })
+    }
+}
+
+/// 写入任务，存储实例释放后写入通道关闭，任务随之退出
+async fn put_task(
+    mut writer: CommitLogWriter,
+    mut rx: UnboundedReceiver<PutRequest>,
+    store: Weak<Store>,
+) {
+    info!("commit_log write 监听初始化");
+    while let Some(PutRequest {
+        message: mut ele,
+        delay_time,
+        resp,
+    }) = rx.recv().await
+    {
+        let Some(store) = store.upgrade() else {
+            break;
+        };
+        info!("收到 写入消息 {ele:?}");
+        if let Err(err) = topic::check_path(&ele.topic) {
+            warn!("消息 topic 校验失败：{err}");
+            let _ = resp.send(Err(err.into()));
+            continue;
+        }
+        ele.set_prop(
+            PROP_QUEUE_ID,
+            &topic::select_queue(&store, &ele).to_string(),
+        );
+        if let Err(err) = ele.store_init().and_then(|_| check_size(&store, &ele)) {
+            warn!("消息格式校验失败：{err}");
+            let _ = resp.send(Err(err));
+            continue;
+        }
+        let max = topic::max_delay_millis(&store, &ele.topic);
+        let delay_time = match delay_time.map_or_else(
+            || ele.delay_millis(ele.store_timestamp(), store.config(), max),
+            Ok,
+        ) {
+            Ok(delay_time) => delay_time,
+            Err(err) => {
+                warn!("消息延迟校验失败：{err}");
+                let _ = resp.send(Err(err.into()));
+                continue;
+            }
+        };
+        let result = write_message(&store, &mut writer, &mut ele, delay_time).await;
+        if let Err(err) = &result {
+            error!("消息写入失败：{err}");
+        }
+        let _ = resp.send(result);
+    }
+}
+
+/// 消息不能跨文件存储，超过单个 commit_log 文件大小的消息无法写入
+fn check_size(store: &Store, message: &Message) -> Result<(), Error> {
+    let file_size = store.config().commit_log_file_size;
+    if message.msg_len() as u64 > file_size {
+        return Err(Error::Protocol(format!(
+            "消息长度 {} 超过 commit_log 文件大小 {file_size}",
+            message.msg_len()
+        )));
+    }
+    Ok(())
+}
+
+/// 写入 commit_log 并构建索引，返回消息的物理偏移量
+async fn write_message(
+    store: &Store,
+    writer: &mut CommitLogWriter,
+    message: &mut Message,
+    delay_time: u64,
+) -> PutResult {
+    message.physical_offset = writer.commit_log_offset(message.msg_len() as usize)?;
+    writer.commit_log_write(message.serialize_binary().as_slice())?;
+    store.commit_log.max_offset.store(
+        message.physical_offset + message.msg_len() as u64,
+        Ordering::Release,
+    );
+    // 发送到consume_queue进行索引存储
+    consume_queue::dispatch(store, message, delay_time).await?;
+    store.commit_log.dispatched_offset.store(
+        message.physical_offset + message.msg_len() as u64,
+        Ordering::Release,
+    );
+    Ok(message.physical_offset)
+}
+
+/// 写入一条消息，延迟属性校验失败时返回对应的错误
+///
+/// 客户端不能写入系统 topic，topic 名称不能作为存储目录时同样返回错误
+pub async fn put_message(store: &Store, message: Message) -> PutResult {
+    topic::check_name(&message.topic)?;
+    put(store, message, None).await
+}
+
+/// 写入一条系统 topic 的延迟消息，用于重试
+pub(crate) async fn put_system(store: &Store, message: Message) -> PutResult {
+    put(store, message, None).await
+}
+
+/// 写入一条立即到期的系统消息，延迟属性会被清除，用于死信与重放
+pub(crate) async fn put_immediate(store: &Store, mut message: Message) -> PutResult {
+    message.clear_delay();
+    put(store, message, Some(0)).await
+}
+
+async fn put(store: &Store, message: Message, delay_time: Option<u64>) -> PutResult {
+    let (resp, rx) = oneshot::channel();
+    store
+        .commit_log
+        .sender
+        .send(PutRequest {
+            message,
+            delay_time,
+            resp,
+        })
+        .map_err(|_| Error::Closed)?;
+    rx.await.map_err(|_| Error::Closed)?
 }
 
 /// commit_log 写对象
 ///
-/// 此对象利用mpsc进行操作，因为避免写入时使用锁竞争
-type CommitLogWriter = MmapWriter;
+/// 此对象由写入任务独占，避免写入时使用锁竞争
+struct CommitLogWriter {
+    mmap: MmapWriter,
+    /// 当前文件写入的位置
+    start_offset: StartOffset,
+}
 
 impl CommitLogWriter {
-    /// 单例获取 START_OFFSET
-    fn instance() -> &'static mut CommitLogWriter {
-        unsafe {
-            if MMAP_WRITER.is_none() {
-                MMAP_WRITER = Some(Self::commit_log_new(None));
-            }
-            MMAP_WRITER.as_mut().unwrap()
+    /// 打开最后一个 commit_log 文件，从 start_offset 记录的位置继续写入
+    fn open(dir_name: &str, file_size: u64, mut start_offset: StartOffset) -> Result<Self, Error> {
+        let offset = start_offset.read();
+        Ok(CommitLogWriter {
+            mmap: MmapWriter::new(None, INIT_LOG_FILE_NAME, dir_name, offset, file_size)?,
+            start_offset,
+        })
+    }
+
+    /// 当前文件名对应的起始物理偏移量
+    fn file_offset(&self) -> Result<u64, Error> {
+        u64::from_str(&self.mmap.file_name).map_err(|_| {
+            Error::Corrupt(format!("commit_log 文件名不合法：{}", self.mmap.file_name))
+        })
+    }
+
+    /// 获取下一条 data_len 长度的消息写入的物理偏移量
+    ///
+    /// 当前文件剩余空间不足时会先创建新的文件
+    fn commit_log_offset(&mut self, data_len: usize) -> Result<u64, Error> {
+        self.check_len(data_len)?;
+        let remain = self.mmap.writer.len() - self.mmap.prev_write_size;
+        if remain < data_len {
+            self.commit_log_new_writer_create()?;
         }
+        Ok(self.file_offset()? + self.mmap.prev_write_size as u64)
     }
-    /// 创建当前的实例
-    fn commit_log_new(file_name: Option<&str>) -> Self {
-        Self::new(
-            file_name,
-            INIT_LOG_FILE_NAME,
-            DIR_NAME,
-            Some(start_offset::read()),
-            CONFIG.commit_log_file_size,
-        )
+
+    /// 新文件也放不下的数据直接返回错误，不能无限创建新文件
+    fn check_len(&self, data_len: usize) -> Result<(), Error> {
+        if data_len > self.mmap.writer.len() {
+            return Err(Error::Protocol(format!(
+                "数据长度 {data_len} 超过 commit_log 文件大小 {}",
+                self.mmap.writer.len()
+            )));
+        }
+        Ok(())
     }
 
     /// 写数据
-    fn commit_log_write(&mut self, data: &[u8]) {
-        let mut buf = &mut self.writer[self.prev_write_size..];
+    fn commit_log_write(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.check_len(data.len())?;
+        let mut buf = &mut self.mmap.writer[self.mmap.prev_write_size..];
 
         info!(
             "当前 commit_log 文件[{}]剩余：{},当前数据大小：{}",
-            self.file_name,
+            self.mmap.file_name,
             buf.len(),
             data.len()
         );
         if buf.len() < data.len() {
-            self.commit_log_new_writer_create();
-            self.commit_log_write(data);
-            return;
+            self.commit_log_new_writer_create()?;
+            return self.commit_log_write(data);
         }
-        buf.write_all(data).unwrap();
-        self.prev_write_size += data.len();
-        start_offset::write(self.prev_write_size as u64);
+        buf.write_all(data)?;
+        self.mmap.prev_write_size += data.len();
+        self.start_offset.write(self.mmap.prev_write_size as u64);
+        Ok(())
     }
 
     /// 当前commit_log文件已满，开始创建新的文件
-    fn commit_log_new_writer_create(&mut self) {
-        let curr = u64::from_str(self.file_name.as_str()).unwrap();
+    fn commit_log_new_writer_create(&mut self) -> Result<(), Error> {
+        let curr = self.file_offset()?;
         info!(
             "当前commit_log文件[{}]已满，开始创建新的文件",
-            self.file_name
+            self.mmap.file_name
         );
-        start_offset::write(0);
+        self.start_offset.write(0);
 
         let new_name = format!(
             "{number:>0width$}",
-            number = curr + CONFIG.commit_log_file_size,
+            number = curr + self.mmap.file_size,
             width = 20
         );
-        let new_writer = Self::commit_log_new(Some(new_name.as_str()));
-        self.new_writer_create(&new_name, new_writer);
+        let new_writer = MmapWriter::new(
+            Some(new_name.as_str()),
+            INIT_LOG_FILE_NAME,
+            &self.mmap.dir_name,
+            0,
+            self.mmap.file_size,
+        )?;
+        self.mmap.new_writer_create(&new_name, new_writer);
+        Ok(())
     }
 }
 
@@ -121,90 +354,222 @@ impl MmapReader {
         }
     }
     /// 初始化所有 commit_log 文件的读取对象
-    fn init_readers() -> Vec<MmapReader> {
-        let log_files = sorted_commit_log_files(DIR_NAME);
+    fn init_readers(dir_name: &str) -> Result<Vec<MmapReader>, Error> {
+        let log_files = sorted_commit_log_files(dir_name)?;
         let mut vec = Vec::<MmapReader>::new();
         if log_files.is_empty() {
-            Self::empty_reader_process(&mut vec);
+            Self::empty_reader_process(dir_name, &mut vec)?;
         } else {
-            Self::not_empty_reader_process(log_files, &mut vec);
+            Self::not_empty_reader_process(dir_name, log_files, &mut vec)?;
         }
-        vec
+        Ok(vec)
     }
 
     /// 存在 log 文件的处理方式
-    fn not_empty_reader_process(log_files: Vec<DirEntry>, vec: &mut Vec<MmapReader>) {
-        log_files.iter().for_each(|ele| {
-            let path = file_path(DIR_NAME).join(ele.file_name().to_str().unwrap());
-            match OpenOptions::new().read(true).open(path) {
-                Ok(file) => {
-                    let ele = Self::new(ele.file_name().to_str().unwrap(), unsafe {
-                        MmapOptions::new().map(&file).unwrap()
-                    });
-                    vec.push(ele);
-                }
-                Err(err) => {
-                    let err = MmapError::OpenErr(err.to_string());
-                    panic(err.to_string().as_str())
-                }
-            }
-        });
+    fn not_empty_reader_process(
+        dir_name: &str,
+        log_files: Vec<DirEntry>,
+        vec: &mut Vec<MmapReader>,
+    ) -> Result<(), Error> {
+        let dir = file_path(dir_name)?;
+        for ele in log_files {
+            let file_name = ele.file_name().to_string_lossy().to_string();
+            let file = OpenOptions::new()
+                .read(true)
+                .open(dir.join(&file_name))
+                .map_err(|err| MmapError::OpenErr(err.to_string()))?;
+            vec.push(Self::new(&file_name, Self::map(&file)?));
+        }
+        Ok(())
     }
 
     /// 如果目录中log 文件为空时的处理
-    fn empty_reader_process(vec: &mut Vec<MmapReader>) {
-        let path = file_path(DIR_NAME).join(INIT_LOG_FILE_NAME);
-        match OpenOptions::new().create(true).read(true).open(path) {
-            Ok(file) => {
-                vec.push(Self::new(INIT_LOG_FILE_NAME, unsafe {
-                    MmapOptions::new().map(&file).unwrap()
-                }));
-            }
-            Err(err) => {
-                let err = MmapError::OpenErr(err.to_string());
-                panic(err.to_string().as_str())
-            }
-        }
+    fn empty_reader_process(dir_name: &str, vec: &mut Vec<MmapReader>) -> Result<(), Error> {
+        let path = file_path(dir_name)?.join(INIT_LOG_FILE_NAME);
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|err| MmapError::OpenErr(err.to_string()))?;
+        vec.push(Self::new(INIT_LOG_FILE_NAME, Self::map(&file)?));
+        Ok(())
     }
 
-    /// 根据queue_consume 读取一个消息
-    ///
-    /// offset  log 文件物理位置偏移
-    ///
-    /// size    读取的长度
-    pub fn read(offset: u64, size: u32) -> Vec<u8> {
-        // commit log 文件索引
-        let index = (offset / CONFIG.commit_log_file_size) as usize;
-        let reader = MMAP_READERS.get(index).unwrap();
+    /// 只读映射文件
+    fn map(file: &File) -> Result<Mmap, MmapError> {
+        unsafe { MmapOptions::new().map(file) }.map_err(|err| MmapError::MmapErr(err.to_string()))
+    }
+}
 
-        let start = offset as usize;
-        let len = (offset + size as u64) as usize;
-        let data = &reader.reader[start..len];
-        data.to_vec()
+/// 根据物理偏移量与消息大小读取一条完整的消息
+///
+/// 数据超出文件范围、不完整或 CRC 校验失败时返回错误
+pub fn read_message(store: &Store, physical_offset: u64, size: u32) -> Result<Message, Error> {
+    if size < Message::mix_len() {
+        return Err(Error::Corrupt(format!(
+            "消息大小 {size} 小于最小长度，物理偏移量 {physical_offset}"
+        )));
     }
+    let data = store.commit_log.read(physical_offset, size)?;
+    // 跳过 msg_len 自身的 4 字节
+    let mut body = data[4..].to_vec();
+    Message::deserialize_binary(&mut body, size - 4)
 }
 
-#[cfg(test)]
+/// 下一条消息写入的物理偏移量
+pub fn max_offset(store: &Store) -> u64 {
+    store.commit_log.max_offset.load(Ordering::Acquire)
+}
+
+/// 已写入索引的位置，此位置之前的消息均已写入 consume_queue
+pub(crate) fn dispatched_offset(store: &Store) -> u64 {
+    store.commit_log.dispatched_offset.load(Ordering::Acquire)
+}
 
+/// 只根据物理偏移量读取一条消息，消息大小从消息头部读取
+///
+/// 偏移量处没有消息时返回 [`Error::NotFound`]
+pub fn lookup(store: &Store, physical_offset: u64) -> Result<Message, Error> {
+    let not_found = || Error::NotFound(format!("commit_log 物理偏移量 {physical_offset} 处的消息"));
+    if physical_offset >= max_offset(store) {
+        return Err(not_found());
+    }
+    let head = store.commit_log.read(physical_offset, 4)?;
+    let msg_len = u32::from_le_bytes([head[0], head[1], head[2], head[3]]);
+    if msg_len == 0 {
+        return Err(not_found());
+    }
+    let message = read_message(store, physical_offset, msg_len.saturating_add(4))?;
+    if message.physical_offset != physical_offset {
+        return Err(not_found());
+    }
+    Ok(message)
+}
+
+#[cfg(test)]
 mod tests {
+    use crate::common::config::Config;
     use crate::common::log_util::log_init;
-    use crate::storage::commit_log::CommitLogWriter;
+    use crate::cust_error::{Error, TopicError};
+    use crate::file_util::temp_dir;
+    use crate::storage::commit_log::{
+        lookup, max_offset, put_message, put_system, CommitLogWriter, DIR_NAME,
+    };
     use crate::storage::message::Message;
+    use crate::storage::start_offset::StartOffset;
+    use crate::storage::store::Store;
     use crossbeam::atomic::AtomicCell;
 
     #[test]
     fn test_01_write_message() {
         log_init();
-        let writer = CommitLogWriter::instance();
+        let dir = temp_dir("commit_log");
+        let dir_name = dir.join(DIR_NAME).to_str().unwrap().to_string();
+        let mut writer =
+            CommitLogWriter::open(&dir_name, 200, StartOffset::open(&dir).unwrap()).unwrap();
         let json = String::from("{\"msg_len\":66,\"body_crc\":342342,\"physical_offset\":0,\"send_timestamp\":1232432443,\"store_timestamp\":1232432999,\"body_len\":21,\"body\":\"此情可待成追忆\",\"topic_len\":9,\"topic\":\"topic_oms\",\"prop_len\":0,\"prop\":\"\"}");
-        let message = Message::deserialize_json(&json).serialize_binary();
+        let message = Message::deserialize_json(&json).unwrap().serialize_binary();
         let x = message.as_slice();
-        writer.commit_log_write(x);
+        writer.commit_log_write(x).unwrap();
 
         let json2 = String::from("{\"msg_len\":66,\"body_crc\":342342,\"physical_offset\":0,\"send_timestamp\":1232432443,\"store_timestamp\":1232432999,\"body_len\":21,\"body\":\"只是当时已茫然\",\"topic_len\":9,\"topic\":\"topic_oms\",\"prop_len\":0,\"prop\":\"\"}");
-        let message2 = Message::deserialize_json(&json2).serialize_binary();
+        let message2 = Message::deserialize_json(&json2)
+            .unwrap()
+            .serialize_binary();
         let x2 = message2.as_slice();
-        writer.commit_log_write(x2);
+        writer.commit_log_write(x2).unwrap();
+        assert_eq!(
+            StartOffset::open(&dir).unwrap().read(),
+            message.len() + message2.len()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lookup() {
+        let store = Store::open(Config::new().unwrap(), temp_dir("lookup"))
+            .await
+            .unwrap();
+        let first = put_message(&store, Message::new("topic_lookup", "a", "_delay-60"))
+            .await
+            .unwrap();
+        let second = put_message(&store, Message::new("topic_lookup", "bc", "_delay-60"))
+            .await
+            .unwrap();
+        assert_eq!(lookup(&store, first).unwrap().body(), "a");
+        let message = lookup(&store, second).unwrap();
+        assert_eq!(message.body(), "bc");
+        assert_eq!(max_offset(&store), second + message.msg_len() as u64);
+        // 不是消息起始位置或超出写入位置
+        assert!(lookup(&store, first + 1).is_err());
+        assert!(matches!(
+            lookup(&store, max_offset(&store)),
+            Err(Error::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_oversized() {
+        let config = Config::new().unwrap();
+        let file_size = config.commit_log_file_size as usize;
+        let store = Store::open(config, temp_dir("oversized")).await.unwrap();
+        let body = "a".repeat(file_size);
+        assert!(matches!(
+            put_message(&store, Message::new("topic_oversized", &body, "_delay-60")).await,
+            Err(Error::Protocol(_))
+        ));
+        // 拒绝后写入任务继续处理后续消息
+        let offset = put_message(&store, Message::new("topic_oversized", "a", "_delay-60"))
+            .await
+            .unwrap();
+        assert_eq!(lookup(&store, offset).unwrap().body(), "a");
+
+        let dir = temp_dir("oversized_writer");
+        let dir_name = dir.join(DIR_NAME).to_str().unwrap().to_string();
+        let mut writer =
+            CommitLogWriter::open(&dir_name, 200, StartOffset::open(&dir).unwrap()).unwrap();
+        assert!(matches!(
+            writer.commit_log_offset(201),
+            Err(Error::Protocol(_))
+        ));
+        assert!(matches!(
+            writer.commit_log_write(&[0; 201]),
+            Err(Error::Protocol(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_put_topic_name() {
+        let store = Store::open(Config::new().unwrap(), temp_dir("put_topic_name"))
+            .await
+            .unwrap();
+        for topic in [
+            "",
+            "..",
+            "../x",
+            "a/b",
+            "a\\b",
+            "%DLQ%g1",
+            "%LEVEL%1",
+            "%RETRY%g1%t",
+        ] {
+            assert_eq!(
+                put_message(&store, Message::new(topic, "a", "_delay-60"))
+                    .await
+                    .unwrap_err()
+                    .to_string(),
+                Error::from(TopicError::InvalidName(topic.to_string())).to_string()
+            );
+        }
+        // 系统 topic 中的消费者组名称同样不能作为存储路径
+        assert!(matches!(
+            put_system(&store, Message::new("%RETRY%../g1%t", "a", "_level-1")).await,
+            Err(Error::Topic(TopicError::InvalidName(_)))
+        ));
+        put_system(&store, Message::new("%RETRY%g1%t", "a", "_level-1"))
+            .await
+            .unwrap();
     }
 
     #[test]
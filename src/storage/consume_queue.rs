@@ -1,82 +1,191 @@
 //! 用于构建 commit_log 数据管理,加快消息消费
 
-use crate::common::config::CONFIG;
-use crate::data_process_util::hashcode;
-use crate::file_util::{file_path, get_all_dirs};
+use crate::common::config::Config;
+use crate::common::time_util::now_millis;
+use crate::cust_error::{Error, MmapError, ScheduleError};
+use crate::data_process_util::str_hashcode;
+use crate::file_util::{file_path, get_all_dirs, sorted_commit_log_files};
+use crate::storage::checkpoint::{Checkpoint, TopicsCheckpoint};
+use crate::storage::inspect::{self, RecordStatus};
 use crate::storage::message::Message;
 use crate::storage::mmap::MmapWriter;
-use lazy_static::lazy_static;
-use log::{info, warn};
-use std::collections::HashMap;
-use std::io::Write;
+use crate::storage::store::Store;
+use crate::storage::topic::Tombstones;
+use crate::storage::{commit_log, consumer_offset, ready_queue};
+use byteorder::{LittleEndian, ReadBytesExt};
+use futures::FutureExt;
+use log::{error, info, warn};
+use memmap2::MmapOptions;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::{Arc, Weak};
 use std::time::Duration;
-use byteorder::{LittleEndian, WriteBytesExt};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use tokio::sync::watch::{Receiver, Sender};
-use tokio::sync::{watch, RwLock};
+use tokio::sync::{mpsc, oneshot, watch, RwLock};
+use tokio::time::Instant;
 use tokio_stream::StreamExt;
+use tokio_util::time::delay_queue::Key;
 use tokio_util::time::DelayQueue;
 
 /// 第一个存储文件的名称
 const INIT_LOG_FILE_NAME: &str = "00000000000000000000";
-/// 文件存储目录，最终的目录还需要拼接对应topic的名称
+/// 文件存储目录，相对数据目录，最终的目录还需要拼接对应topic的名称与队列
 ///
 /// |consume_queue
 ///     |topic_test
-///         |filename
-const BASE_DIR_NAME: &str = "store/consume_queue";
+///         |checkpoint
+///         |queue_id
+///             |filename
+pub(crate) const BASE_DIR_NAME: &str = "consume_queue";
+/// 固定延迟级别的 consume_queue 名称前缀，每个级别一个队列，如 %LEVEL%3/0
+///
+/// 同一级别的消息延迟相同，队列内天然按到期时间有序，只需将队首放入延迟队列
+pub(crate) const LEVEL_TOPIC_PREFIX: &str = "%LEVEL%";
 
-lazy_static! {
-    /// topic区分的writer key 就是 topic
-    static ref WRITERS: RwLock<HashMap<String, ConsumeQueueWriter>> = {
-        RwLock::new(writers_init())
-    };
-    /// 存放延迟消息的队列
-    static ref DELAY_QUEUE: RwLock<DelayQueue<QueueMessage>> = {
-        let mut queue = DelayQueue::<QueueMessage>::with_capacity(1024);
-        let (block,duration) = QueueMessage::block_message();
-        // 设置阻塞元素
-        queue.insert(block, duration);
-        RwLock::new(queue)
-    };
+/// 毫秒时间戳与 Instant 换算的基准
+type ClockBase = (Instant, u64);
 
-    /// 传递过期消息的 channel
-    static ref ESCAPE_CHANNEL: Sender<QueueMessage> = {
-        let (tx, mut rx) = watch::channel(QueueMessage::default());
-        // TODO 根据tag_hashcode 创建对应的 接收者，然后将消息存放到对应的队列
-        let mut r2 = rx.clone();
-        tokio::spawn(async move {
-            while rx.changed().await.is_ok() {
-                info!("topic 1 收到到期消息 ： {:?}", *rx.borrow());
-            }
-        });
+/// 旧版本 commit_log 中的消息：物理偏移量 -> (消息大小, tag_hashcode)
+type LegacyRecords = HashMap<u64, (u32, u64)>;
 
-        tokio::spawn(async move {
-            while r2.changed().await.is_ok() {
-                info!("topic 2 收到到期消息 ： {:?}", *r2.borrow());
-            }
-        });
-        tx
-    };
+/// 存储实例的 consume_queue 与延迟队列
+pub(crate) struct ConsumeQueue {
+    /// 存储目录
+    base_dir: String,
+    /// 每个文件的大小
+    file_size: u64,
+    /// topic 队列区分的 writer，key 为 topic/queue_id
+    writers: RwLock<HashMap<String, ConsumeQueueWriter>>,
+    /// 延迟队列调度通道，延迟队列由 process_message 任务独占，避免锁竞争
+    scheduler: UnboundedSender<ScheduleCmd>,
+}
+
+/// 尚未启动的延迟队列调度任务，存储实例创建后启动
+pub(crate) struct ScheduleTask {
+    rx: UnboundedReceiver<ScheduleCmd>,
+    /// 最大延迟毫秒数，用于阻塞元素
+    max_delay: u64,
+}
 
+impl ScheduleTask {
+    /// 启动延迟队列调度任务与到期消息的投递任务
+    pub(crate) fn spawn(self, store: Weak<Store>) {
+        let (fire_tx, fire_rx) = mpsc::unbounded_channel::<Vec<(QueueMessage, Message)>>();
+        tokio::spawn(fire_task(fire_rx, store.clone()));
+        tokio::spawn(process_message(self.rx, fire_tx, store, self.max_delay));
+    }
 }
 
-type ConsumeQueueWriter = MmapWriter;
+impl ConsumeQueue {
+    /// 打开数据目录下的 consume_queue，返回的调度任务需要在存储实例创建后启动
+    pub(crate) fn open(config: &Config, dir: &Path) -> Result<(Self, ScheduleTask), Error> {
+        let base_dir = dir.join(BASE_DIR_NAME).to_string_lossy().to_string();
+        let file_size = config.consume_queue_file_size;
+        let (scheduler, rx) = mpsc::unbounded_channel::<ScheduleCmd>();
+        let consume_queue = ConsumeQueue {
+            writers: RwLock::new(writers_init(&base_dir, file_size)?),
+            base_dir,
+            file_size,
+            scheduler,
+        };
+        let task = ScheduleTask {
+            rx,
+            max_delay: config.max_delay_millis(),
+        };
+        Ok((consume_queue, task))
+    }
+
+    /// 每个文件的大小
+    pub(crate) fn file_size(&self) -> u64 {
+        self.file_size
+    }
+}
+
+/// 延迟队列的调度指令
+#[derive(Debug)]
+enum ScheduleCmd {
+    /// 新增延迟消息，附带到期时间的毫秒时间戳
+    Insert(QueueMessage, u64),
+    /// 修改延迟消息的到期时间
+    Reschedule {
+        physical_offset: u64,
+        delay_time: u64,
+        resp: oneshot::Sender<Result<(), ScheduleError>>,
+    },
+    /// 取消未到期的延迟消息
+    Cancel {
+        physical_offset: u64,
+        resp: oneshot::Sender<Result<(), ScheduleError>>,
+    },
+}
+
+pub(crate) type ConsumeQueueWriter = MmapWriter;
 impl ConsumeQueueWriter {
     /// 创建当前的实例
-    /// dir_name 是base_dir_name/topic
-    fn consume_queue_new(file_name: Option<&str>, dir_name: &str) -> Self {
-        Self::new(
-            file_name,
-            INIT_LOG_FILE_NAME,
-            dir_name,
-            None,
-            CONFIG.consume_queue_file_size,
-        )
+    /// dir_name 是base_dir_name/topic/queue_id
+    ///
+    /// file_name 为 None 时打开最后一个文件并从头扫描恢复写入的位置，Some 用于创建新的写文件
+    pub(crate) fn consume_queue_new(
+        file_name: Option<&str>,
+        dir_name: &str,
+        file_size: u64,
+    ) -> Result<Self, Error> {
+        match file_name {
+            None => Self::consume_queue_open(dir_name, file_size, None),
+            Some(_) => Self::new(file_name, INIT_LOG_FILE_NAME, dir_name, 0, file_size),
+        }
+    }
+
+    /// 打开最后一个文件，从 checkpoint 记录的逻辑偏移量开始向后扫描宕机前已写入的索引，恢复写入的位置
+    ///
+    /// checkpoint 不在最后一个文件中或其之前的位置还未写入时，从文件开头扫描
+    pub(crate) fn consume_queue_open(
+        dir_name: &str,
+        file_size: u64,
+        checkpoint: Option<u64>,
+    ) -> Result<Self, Error> {
+        let mut writer = Self::new(None, INIT_LOG_FILE_NAME, dir_name, 0, file_size)?;
+        let base = writer.file_offset()?;
+        let len = QueueMessage::len() as usize;
+        let mut start = checkpoint
+            .filter(|offset| offset / file_size * file_size == base)
+            .map_or(0, |offset| (offset % file_size) as usize / len * len);
+        if start > 0 && !slot_written(&writer.writer, start - len) {
+            warn!("consume_queue[{dir_name}] checkpoint 之前的索引未写入，从文件开头恢复");
+            start = 0;
+        }
+        let mut position = start;
+        while slot_written(&writer.writer, position) {
+            position += len;
+        }
+        if position > start {
+            info!(
+                "consume_queue[{dir_name}] 恢复 checkpoint 之后写入的索引：{} 条",
+                (position - start) / len
+            );
+        }
+        writer.prev_write_size = position;
+        Ok(writer)
+    }
+
+    /// 当前文件名对应的起始逻辑偏移量
+    pub(crate) fn file_offset(&self) -> Result<u64, Error> {
+        file_base(&self.file_name)
     }
 
-    /// 写数据
-    fn consume_queue_write(&mut self, data: &[u8]) {
+    /// 下一条索引写入的逻辑偏移量
+    pub(crate) fn write_offset(&self) -> Result<u64, Error> {
+        Ok(self.file_offset()? + self.prev_write_size as u64)
+    }
+
+    /// 写数据，返回数据在 consume_queue 中的逻辑偏移量
+    pub(crate) fn consume_queue_write(&mut self, data: &[u8]) -> Result<u64, Error> {
+        let file_offset = self.file_offset()?;
         let mut buf = &mut self.writer[self.prev_write_size..];
 
         info!(
@@ -85,23 +194,20 @@ impl ConsumeQueueWriter {
             buf.len(),
             data.len()
         );
-        // 这里-8的原因是最后8个字节存储当前写入的位置
-        if buf.len()-8 < data.len() {
-            self.consume_queue_new_writer_create();
-            self.consume_queue_write(data);
-            return;
+        // 写入的位置由 checkpoint 记录，文件中只存储索引数据
+        if buf.len() < data.len() {
+            self.consume_queue_new_writer_create()?;
+            return self.consume_queue_write(data);
         }
-        buf.write_all(data).unwrap();
+        let queue_offset = file_offset + self.prev_write_size as u64;
+        buf.write_all(data)?;
         self.prev_write_size += data.len();
-        // 存储写入的位置
-        let end = buf.len()-8;
-        let mut start_offset_buf = &mut buf[end..];
-        start_offset_buf.write_u64::<LittleEndian>(self.prev_write_size as u64).unwrap();
+        Ok(queue_offset)
     }
 
     /// 当前commit_log文件已满，开始创建新的文件
-    fn consume_queue_new_writer_create(&mut self) {
-        let curr = u64::from_str(self.file_name.as_str()).unwrap();
+    fn consume_queue_new_writer_create(&mut self) -> Result<(), Error> {
+        let curr = self.file_offset()?;
         info!(
             "当前 consume_queue 文件[{}]已满，开始创建新的文件",
             self.file_name
@@ -109,31 +215,999 @@ impl ConsumeQueueWriter {
 
         let new_name = format!(
             "{number:>0width$}",
-            number = curr + CONFIG.consume_queue_file_size,
+            number = curr + self.file_size,
             width = 20
         );
-        let new_writer = Self::consume_queue_new(Some(new_name.as_str()),"");
+        let new_writer = Self::consume_queue_new(
+            Some(new_name.as_str()),
+            &self.dir_name.clone(),
+            self.file_size,
+        )?;
         self.new_writer_create(&new_name, new_writer);
+        Ok(())
     }
 }
 
-fn writers_init() -> HashMap<String, ConsumeQueueWriter> {
+/// consume_queue 文件名对应的起始逻辑偏移量
+fn file_base(file_name: &str) -> Result<u64, Error> {
+    u64::from_str(file_name)
+        .map_err(|_| Error::Corrupt(format!("consume_queue 文件名不合法：{file_name}")))
+}
+
+/// data 中 position 位置是否已写入索引，已写入的索引大小不为 0
+fn slot_written(data: &[u8], position: usize) -> bool {
+    data.get(position..position + QueueMessage::len() as usize)
+        .is_some_and(|slot| QueueMessage::deserialize_binary(slot, 0).size != 0)
+}
+
+/// 初始化 base_dir_name 下每个 topic 每个队列的 writer，key 为 topic/queue_id
+///
+/// 写入的位置从 topic 的 checkpoint 恢复
+pub(crate) fn writers_init(
+    base_dir_name: &str,
+    file_size: u64,
+) -> Result<HashMap<String, ConsumeQueueWriter>, Error> {
     let mut map = HashMap::<String, ConsumeQueueWriter>::with_capacity(1024);
-    let path = file_path(BASE_DIR_NAME);
-    get_all_dirs(&path).iter().for_each(|ele| {
-        let key = ele.file_name().to_str().unwrap().to_string();
-        let dir_name = format!("{BASE_DIR_NAME}/{key}");
-        let writer = ConsumeQueueWriter::consume_queue_new(None, &dir_name);
-        info!("构建 consume_queue_writer：{:?}", writer);
-        map.insert(key, writer);
+    let path = file_path(base_dir_name)?;
+    for topic in get_all_dirs(&path)? {
+        let topic = topic.file_name().to_string_lossy().to_string();
+        let checkpoint = Checkpoint::load(&path.join(&topic)).unwrap_or_default();
+        for queue in get_all_dirs(&path.join(&topic))? {
+            let queue = queue.file_name().to_string_lossy().to_string();
+            let queue_offset = u32::from_str(&queue)
+                .ok()
+                .and_then(|queue_id| checkpoint.queues.get(&queue_id).copied());
+            let key = format!("{topic}/{queue}");
+            let dir_name = format!("{base_dir_name}/{key}");
+            let writer =
+                ConsumeQueueWriter::consume_queue_open(&dir_name, file_size, queue_offset)?;
+            info!("构建 consume_queue_writer：{:?}", writer);
+            map.insert(key, writer);
+        }
+    }
+    Ok(map)
+}
+
+/// 创建新队列的 writer，并立即将队列写入所在 topic 的 checkpoint
+///
+/// 没有 checkpoint 的 topic 目录被视为旧版本的数据，因此需要在写入索引之前创建
+pub(crate) fn queue_writer_create(
+    base_dir_name: &str,
+    queue_name: &str,
+    file_size: u64,
+    physical_offset: u64,
+) -> Result<ConsumeQueueWriter, Error> {
+    let writer = ConsumeQueueWriter::consume_queue_new(
+        None,
+        &format!("{base_dir_name}/{queue_name}"),
+        file_size,
+    )?;
+    update_checkpoint(
+        base_dir_name,
+        queue_name,
+        writer.write_offset()?,
+        physical_offset,
+    )?;
+    Ok(writer)
+}
+
+/// 更新 topic 的 checkpoint 中一个队列的写入位置与已写入索引的 commit_log 位置
+fn update_checkpoint(
+    base_dir_name: &str,
+    queue_name: &str,
+    queue_offset: u64,
+    physical_offset: u64,
+) -> Result<(), Error> {
+    let (topic, queue_id) = split_queue_name(queue_name)
+        .ok_or_else(|| Error::Corrupt(format!("队列名称不合法：{queue_name}")))?;
+    let topic_dir = Path::new(base_dir_name).join(topic);
+    let mut checkpoint = Checkpoint::load(&topic_dir).unwrap_or_default();
+    checkpoint.physical_offset = physical_offset;
+    checkpoint.queues.insert(queue_id, queue_offset);
+    checkpoint.persist(&topic_dir)
+}
+
+/// 按 topic 持久化 writers 的写入位置，physical_offset 为已写入索引的 commit_log 位置
+pub(crate) fn persist_checkpoints(
+    base_dir_name: &str,
+    writers: &HashMap<String, ConsumeQueueWriter>,
+    physical_offset: u64,
+) {
+    let mut checkpoints = HashMap::<&str, Checkpoint>::new();
+    for (key, writer) in writers {
+        let Some((topic, queue_id)) = split_queue_name(key) else {
+            continue;
+        };
+        match writer.write_offset() {
+            Ok(queue_offset) => {
+                let checkpoint = checkpoints.entry(topic).or_insert_with(|| Checkpoint {
+                    physical_offset,
+                    ..Checkpoint::default()
+                });
+                checkpoint.queues.insert(queue_id, queue_offset);
+            }
+            Err(err) => warn!("读取写入位置失败：{key} {err}"),
+        }
+    }
+    for (topic, checkpoint) in checkpoints {
+        if let Err(err) = checkpoint.persist(&Path::new(base_dir_name).join(topic)) {
+            error!("持久化 checkpoint 失败：{base_dir_name}/{topic} {err}");
+        }
+    }
+}
+
+/// 持久化 consume_queue 与 ready_queue 的 checkpoint，以及 consume_queue 目录下所有的 topic
+///
+/// 先读取已写入索引的 commit_log 位置再读取写入位置，保证该位置之前的消息都在记录的写入位置之前
+pub(crate) async fn checkpoint(store: &Store) {
+    let physical_offset = commit_log::dispatched_offset(store);
+    let consume_queue = &store.consume_queue;
+    persist_checkpoints(
+        &consume_queue.base_dir,
+        &*consume_queue.writers.read().await,
+        physical_offset,
+    );
+    let topics = TopicsCheckpoint {
+        physical_offset,
+        topics: topic_names(&consume_queue.base_dir).into_iter().collect(),
+    };
+    if let Err(err) = topics.persist(Path::new(&consume_queue.base_dir)) {
+        error!("持久化 topic 记录失败：{} {err}", consume_queue.base_dir);
+    }
+    ready_queue::checkpoint(store, physical_offset).await;
+}
+
+/// 启动定时持久化 checkpoint 的任务，存储实例释放后任务退出
+pub(crate) fn checkpoint_task(store: &Arc<Store>) {
+    let interval = Duration::from_millis(store.config().checkpoint_interval);
+    let store = Arc::downgrade(store);
+    tokio::spawn(async move {
+        info!("checkpoint 定时持久化，间隔：{interval:?}");
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let Some(store) = store.upgrade() else {
+                break;
+            };
+            checkpoint(&store).await;
+        }
+    });
+}
+
+/// base_dir_name 下所有 topic 的名称，目录不存在或读取失败时返回空
+///
+/// 只读取目录，不会创建，离线检查时不能改变数据目录的状态
+pub(crate) fn topic_names(base_dir_name: &str) -> Vec<String> {
+    let path = Path::new(base_dir_name).to_path_buf();
+    if !path.exists() {
+        return Vec::new();
+    }
+    get_all_dirs(&path)
+        .inspect_err(|err| warn!("读取目录失败：{base_dir_name} {err}"))
+        .unwrap_or_default()
+        .iter()
+        .map(|dir| dir.file_name().to_string_lossy().to_string())
+        .collect()
+}
+
+/// base_dir_name 下 topic 已存在的队列的存储目录，目录读取失败时返回空
+pub(crate) fn queue_dirs(base_dir_name: &str, topic: &str) -> Vec<String> {
+    let path = Path::new(base_dir_name).join(topic);
+    if !path.exists() {
+        return Vec::new();
+    }
+    get_all_dirs(&path)
+        .inspect_err(|err| warn!("读取目录失败：{} {err}", path.display()))
+        .unwrap_or_default()
+        .iter()
+        .map(|dir| {
+            format!(
+                "{base_dir_name}/{topic}/{}",
+                dir.file_name().to_string_lossy()
+            )
+        })
+        .collect()
+}
+
+/// 读取 dir_name 目录下所有已写入的索引数据，目录不存在时返回空
+pub(crate) fn queue_entries(dir_name: &str) -> Result<Vec<QueueMessage>, Error> {
+    let mut entries = Vec::new();
+    if !Path::new(dir_name).exists() {
+        return Ok(entries);
+    }
+    for file in sorted_commit_log_files(dir_name)? {
+        let base = file_base(&file.file_name().to_string_lossy())?;
+        entries.extend(file_entries(&file.path(), base)?);
+    }
+    Ok(entries)
+}
+
+/// 读取起始逻辑偏移量为 base 的一个文件中已写入的索引数据
+fn file_entries(path: &Path, base: u64) -> Result<Vec<QueueMessage>, Error> {
+    let mut entries = Vec::new();
+    let file = File::open(path).map_err(|err| MmapError::OpenErr(err.to_string()))?;
+    if file.metadata()?.len() == 0 {
+        return Ok(entries);
+    }
+    let reader = unsafe { MmapOptions::new().map(&file) }
+        .map_err(|err| MmapError::MmapErr(err.to_string()))?;
+    let len = QueueMessage::len() as usize;
+    // 索引连续写入，第一个未写入的位置之后没有数据
+    let mut start = 0;
+    while slot_written(&reader, start) {
+        entries.push(QueueMessage::deserialize_binary(
+            &reader[start..start + len],
+            base + start as u64,
+        ));
+        start += len;
+    }
+    Ok(entries)
+}
+
+/// 删除 dir_name 中 current_base 之前连续过期的文件，返回删除后剩余的第一个文件的起始逻辑偏移量，没有删除时返回 None
+///
+/// 文件中所有消息的到期时间都不晚于 expire_before 时过期，fired_only 为 true 时还要求所有消息都已到期或已取消，
+/// 遇到未过期的文件即停止，保证剩余的文件连续，消息无法读取时视为未过期
+pub(crate) fn remove_expired_files(
+    store: &Store,
+    dir_name: &str,
+    current_base: u64,
+    file_size: u64,
+    expire_before: u64,
+    fired_only: bool,
+) -> Result<Option<u64>, Error> {
+    let mut first = None;
+    for file in sorted_commit_log_files(dir_name)? {
+        let base = file_base(&file.file_name().to_string_lossy())?;
+        if base >= current_base {
+            break;
+        }
+        let expired = file_entries(&file.path(), base)?.iter().all(|entry| {
+            (entry.fired || !fired_only)
+                && entry
+                    .deadline(store)
+                    .is_ok_and(|deadline| deadline <= expire_before)
+        });
+        if !expired {
+            break;
+        }
+        fs::remove_file(file.path())?;
+        info!("删除过期的索引文件：{}", file.path().display());
+        first = Some(base + file_size);
+    }
+    Ok(first)
+}
+
+/// 删除 topic 所有队列中已全部到期且到期时间不晚于 expire_before 的文件，正在写入的文件不会删除
+///
+/// 返回之前的消息不再需要索引的 commit_log 位置，即剩余索引与延迟级别队列中 topic 未到期消息的最小物理偏移量，
+/// 没有删除文件时返回 None
+pub(crate) async fn clean_expired(store: &Store, topic: &str, expire_before: u64) -> Option<u64> {
+    // 此位置之前的消息都已写入索引，没有剩余的索引时之前的消息都已随文件删除
+    let dispatched = commit_log::dispatched_offset(store);
+    let queues = current_files(&*store.consume_queue.writers.read().await, topic);
+    let file_size = store.consume_queue.file_size;
+    let mut removed = false;
+    for (queue_name, current_base) in &queues {
+        let dir_name = queue_dir(store, queue_name);
+        match remove_expired_files(
+            store,
+            &dir_name,
+            *current_base,
+            file_size,
+            expire_before,
+            true,
+        ) {
+            Ok(first) => removed |= first.is_some(),
+            Err(err) => warn!("删除过期的索引文件失败：{dir_name} {err}"),
+        }
+    }
+    if !removed {
+        return None;
+    }
+    let remaining = queues.iter().filter_map(|(queue_name, _)| {
+        entries_or_empty(&queue_dir(store, queue_name))
+            .first()
+            .map(QueueMessage::physical_offset)
+    });
+    let levels = level_queue_dirs(store)
+        .into_iter()
+        .flat_map(|dir_name| entries_or_empty(&dir_name))
+        .filter(|entry| !entry.fired)
+        .filter(|entry| {
+            commit_log::read_message(store, entry.physical_offset, entry.size)
+                .is_ok_and(|message| message.topic == topic)
+        })
+        .map(|entry| entry.physical_offset)
+        .collect::<Vec<_>>();
+    Some(remaining.chain(levels).fold(dispatched, u64::min))
+}
+
+/// writers 中 topic 每个队列的名称与正在写入的文件的起始逻辑偏移量
+pub(crate) fn current_files(
+    writers: &HashMap<String, ConsumeQueueWriter>,
+    topic: &str,
+) -> Vec<(String, u64)> {
+    writers
+        .iter()
+        .filter(|(queue_name, _)| {
+            split_queue_name(queue_name).is_some_and(|(name, _)| name == topic)
+        })
+        .filter_map(|(queue_name, writer)| Some((queue_name.clone(), writer.file_offset().ok()?)))
+        .collect()
+}
+
+/// 读取索引数据用于统计，读取失败时记录日志并返回空
+fn entries_or_empty(dir_name: &str) -> Vec<QueueMessage> {
+    queue_entries(dir_name)
+        .inspect_err(|err| warn!("读取 consume_queue 失败：{dir_name} {err}"))
+        .unwrap_or_default()
+}
+
+/// 所有存在延迟消息索引的 topic，不包括延迟级别队列
+pub(crate) fn topics(store: &Store) -> Vec<String> {
+    let mut topics = topic_names(&store.consume_queue.base_dir);
+    topics.retain(|topic| !topic.starts_with(LEVEL_TOPIC_PREFIX));
+    topics
+}
+
+/// topic 已存在的延迟消息队列数量
+pub(crate) fn existing_queue_count(store: &Store, topic: &str) -> u32 {
+    queue_dir_count(&store.consume_queue.base_dir, topic)
+}
+
+/// 所有延迟级别队列的存储目录
+fn level_queue_dirs(store: &Store) -> Vec<String> {
+    let base_dir = &store.consume_queue.base_dir;
+    topic_names(base_dir)
+        .iter()
+        .filter(|topic| topic.starts_with(LEVEL_TOPIC_PREFIX))
+        .flat_map(|topic| queue_dirs(base_dir, topic))
+        .collect()
+}
+
+/// topic 尚未到期的消息数量与最早的到期时间的毫秒时间戳
+///
+/// 到期时间需要读取 commit_log 中的存储时间，延迟级别队列中的消息还需要读取 commit_log 判断所属 topic
+pub(crate) fn pending(store: &Store, topic: &str) -> (u64, Option<u64>) {
+    pending_with(store, topic, &level_pending(store))
+}
+
+/// 同 [`pending`]，levels 为 [`level_pending`] 的结果，统计多个 topic 时只读取一次延迟级别队列
+pub(crate) fn pending_with(
+    store: &Store,
+    topic: &str,
+    levels: &HashMap<String, (u64, Option<u64>)>,
+) -> (u64, Option<u64>) {
+    let (mut count, mut next_due) = levels.get(topic).copied().unwrap_or_default();
+    let entries = queue_dirs(&store.consume_queue.base_dir, topic)
+        .into_iter()
+        .flat_map(|dir_name| entries_or_empty(&dir_name))
+        .filter(|entry| !entry.fired);
+    for entry in entries {
+        count += 1;
+        if let Ok(deadline) = entry.deadline(store) {
+            next_due = earliest(next_due, deadline);
+        }
+    }
+    (count, next_due)
+}
+
+/// 延迟级别队列中尚未到期的消息，按所属 topic 统计数量与最早的到期时间
+pub(crate) fn level_pending(store: &Store) -> HashMap<String, (u64, Option<u64>)> {
+    let mut levels = HashMap::<String, (u64, Option<u64>)>::new();
+    let level_entries = level_queue_dirs(store)
+        .into_iter()
+        .flat_map(|dir_name| entries_or_empty(&dir_name))
+        .filter(|entry| !entry.fired);
+    for entry in level_entries {
+        if let Ok(message) = commit_log::read_message(store, entry.physical_offset, entry.size) {
+            let deadline = message.store_timestamp() + entry.delay_time;
+            let (count, next_due) = levels.entry(message.topic).or_default();
+            *count += 1;
+            *next_due = earliest(*next_due, deadline);
+        }
+    }
+    levels
+}
+
+/// 合并最早的到期时间
+fn earliest(next_due: Option<u64>, deadline: u64) -> Option<u64> {
+    Some(next_due.map_or(deadline, |due| due.min(deadline)))
+}
+
+/// 删除 topic 及其重试 topic 的索引文件，延迟级别队列中属于这些 topic 的消息标记为已到期
+///
+/// 已在延迟队列中的消息到期时发现索引已不存在或已标记，不再投递
+pub(crate) async fn remove_topic(store: &Store, topic: &str) {
+    let base_dir = &store.consume_queue.base_dir;
+    let file_size = store.consume_queue.file_size;
+    let mut writers = store.consume_queue.writers.write().await;
+    for name in topic_names(base_dir) {
+        if !ready_queue::belongs_to_topic(&name, topic) {
+            continue;
+        }
+        writers.retain(|key, _| !key.starts_with(&format!("{name}/")));
+        if let Err(err) = fs::remove_dir_all(Path::new(base_dir).join(&name)) {
+            warn!("删除 consume_queue 目录失败：{name} {err}");
+        }
+    }
+    for dir_name in level_queue_dirs(store) {
+        for mut entry in entries_or_empty(&dir_name) {
+            if entry.fired {
+                continue;
+            }
+            let message = match commit_log::read_message(store, entry.physical_offset, entry.size) {
+                Ok(message) => message,
+                Err(err) => {
+                    warn!("读取延迟级别消息失败：{entry:?} {err}");
+                    continue;
+                }
+            };
+            if ready_queue::belongs_to_topic(&message.topic, topic) {
+                entry.fired = true;
+                let data = entry.serialize_binary();
+                if let Err(err) =
+                    consume_queue_update(&dir_name, file_size, entry.queue_offset, &data)
+                {
+                    warn!("标记延迟级别消息失败：{entry:?} {err}");
+                }
+            }
+        }
+    }
+}
+
+/// topic 中一个队列的名称，即相对存储目录的路径
+pub(crate) fn queue_key(topic: &str, queue_id: u32) -> String {
+    format!("{topic}/{queue_id}")
+}
+
+/// 消息索引所在的 consume_queue 名称，固定延迟级别的消息写入对应级别的队列
+pub(crate) fn queue_name(message: &Message) -> String {
+    match message.delay_level() {
+        Some(level) => queue_key(&level_queue_name(level), 0),
+        None => queue_key(&message.topic, message.queue_id()),
+    }
+}
+
+/// 延迟级别对应的 consume_queue 名称
+fn level_queue_name(level: u8) -> String {
+    format!("{LEVEL_TOPIC_PREFIX}{level}")
+}
+
+/// 延迟级别队列的存储目录
+fn level_queue_dir(store: &Store, level: u8) -> String {
+    queue_dir(store, &queue_key(&level_queue_name(level), 0))
+}
+
+/// 将队列名称拆分为 topic 与 queue_id，名称不合法时返回 None
+pub(crate) fn split_queue_name(queue_name: &str) -> Option<(&str, u32)> {
+    let (topic, queue_id) = queue_name.rsplit_once('/')?;
+    Some((topic, u32::from_str(queue_id).ok()?))
+}
+
+/// base_dir_name 下 topic 已存在的队列数量，即最大的队列 id 加一
+pub(crate) fn queue_dir_count(base_dir_name: &str, topic: &str) -> u32 {
+    queue_dirs(base_dir_name, topic)
+        .iter()
+        .filter_map(|dir_name| split_queue_name(dir_name))
+        .map(|(_, queue_id)| queue_id + 1)
+        .max()
+        .unwrap_or_default()
+}
+
+/// consume_queue 名称对应的存储目录
+fn queue_dir(store: &Store, queue_name: &str) -> String {
+    format!("{}/{queue_name}", store.consume_queue.base_dir)
+}
+
+/// 将写入 commit_log 的消息构建索引写入对应 topic 的 consume_queue，并加入延迟队列
+///
+/// delay_time 为已经校验过的延迟毫秒数
+pub async fn dispatch(store: &Store, message: &Message, delay_time: u64) -> Result<(), Error> {
+    let (mut queue_message, deadline) = QueueMessage::from_message(message, delay_time);
+    {
+        let queue_name = queue_name(message);
+        let mut writers = store.consume_queue.writers.write().await;
+        let writer = match writers.entry(queue_name.clone()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(queue_writer_create(
+                &store.consume_queue.base_dir,
+                &queue_name,
+                store.consume_queue.file_size,
+                commit_log::dispatched_offset(store),
+            )?),
+        };
+        queue_message.queue_offset =
+            writer.consume_queue_write(&queue_message.serialize_binary())?;
+    }
+    store
+        .consume_queue
+        .scheduler
+        .send(ScheduleCmd::Insert(queue_message, deadline))
+        .map_err(|_| Error::Closed)
+}
+
+/// 修改一条未到期消息的延迟时间，delay_time 为从当前时刻起的延迟毫秒数
+///
+/// 新的到期时间会同步写回 consume_queue，重启恢复后依然生效
+///
+/// 固定延迟级别的消息不支持修改到期时间，否则会破坏级别队列的有序性
+pub async fn reschedule(
+    store: &Store,
+    physical_offset: u64,
+    delay_time: u64,
+) -> Result<(), ScheduleError> {
+    let (resp, rx) = oneshot::channel();
+    store
+        .consume_queue
+        .scheduler
+        .send(ScheduleCmd::Reschedule {
+            physical_offset,
+            delay_time,
+            resp,
+        })
+        .map_err(|_| ScheduleError::Closed)?;
+    rx.await.map_err(|_| ScheduleError::Closed)?
+}
+
+/// 取消一条未到期的延迟消息，消息在 consume_queue 中标记为已到期，不再投递，重启后也不会恢复
+///
+/// 固定延迟级别的消息与已到期的消息不支持取消
+pub async fn cancel(store: &Store, physical_offset: u64) -> Result<(), ScheduleError> {
+    let (resp, rx) = oneshot::channel();
+    store
+        .consume_queue
+        .scheduler
+        .send(ScheduleCmd::Cancel {
+            physical_offset,
+            resp,
+        })
+        .map_err(|_| ScheduleError::Closed)?;
+    rx.await.map_err(|_| ScheduleError::Closed)?
+}
+
+/// queue_offset 所在的 consume_queue 文件路径
+fn consume_queue_file(dir_name: &str, file_size: u64, queue_offset: u64) -> PathBuf {
+    let file_name = format!(
+        "{number:>0width$}",
+        number = queue_offset / file_size * file_size,
+        width = 20
+    );
+    Path::new(dir_name).join(file_name)
+}
+
+/// 读取 dir_name 目录下 queue_offset 位置的索引数据，尚未写入时返回 None
+pub(crate) fn consume_queue_read(
+    dir_name: &str,
+    file_size: u64,
+    queue_offset: u64,
+) -> Option<QueueMessage> {
+    let file = OpenOptions::new()
+        .read(true)
+        .open(consume_queue_file(dir_name, file_size, queue_offset))
+        .ok()?;
+    let mmap = unsafe { MmapOptions::new().map(&file).ok()? };
+    let start = (queue_offset % file_size) as usize;
+    let data = mmap.get(start..start + QueueMessage::len() as usize)?;
+    let queue_message = QueueMessage::deserialize_binary(data, queue_offset);
+    (!queue_message.is_block_message()).then_some(queue_message)
+}
+
+/// queue_offset 之后下一条索引数据的逻辑偏移量，与写入时的文件滚动规则保持一致
+pub(crate) fn next_queue_offset(queue_offset: u64, file_size: u64) -> u64 {
+    let len = QueueMessage::len() as u64;
+    let next = queue_offset + len;
+    if next % file_size + len > file_size {
+        (next / file_size + 1) * file_size
+    } else {
+        next
+    }
+}
+
+/// 覆盖写 dir_name 目录下 queue_offset 位置的索引数据
+fn consume_queue_update(
+    dir_name: &str,
+    file_size: u64,
+    queue_offset: u64,
+    data: &[u8],
+) -> Result<(), Error> {
+    let path = consume_queue_file(dir_name, file_size, queue_offset);
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .map_err(|err| MmapError::OpenErr(err.to_string()))?;
+    let mut mmap = MmapWriter::mmap_mut_create(&file, file_size)?;
+    let start = (queue_offset % file_size) as usize;
+    let mut buf = &mut mmap[start..start + data.len()];
+    buf.write_all(data)?;
+    mmap.flush()?;
+    Ok(())
+}
+
+/// 根据 commit_log 重新生成数据目录 dir 下所有队列的索引文件与写入位置，返回每个队列重新生成后的索引数量
+///
+/// 物理偏移量小于 from 的原有索引保留，之后的索引根据 commit_log 中物理偏移量不小于 from 的有效记录重新生成，
+/// 原有索引的到期标记与修改过的延迟时间会被保留，已写入 ready_queue 的消息标记为已到期
+///
+/// 已删除的 topic 在删除前写入的消息根据 config/deleted_topics.json 中的记录跳过
+///
+/// 需要在存储实例打开之前调用，数据目录下没有 consume_queue 目录时存储实例打开时会自动从头重建
+pub fn rebuild(dir: &Path, config: &Config, from: u64) -> Result<BTreeMap<String, usize>, Error> {
+    migrate_legacy(dir, config)?;
+    rebuild_queues(dir, config, from, None)
+}
+
+/// 数据目录下已有 commit_log 但缺少 consume_queue 目录，需要重建索引
+pub(crate) fn index_missing(dir: &Path) -> bool {
+    dir.join(commit_log::DIR_NAME).exists() && !dir.join(BASE_DIR_NAME).exists()
+}
+
+/// 根据 commit_log 重新生成索引文件，queues 为 None 时重新生成已有的队列与 commit_log 中出现的所有队列
+pub(crate) fn rebuild_queues(
+    dir: &Path,
+    config: &Config,
+    from: u64,
+    queues: Option<&BTreeSet<String>>,
+) -> Result<BTreeMap<String, usize>, Error> {
+    let base_dir = dir.join(BASE_DIR_NAME).to_string_lossy().to_string();
+    let file_size = config.consume_queue_file_size;
+    let mut rebuilding = BTreeMap::<String, RebuildingQueue>::new();
+    let names = match queues {
+        Some(queues) => queues.iter().cloned().collect(),
+        None => topic_names(&base_dir)
+            .iter()
+            .flat_map(|topic| queue_dirs(&base_dir, topic))
+            .map(|dir_name| {
+                dir_name
+                    .trim_start_matches(&format!("{base_dir}/"))
+                    .to_string()
+            })
+            .collect::<Vec<_>>(),
+    };
+    for queue in names {
+        let rebuild = RebuildingQueue::load(&format!("{base_dir}/{queue}"), from)?;
+        rebuilding.insert(queue, rebuild);
+    }
+    let delivered = delivered_offsets(dir)?;
+    // 重新生成的队列中 commit_log 最后一条有效消息之前的消息均已写入索引
+    let mut physical_offset = 0;
+    let tombstones = Tombstones::load(dir);
+    let mut result = Ok(());
+    inspect::walk_commit_log(dir, from, |record| {
+        let Some(message) = record.message.filter(|_| record.status == RecordStatus::Ok) else {
+            return;
+        };
+        physical_offset = physical_offset.max(message.physical_offset + record.size as u64);
+        if result.is_err() || tombstones.covers(&message.topic, message.physical_offset) {
+            return;
+        }
+        let queue = queue_name(&message);
+        if queues.is_some_and(|queues| !queues.contains(&queue)) {
+            return;
+        }
+        if !rebuilding.contains_key(&queue) {
+            match RebuildingQueue::load(&format!("{base_dir}/{queue}"), from) {
+                Ok(rebuild) => rebuilding.insert(queue.clone(), rebuild),
+                Err(err) => {
+                    result = Err(err);
+                    return;
+                }
+            };
+        }
+        // 只保留索引，不在内存中保存消息内容
+        let rebuild = rebuilding.get_mut(&queue).unwrap();
+        let previous = rebuild.previous.get(&message.physical_offset);
+        let entry = rebuilt_entry(
+            config,
+            &message,
+            previous,
+            delivered.contains(&message.physical_offset),
+        );
+        rebuild.entries.push(entry);
+    })?;
+    result?;
+    let mut rebuilt = BTreeMap::new();
+    for (queue, rebuild) in rebuilding {
+        let entries = rebuild.entries;
+        rewrite_queue(&base_dir, &queue, file_size, &entries, physical_offset)?;
+        info!("重新生成队列[{queue}]的索引：{} 条", entries.len());
+        rebuilt.insert(queue, entries.len());
+    }
+    Ok(rebuilt)
+}
+
+/// 重新生成中的队列
+struct RebuildingQueue {
+    /// 物理偏移量不小于 from 的原有索引，保留其中的延迟时间与到期标记
+    previous: HashMap<u64, QueueMessage>,
+    /// 重新生成的索引，物理偏移量小于 from 的原有索引保持不变
+    entries: Vec<QueueMessage>,
+}
+
+impl RebuildingQueue {
+    /// 读取 dir_name 下原有的索引，目录不存在时为空
+    fn load(dir_name: &str, from: u64) -> Result<Self, Error> {
+        let (entries, previous): (Vec<_>, Vec<_>) = queue_entries(dir_name)?
+            .into_iter()
+            .partition(|entry| entry.physical_offset < from);
+        Ok(RebuildingQueue {
+            previous: previous
+                .into_iter()
+                .map(|entry| (entry.physical_offset, entry))
+                .collect(),
+            entries,
+        })
+    }
+}
+
+/// 已到期写入 ready_queue 的消息的物理偏移量
+fn delivered_offsets(dir: &Path) -> Result<HashSet<u64>, Error> {
+    let base_dir = dir
+        .join(ready_queue::BASE_DIR_NAME)
+        .to_string_lossy()
+        .to_string();
+    let mut offsets = HashSet::new();
+    if !Path::new(&base_dir).exists() {
+        return Ok(offsets);
+    }
+    for topic in topic_names(&base_dir) {
+        for dir_name in queue_dirs(&base_dir, &topic) {
+            let entries = queue_entries(&dir_name)?;
+            offsets.extend(entries.iter().map(QueueMessage::physical_offset));
+        }
+    }
+    Ok(offsets)
+}
+
+/// 根据 commit_log 中的消息重新构建索引，用于修复与重建
+///
+/// previous 为原有的索引，大小一致时保留其到期标记与修改过的延迟时间，否则根据消息的延迟属性重新计算延迟时间
+///
+/// delivered 表示消息已写入 ready_queue，此时标记为已到期，避免重复投递
+pub(crate) fn rebuilt_entry(
+    config: &Config,
+    message: &Message,
+    previous: Option<&QueueMessage>,
+    delivered: bool,
+) -> QueueMessage {
+    let (delay_time, fired) = match previous.filter(|entry| entry.size == message.msg_len()) {
+        Some(entry) => (entry.delay_time, entry.fired || delivered),
+        // 死信与重放的消息写入时清除了延迟属性，立即到期
+        None => (
+            message
+                .delay_millis(message.store_timestamp(), config, u64::MAX)
+                .unwrap_or(0),
+            delivered,
+        ),
+    };
+    let (mut entry, _) = QueueMessage::from_message(message, delay_time);
+    entry.fired = fired;
+    entry
+}
+
+/// 删除 base_dir_name 下队列原有的索引文件，按顺序重新写入 entries，逻辑偏移量从 0 开始重新分配，并更新 checkpoint
+///
+/// 延迟队列中保存了逻辑偏移量，只能在存储实例打开之前调用
+pub(crate) fn rewrite_queue(
+    base_dir_name: &str,
+    queue_name: &str,
+    file_size: u64,
+    entries: &[QueueMessage],
+    physical_offset: u64,
+) -> Result<(), Error> {
+    let dir_name = format!("{base_dir_name}/{queue_name}");
+    if Path::new(&dir_name).exists() {
+        fs::remove_dir_all(&dir_name)?;
+    }
+    let mut writer = queue_writer_create(base_dir_name, queue_name, file_size, physical_offset)?;
+    for entry in entries {
+        writer.consume_queue_write(&entry.serialize_binary())?;
+    }
+    writer.writer.flush()?;
+    update_checkpoint(
+        base_dir_name,
+        queue_name,
+        writer.write_offset()?,
+        physical_offset,
+    )
+}
+
+/// 恢复已写入 commit_log 但宕机前还未写入索引的消息
+///
+/// 从所有 topic 的 checkpoint 中最小的 commit_log 位置开始检查，存在 checkpoint 的 topic 只检查不早于其记录位置的消息，
+/// 目录丢失或 checkpoint 无效时从头检查全部消息，已删除的 topic 不会恢复，返回开始检查的位置
+pub(crate) fn recover(dir: &Path, config: &Config) -> Result<u64, Error> {
+    let base_dir = dir.join(BASE_DIR_NAME).to_string_lossy().to_string();
+    let positions = topic_names(&base_dir)
+        .into_iter()
+        .filter_map(|topic| {
+            let checkpoint = Checkpoint::load(&Path::new(&base_dir).join(&topic))?;
+            Some((topic, checkpoint.physical_offset))
+        })
+        .collect::<HashMap<_, _>>();
+    let tombstones = Tombstones::load(dir);
+    let start = recover_start(&base_dir, &positions, &tombstones);
+    info!("从 commit_log 物理偏移量 {start} 开始检查未写入索引的消息");
+    let mut indexed = HashMap::<String, HashSet<u64>>::new();
+    let mut missing = BTreeSet::new();
+    let mut from = u64::MAX;
+    inspect::walk_commit_log(dir, start, |record| {
+        let Some(message) = record.message.filter(|_| record.status == RecordStatus::Ok) else {
+            return;
+        };
+        if tombstones.covers(&message.topic, message.physical_offset) {
+            return;
+        }
+        let queue = queue_name(&message);
+        let position = split_queue_name(&queue)
+            .and_then(|(topic, _)| positions.get(topic))
+            .copied()
+            .unwrap_or(0);
+        if message.physical_offset < position {
+            return;
+        }
+        let offsets = indexed.entry(queue.clone()).or_insert_with(|| {
+            entries_or_empty(&format!("{base_dir}/{queue}"))
+                .iter()
+                .map(QueueMessage::physical_offset)
+                .collect()
+        });
+        if !offsets.contains(&message.physical_offset) {
+            from = from.min(message.physical_offset);
+            missing.insert(queue);
+        }
+    })?;
+    if !missing.is_empty() {
+        warn!("commit_log 中存在未写入索引的消息，重新生成队列：{missing:?}");
+        rebuild_queues(dir, config, from, Some(&missing))?;
+    }
+    Ok(start)
+}
+
+/// 检查未写入索引的消息的起始位置，即 topic 记录与所有 topic 的 checkpoint 中最小的物理偏移量
+///
+/// topic 记录不存在或无效、记录中未删除的 topic 目录丢失，或目录下的 topic 缺少有效的 checkpoint 时，
+/// 无法确定丢失的索引从哪里开始，从头检查
+fn recover_start(base_dir: &str, positions: &HashMap<String, u64>, tombstones: &Tombstones) -> u64 {
+    let Some(recorded) = TopicsCheckpoint::load(Path::new(base_dir)) else {
+        warn!("consume_queue 缺少有效的 topic 记录，从头检查");
+        return 0;
+    };
+    let topics = topic_names(base_dir);
+    if let Some(topic) = topics.iter().find(|topic| !positions.contains_key(*topic)) {
+        warn!("topic[{topic}] 缺少有效的 checkpoint，从头检查");
+        return 0;
+    }
+    let lost = recorded
+        .topics
+        .iter()
+        .find(|topic| !topics.contains(topic) && !tombstones.is_deleted(topic));
+    if let Some(topic) = lost {
+        warn!("topic[{topic}] 的目录已丢失，从头检查");
+        return 0;
+    }
+    positions
+        .values()
+        .copied()
+        .fold(recorded.physical_offset, u64::min)
+}
+
+/// 旧版本在每个索引文件的最后 8 个字节存储写入的位置，每个文件少存储一条索引，也没有 checkpoint
+///
+/// 将没有 checkpoint 的 topic 按当前的文件格式重新写入，ready_queue 的消费进度同步转换，需要在存储实例打开之前调用
+///
+/// 最初版本的索引为 24 个字节，延迟时间为秒，迁移时转换为毫秒
+///
+/// 旧版本的 tag_hashcode 使用随 Rust 版本变化的 DefaultHasher 计算，迁移时根据 commit_log 中消息的 tag 重新计算
+pub(crate) fn migrate_legacy(dir: &Path, config: &Config) -> Result<(), Error> {
+    let file_size = config.consume_queue_file_size;
+    let mut legacy = None;
+    let mut ready_queues = BTreeSet::new();
+    for base in [BASE_DIR_NAME, ready_queue::BASE_DIR_NAME] {
+        let base_dir = dir.join(base).to_string_lossy().to_string();
+        if !Path::new(&base_dir).exists() {
+            continue;
+        }
+        for topic in topic_names(&base_dir) {
+            // checkpoint 校验失败的 topic 已是当前的文件格式，由 recover 从头检查
+            if Checkpoint::exists(&Path::new(&base_dir).join(&topic)) {
+                continue;
+            }
+            // 旧版本的消息均已写入索引，checkpoint 记录 commit_log 的末尾
+            let (physical_offset, records) = match &legacy {
+                Some(legacy) => legacy,
+                None => legacy.insert(legacy_commit_log(dir)?),
+            };
+            for dir_name in queue_dirs(&base_dir, &topic) {
+                let queue = dir_name.trim_start_matches(&format!("{base_dir}/"));
+                let mut entries = legacy_entries(&dir_name, records)?;
+                for entry in entries.iter_mut() {
+                    if let Some((_, tag_hashcode)) = records.get(&entry.physical_offset) {
+                        entry.tag_hashcode = *tag_hashcode;
+                    }
+                }
+                rewrite_queue(&base_dir, queue, file_size, &entries, *physical_offset)?;
+                info!("迁移旧版本的索引文件[{base}/{queue}]：{} 条", entries.len());
+                if base == ready_queue::BASE_DIR_NAME {
+                    ready_queues.insert(queue.to_string());
+                }
+            }
+        }
+    }
+    if !ready_queues.is_empty() {
+        consumer_offset::migrate(dir, &ready_queues, |queue_offset| {
+            legacy_queue_offset(queue_offset, file_size)
+        })?;
+    }
+    Ok(())
+}
+
+/// commit_log 中最后一条有效消息的结束位置，以及每条有效消息的物理偏移量 -> (消息大小, 按当前算法计算的 tag_hashcode)
+fn legacy_commit_log(dir: &Path) -> Result<(u64, LegacyRecords), Error> {
+    let mut end = 0;
+    let mut records = HashMap::new();
+    inspect::walk_commit_log(dir, 0, |record| {
+        let Some(message) = record.message.filter(|_| record.status == RecordStatus::Ok) else {
+            return;
+        };
+        end = message.physical_offset + record.size as u64;
+        let tag_hashcode = str_hashcode(message.tag().unwrap_or_default());
+        records.insert(message.physical_offset, (message.msg_len(), tag_hashcode));
+    })?;
+    Ok((end, records))
+}
+
+/// 按旧版本的文件格式读取 dir_name 目录下的索引，records 为 [`legacy_commit_log`] 读取的消息
+///
+/// 两种旧版本的索引长度不同且没有版本标记，分别解析后选择与 commit_log 中的消息吻合较多的一种
+fn legacy_entries(dir_name: &str, records: &LegacyRecords) -> Result<Vec<QueueMessage>, Error> {
+    let mut files = Vec::new();
+    for file in sorted_commit_log_files(dir_name)? {
+        let data = fs::read(file.path())?;
+        let Some(tail) = data.len().checked_sub(8) else {
+            continue;
+        };
+        let mut reader = Cursor::new(&data[tail..]);
+        // 写入位置损坏时只读取文件范围内的数据
+        let end = (reader.read_u64::<LittleEndian>()? as usize).min(tail);
+        files.push((data, end));
+    }
+    let decode = |len: usize, parse: fn(&[u8]) -> QueueMessage| {
+        files
+            .iter()
+            .flat_map(|(data, end)| data[..end / len * len].chunks_exact(len).map(parse))
+            .collect::<Vec<_>>()
+    };
+    let matched = |entries: &[QueueMessage]| {
+        entries
+            .iter()
+            .filter(|entry| {
+                records
+                    .get(&entry.physical_offset)
+                    .is_some_and(|(size, _)| *size == entry.size)
+            })
+            .count()
+    };
+    let entries = decode(QueueMessage::len() as usize, |data| {
+        QueueMessage::deserialize_binary(data, 0)
     });
-    map
+    let initial = decode(INITIAL_ENTRY_LEN, QueueMessage::deserialize_initial);
+    if matched(&initial) > matched(&entries) {
+        info!("索引文件[{dir_name}]为最初版本的格式，延迟时间转换为毫秒");
+        return Ok(initial);
+    }
+    Ok(entries)
+}
+
+/// 旧版本文件格式中的逻辑偏移量转换为当前文件格式中同一条索引的逻辑偏移量
+fn legacy_queue_offset(queue_offset: u64, file_size: u64) -> u64 {
+    let len = QueueMessage::len() as u64;
+    let index = queue_offset / file_size * ((file_size - 8) / len) + queue_offset % file_size / len;
+    let per_file = file_size / len;
+    index / per_file * file_size + index % per_file * len
 }
-// pub fn send(message: &Message){
-//     let (tx, rx) = spmc_channel();
-//     //
-//
-// }
 
 /// 最优的可能是spsc,但是那样可能会相对复杂，
 ///
@@ -157,57 +1231,149 @@ fn spmc_channel() -> (Sender<Message>, Receiver<Message>) {
     (tx, b)
 }
 
+/// 已到期标记，存储在 size 的最高位
+const FIRED_FLAG: u32 = 1 << 31;
+/// 最初版本的索引长度：物理偏移量 8、数据大小 4、tag 的 hash_code 8、延迟秒数 4
+const INITIAL_ENTRY_LEN: usize = 24;
+
 /// commit_log 索引数据
 #[derive(Debug, Clone, Default)]
 pub struct QueueMessage {
     // commit_log 物理偏移量 8
     physical_offset: u64,
-    // 数据大小 4
+    // 数据大小 4，最高位为已到期标记
     size: u32,
     // tag  的hash_code 8
     tag_hashcode: u64,
-    // 最长支持一年  31_536_000  秒 4
-    pub delay_time: u32,
+    // 相对消息存储时间的延迟，最长支持一年  31_536_000_000  毫秒 8
+    pub delay_time: u64,
+    // 在 consume_queue 中的逻辑偏移量，不参与持久化
+    queue_offset: u64,
+    // 固定延迟级别，0 表示非级别消息，不参与持久化
+    level: u8,
+    // 是否已到期并写入 ready_queue，取消的消息同样标记为已到期
+    fired: bool,
 }
 impl QueueMessage {
-    /// 定长长度 1G 内存可以存储 4473_9242条数据
+    /// 定长长度 1G 内存可以存储 3834_7922条数据
     pub fn len() -> u16 {
-        24_u16
+        28_u16
     }
-    /// 根据 commit_log message 构建一个 QueueMessage
-    pub fn from_message(message: &Message) -> (Self, Duration) {
-        let prop = message.prop.clone();
-        let delay_time = prop.split('-').collect::<Vec<_>>();
-        QueueMessage::new(
+    /// 根据 commit_log message 构建一个 QueueMessage，同时返回到期时间的毫秒时间戳
+    pub fn from_message(message: &Message, delay_time: u64) -> (Self, u64) {
+        let mut queue_message = QueueMessage::new(
             message.physical_offset,
             message.msg_len(),
-            &message.topic,
-            u32::from_str(delay_time.get(1).unwrap()).unwrap(),
-        )
+            message.tag().unwrap_or_default(),
+            delay_time,
+        );
+        queue_message.level = message.delay_level().unwrap_or(0);
+        (queue_message, message.store_timestamp() + delay_time)
     }
 
     ///  创建消息
-    pub fn new(physical_offset: u64, size: u32, tag: &str, delay_time: u32) -> (Self, Duration) {
-        let message = QueueMessage {
+    pub fn new(physical_offset: u64, size: u32, tag: &str, delay_time: u64) -> Self {
+        QueueMessage {
             physical_offset,
             size,
-            tag_hashcode: hashcode(&tag),
+            tag_hashcode: str_hashcode(tag),
             delay_time,
+            queue_offset: 0,
+            level: 0,
+            fired: false,
+        }
+    }
+
+    /// 消息 tag 的 hash_code，没有 tag 时为空字符串的 hash_code
+    pub fn tag_hashcode(&self) -> u64 {
+        self.tag_hashcode
+    }
+
+    /// commit_log 物理偏移量
+    pub fn physical_offset(&self) -> u64 {
+        self.physical_offset
+    }
+
+    /// 消息大小
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// 在所属队列中的逻辑偏移量
+    pub fn queue_offset(&self) -> u64 {
+        self.queue_offset
+    }
+
+    /// 是否已到期或已取消
+    pub fn fired(&self) -> bool {
+        self.fired
+    }
+
+    /// 复制为写入其他队列的索引数据
+    pub(crate) fn copy_to(&self, queue_offset: u64) -> Self {
+        QueueMessage {
+            queue_offset,
+            level: 0,
+            fired: false,
+            ..self.clone()
+        }
+    }
+
+    /// 序列化为 consume_queue 存储的定长字节编码,使用小端序列化
+    pub fn serialize_binary(&self) -> Vec<u8> {
+        let mut v = Vec::<u8>::with_capacity(Self::len() as usize);
+        v.extend(self.physical_offset.to_le_bytes());
+        let size = if self.fired {
+            self.size | FIRED_FLAG
+        } else {
+            self.size
         };
-        let time = message.duration();
-        (message, time)
+        v.extend(size.to_le_bytes());
+        v.extend(self.tag_hashcode.to_le_bytes());
+        v.extend(self.delay_time.to_le_bytes());
+        v
+    }
+
+    /// 从 consume_queue 文件中读取一个 QueueMessage
+    pub fn deserialize_binary(data: &[u8], queue_offset: u64) -> Self {
+        let mut reader = Cursor::new(data);
+        let physical_offset = reader.read_u64::<LittleEndian>().unwrap();
+        let size = reader.read_u32::<LittleEndian>().unwrap();
+        QueueMessage {
+            physical_offset,
+            size: size & !FIRED_FLAG,
+            tag_hashcode: reader.read_u64::<LittleEndian>().unwrap(),
+            delay_time: reader.read_u64::<LittleEndian>().unwrap(),
+            queue_offset,
+            level: 0,
+            fired: size & FIRED_FLAG != 0,
+        }
+    }
+
+    /// 从最初版本 24 个字节的索引数据反序列化，延迟时间由秒转换为毫秒
+    fn deserialize_initial(data: &[u8]) -> Self {
+        let mut reader = Cursor::new(data);
+        QueueMessage {
+            physical_offset: reader.read_u64::<LittleEndian>().unwrap(),
+            size: reader.read_u32::<LittleEndian>().unwrap(),
+            tag_hashcode: reader.read_u64::<LittleEndian>().unwrap(),
+            delay_time: reader.read_u32::<LittleEndian>().unwrap() as u64 * 1000,
+            ..QueueMessage::default()
+        }
     }
 
-    /// 无效的延迟消息，用于阻塞循环
-    fn block_message() -> (Self, Duration) {
+    /// 无效的延迟消息，用于阻塞循环，max_delay 为最大延迟毫秒数
+    fn block_message(max_delay: u64) -> (Self, u64) {
         let message = QueueMessage {
             physical_offset: 0,
             size: 0,
             tag_hashcode: 0,
-            delay_time: CONFIG.max_delay_time,
+            delay_time: max_delay,
+            queue_offset: 0,
+            level: 0,
+            fired: false,
         };
-        let time = message.duration();
-        (message, time)
+        (message, now_millis() + max_delay)
     }
 
     /// 是否是阻塞的无效消息
@@ -215,60 +1381,936 @@ impl QueueMessage {
         self.size == 0
     }
 
-    fn duration(&self) -> Duration {
-        let n = self.delay_time;
-        Duration::from_secs(n as u64)
+    /// 根据 commit_log 中的存储时间计算到期时间的毫秒时间戳
+    fn deadline(&self, store: &Store) -> Result<u64, Error> {
+        let message = commit_log::read_message(store, self.physical_offset, self.size)?;
+        Ok(message.store_timestamp() + self.delay_time)
     }
 }
 
-/// 初始化延迟消息
-pub async fn init() {
-    init_message().await;
-    process_message().await;
+/// 恢复存储实例中未到期的延迟消息
+///
+/// 无法读取的消息记录日志后跳过，不影响其他消息的恢复
+pub async fn init(store: &Store) -> Result<(), Error> {
+    init_message(store).await
 }
 /// 从磁盘反序列化出 queue_message ，初始化到延迟队列
-async fn init_message() {
-    for r in 1..10 {
-        let (task_01, duration1) = QueueMessage::new(r as u64, r as u32, "", r as u32);
-        {
-            DELAY_QUEUE.write().await.insert(task_01, duration1);
+async fn init_message(store: &Store) -> Result<(), Error> {
+    let path = file_path(&store.consume_queue.base_dir)?;
+    for topic in get_all_dirs(&path)? {
+        let topic = topic.file_name().to_string_lossy().to_string();
+        if let Some(level) = topic.strip_prefix(LEVEL_TOPIC_PREFIX) {
+            match u8::from_str(level) {
+                Ok(level) => init_level_message(store, level)?,
+                Err(_) => warn!("忽略不合法的延迟级别目录：{topic}"),
+            }
+            continue;
+        }
+        for queue in get_all_dirs(&path.join(&topic))? {
+            let queue = queue.file_name().to_string_lossy().to_string();
+            match u32::from_str(&queue) {
+                Ok(queue_id) => {
+                    init_queue_message(store, &queue_dir(store, &queue_key(&topic, queue_id)))?
+                }
+                Err(_) => warn!("忽略不合法的队列目录：{topic}/{queue}"),
+            }
         }
     }
+    Ok(())
+}
+
+/// 恢复一个队列中所有未到期的消息
+fn init_queue_message(store: &Store, dir_name: &str) -> Result<(), Error> {
+    for queue_message in queue_entries(dir_name)? {
+        if queue_message.fired {
+            continue;
+        }
+        let deadline = match queue_message.deadline(store) {
+            Ok(deadline) => deadline,
+            Err(err) => {
+                error!("延迟消息无法读取，跳过恢复：{queue_message:?} {err}");
+                continue;
+            }
+        };
+        info!("恢复延迟消息：{queue_message:?}，到期时间：{deadline}");
+        store
+            .consume_queue
+            .scheduler
+            .send(ScheduleCmd::Insert(queue_message, deadline))
+            .map_err(|_| Error::Closed)?;
+    }
+    Ok(())
+}
+
+/// 固定延迟级别只需恢复第一条未到期的消息，后续消息在其到期后依次加载
+fn init_level_message(store: &Store, level: u8) -> Result<(), Error> {
+    let dir_name = level_queue_dir(store, level);
+    let file_size = store.consume_queue.file_size;
+    let first = sorted_commit_log_files(&dir_name)?
+        .first()
+        .and_then(|file| u64::from_str(&file.file_name().to_string_lossy()).ok());
+    let mut head =
+        first.and_then(|queue_offset| consume_queue_read(&dir_name, file_size, queue_offset));
+    // 级别队列有序，已到期的消息都在队首
+    while let Some(queue_message) = head.as_ref().filter(|msg| msg.fired) {
+        head = consume_queue_read(
+            &dir_name,
+            file_size,
+            next_queue_offset(queue_message.queue_offset, file_size),
+        );
+    }
+    let Some(mut queue_message) = head else {
+        return Ok(());
+    };
+    queue_message.level = level;
+    let (queue_message, deadline) = match queue_message.deadline(store) {
+        Ok(deadline) => (queue_message, deadline),
+        Err(err) => {
+            error!("延迟级别[{level}]队首消息无法读取，跳过：{queue_message:?} {err}");
+            match level_next(store, &queue_message) {
+                Some(next) => next,
+                None => return Ok(()),
+            }
+        }
+    };
+    info!("恢复延迟级别[{level}]队首消息：{queue_message:?}，到期时间：{deadline}");
+    store
+        .consume_queue
+        .scheduler
+        .send(ScheduleCmd::Insert(queue_message, deadline))
+        .map_err(|_| Error::Closed)
+}
+
+/// 级别队首消息到期后，从 consume_queue 加载该级别的下一条消息，无法读取的消息被跳过
+fn level_next(store: &Store, msg: &QueueMessage) -> Option<(QueueMessage, u64)> {
+    let dir_name = level_queue_dir(store, msg.level);
+    let file_size = store.consume_queue.file_size;
+    let mut queue_offset = msg.queue_offset;
+    loop {
+        queue_offset = next_queue_offset(queue_offset, file_size);
+        let mut queue_message = consume_queue_read(&dir_name, file_size, queue_offset)?;
+        queue_message.level = msg.level;
+        match queue_message.deadline(store) {
+            Ok(deadline) => return Some((queue_message, deadline)),
+            Err(err) => error!("延迟级别消息无法读取，跳过：{queue_message:?} {err}"),
+        }
+    }
+}
+
+/// 将毫秒时间戳转换为延迟队列使用的 Instant
+///
+/// 以固定的基准换算，相同的到期时间总是得到相同的 Instant，从而在延迟队列中同时到期
+fn deadline_instant(clock_base: ClockBase, deadline: u64) -> Instant {
+    let (base_instant, base_millis) = clock_base;
+    match deadline.checked_sub(base_millis) {
+        Some(after) => base_instant + Duration::from_millis(after),
+        None => base_instant,
+    }
 }
 
 /// 处理所有的延迟消息
-async fn process_message() {
-    tokio::spawn(async move {
-        loop {
-            let mut queue = DELAY_QUEUE.write().await;
-            if let Some(ele) = queue.next().await {
-                // 在这里处理取出的元素
-                let msg = ele.get_ref();
-                if msg.is_block_message() {
-                    let (block, duration) = QueueMessage::block_message();
-                    warn!("无效阻塞消息消费：{msg:?}，将重新赋值：{block:?}");
-                    queue.insert(block, duration);
-                    return;
+///
+/// keys 记录 physical_offset 对应的延迟队列 key 与 consume_queue 逻辑偏移量，用于修改到期时间与取消
+///
+/// levels 记录已有队首消息在延迟队列中的延迟级别
+///
+/// 同时到期的消息按 commit_log 写入顺序交给 fire_task 投递，保证相同分片键的消息在到期时间相同时先进先出
+///
+/// 文件读写在阻塞线程池中执行，投递由 fire_task 完成，不阻塞延迟队列的调度
+///
+/// 存储实例释放后调度通道关闭，任务随之退出
+async fn process_message(
+    mut rx: UnboundedReceiver<ScheduleCmd>,
+    fire_tx: UnboundedSender<Vec<(QueueMessage, Message)>>,
+    store: Weak<Store>,
+    max_delay: u64,
+) {
+    let clock_base = (Instant::now(), now_millis());
+    let mut queue = DelayQueue::<QueueMessage>::with_capacity(1024);
+    let (block, deadline) = QueueMessage::block_message(max_delay);
+    // 设置阻塞元素
+    queue.insert_at(block, deadline_instant(clock_base, deadline));
+    let mut keys = HashMap::<u64, (Key, QueueMessage)>::with_capacity(1024);
+    let mut levels = HashSet::<u8>::new();
+    loop {
+        tokio::select! {
+            cmd = rx.recv() => match cmd {
+                None => break,
+                Some(ScheduleCmd::Insert(msg, deadline)) if msg.level > 0 => {
+                    // 级别队列已有队首在调度中，该消息会在之前的消息到期后从磁盘加载
+                    if levels.insert(msg.level) {
+                        queue.insert_at(msg, deadline_instant(clock_base, deadline));
+                    }
+                }
+                Some(ScheduleCmd::Insert(msg, deadline)) => {
+                    let physical_offset = msg.physical_offset;
+                    let key = queue.insert_at(msg.clone(), deadline_instant(clock_base, deadline));
+                    keys.insert(physical_offset, (key, msg));
+                }
+                Some(ScheduleCmd::Reschedule { physical_offset, delay_time, resp }) => {
+                    let Some(store) = store.upgrade() else {
+                        break;
+                    };
+                    // 先写回 consume_queue，持久化失败时保持原来的到期时间
+                    let result = match keys.get_mut(&physical_offset) {
+                        Some((key, msg)) => {
+                            let deadline = now_millis() + delay_time;
+                            let mut updated = msg.clone();
+                            match reschedule_persist(&store, &mut updated, deadline).await {
+                                Ok(()) => {
+                                    queue.remove(key);
+                                    *key = queue.insert_at(
+                                        updated.clone(),
+                                        deadline_instant(clock_base, deadline),
+                                    );
+                                    *msg = updated;
+                                    Ok(())
+                                }
+                                Err(err) => {
+                                    error!("持久化修改后的到期时间失败：{msg:?} {err}");
+                                    Err(ScheduleError::Persist(err.to_string()))
+                                }
+                            }
+                        }
+                        None => Err(ScheduleError::NotFound(physical_offset)),
+                    };
+                    let _ = resp.send(result);
+                }
+                Some(ScheduleCmd::Cancel { physical_offset, resp }) => {
+                    let Some(store) = store.upgrade() else {
+                        break;
+                    };
+                    // 先在 consume_queue 中标记，持久化失败时消息依然会到期投递
+                    let result = match keys.entry(physical_offset) {
+                        Entry::Occupied(entry) => {
+                            let mut msg = entry.get().1.clone();
+                            match cancel_persist(&store, &mut msg).await {
+                                Ok(()) => {
+                                    queue.remove(&entry.remove().0);
+                                    Ok(())
+                                }
+                                Err(err) => {
+                                    error!("持久化取消的延迟消息失败：{msg:?} {err}");
+                                    Err(ScheduleError::Persist(err.to_string()))
+                                }
+                            }
+                        }
+                        Entry::Vacant(_) => Err(ScheduleError::NotFound(physical_offset)),
+                    };
+                    let _ = resp.send(result);
+                }
+            },
+            Some(ele) = queue.next() => {
+                let Some(store) = store.upgrade() else {
+                    break;
+                };
+                // 同时到期的消息一起取出，按到期时间与写入顺序依次投递
+                let mut expired = vec![ele.into_inner()];
+                while let Some(Some(ele)) = queue.next().now_or_never() {
+                    expired.push(ele.into_inner());
+                }
+                let mut batch = Vec::with_capacity(expired.len());
+                for msg in expired {
+                    if msg.is_block_message() {
+                        let (block, deadline) = QueueMessage::block_message(max_delay);
+                        warn!("无效阻塞消息消费：{msg:?}，将重新赋值：{block:?}");
+                        queue.insert_at(block, deadline_instant(clock_base, deadline));
+                        continue;
+                    }
+                    info!("消息过期：{msg:?}");
+                    keys.remove(&msg.physical_offset);
+                    if msg.level > 0 {
+                        let next = blocking({
+                            let (store, msg) = (store.clone(), msg.clone());
+                            move || level_next(&store, &msg)
+                        });
+                        match next.await {
+                            Ok(Some((next, deadline))) => {
+                                queue.insert_at(next, deadline_instant(clock_base, deadline));
+                            }
+                            Ok(None) => {
+                                levels.remove(&msg.level);
+                            }
+                            Err(err) => {
+                                error!("加载延迟级别[{}]的下一条消息失败：{err}", msg.level);
+                                levels.remove(&msg.level);
+                            }
+                        }
+                    }
+                    match commit_log::read_message(&store, msg.physical_offset, msg.size) {
+                        Ok(message) => batch.push((msg, message)),
+                        Err(err) => error!("到期消息无法读取，不再投递：{msg:?} {err}"),
+                    }
+                }
+                batch.sort_by_key(|(msg, message)| {
+                    (message.store_timestamp() + msg.delay_time, msg.physical_offset)
+                });
+                if fire_tx.send(batch).is_err() {
+                    break;
                 }
-                info!("消息过期：{msg:?}");
-
-                ESCAPE_CHANNEL.send(ele.into_inner()).unwrap();
             }
         }
+    }
+    info!("延迟队列调度任务退出");
+}
+
+/// 按到期顺序依次投递 process_message 取出的到期消息，调度任务退出后随之退出
+async fn fire_task(mut rx: UnboundedReceiver<Vec<(QueueMessage, Message)>>, store: Weak<Store>) {
+    while let Some(batch) = rx.recv().await {
+        let Some(store) = store.upgrade() else {
+            break;
+        };
+        for (msg, message) in batch {
+            fire(&store, msg, message).await;
+        }
+    }
+}
+
+/// 在阻塞线程池中执行文件读写，打开、映射文件与刷盘不占用异步任务的线程
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> Result<T, Error> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|err| Error::Io(err.into()))
+}
+
+/// 将到期消息写入对应 topic 的 ready_queue 等待消费，并在 consume_queue 中标记为已到期
+///
+/// 标记之前宕机会在恢复后重复写入 ready_queue，保证消息至少投递一次
+///
+/// topic 被删除后索引已不存在或已标记为到期，此时不再投递
+async fn fire(store: &Store, mut msg: QueueMessage, message: Message) {
+    let dir_name = queue_dir(store, &queue_name(&message));
+    let file_size = store.consume_queue.file_size;
+    let entry = blocking({
+        let (dir_name, queue_offset) = (dir_name.clone(), msg.queue_offset);
+        move || consume_queue_read(&dir_name, file_size, queue_offset)
     });
+    let entry = match entry.await {
+        Ok(entry) => entry,
+        Err(err) => {
+            error!("读取到期消息的索引失败，重启后重新投递：{msg:?} {err}");
+            return;
+        }
+    };
+    let removed =
+        entry.is_none_or(|entry| entry.fired || entry.physical_offset != msg.physical_offset);
+    if removed {
+        warn!("消息所属的 topic 已删除，不再投递：{}", message.topic);
+        return;
+    }
+    if let Err(err) = ready_queue::append(store, &message.topic, message.queue_id(), &msg).await {
+        error!("到期消息写入 ready_queue 失败，重启后重新投递：{msg:?} {err}");
+        return;
+    }
+    msg.fired = true;
+    if let Err(err) = queue_update(store, &message, &msg).await {
+        error!("标记到期消息失败：{msg:?} {err}");
+    }
+}
+
+/// 在阻塞线程池中覆盖写消息在 consume_queue 中的索引数据
+async fn queue_update(store: &Store, message: &Message, msg: &QueueMessage) -> Result<(), Error> {
+    let dir_name = queue_dir(store, &queue_name(message));
+    let file_size = store.consume_queue.file_size;
+    let (queue_offset, data) = (msg.queue_offset, msg.serialize_binary());
+    blocking(move || consume_queue_update(&dir_name, file_size, queue_offset, &data)).await?
+}
+
+/// 将修改后的到期时间换算为相对存储时间的延迟，写回 consume_queue
+async fn reschedule_persist(
+    store: &Store,
+    msg: &mut QueueMessage,
+    deadline: u64,
+) -> Result<(), Error> {
+    let message = commit_log::read_message(store, msg.physical_offset, msg.size)?;
+    msg.delay_time = deadline.saturating_sub(message.store_timestamp());
+    info!("修改延迟消息到期时间：{msg:?}");
+    queue_update(store, &message, msg).await
+}
+
+/// 将取消的消息在 consume_queue 中标记为已到期
+async fn cancel_persist(store: &Store, msg: &mut QueueMessage) -> Result<(), Error> {
+    let message = commit_log::read_message(store, msg.physical_offset, msg.size)?;
+    msg.fired = true;
+    info!("取消延迟消息：{msg:?}");
+    queue_update(store, &message, msg).await
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::consume_queue::writers_init;
+    use crate::common::config::Config;
+    use crate::common::time_util::now_millis;
+    use crate::consume_queue::{
+        cancel, checkpoint, deadline_instant, migrate_legacy, next_queue_offset, pending,
+        queue_dirs, queue_entries, queue_writer_create, rebuild, recover, reschedule,
+        rewrite_queue, split_queue_name, update_checkpoint, writers_init, ConsumeQueueWriter,
+        QueueMessage, BASE_DIR_NAME, INITIAL_ENTRY_LEN, INIT_LOG_FILE_NAME,
+    };
+    use crate::cust_error::ScheduleError;
+    use crate::data_process_util::{hashcode, str_hashcode};
+    use crate::file_util::temp_dir;
     use crate::log_util::log_init;
+    use crate::storage::checkpoint::{Checkpoint, CHECKPOINT_FILE, TOPICS_FILE};
+    use crate::storage::commit_log::{self, put_message};
+    use crate::storage::consumer_offset::ConsumerOffset;
+    use crate::storage::message::Message;
+    use crate::storage::ready_queue::{self, delivered_count};
+    use crate::storage::store::{Store, CONFIG_DIR_NAME};
+    use std::collections::{BTreeSet, HashMap};
+    use std::path::Path;
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    /// 轮询等待条件满足，超时则测试失败
+    async fn wait_until(mut condition: impl FnMut() -> bool) {
+        let poll = async {
+            while !condition() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), poll)
+            .await
+            .expect("等待超时");
+    }
 
     #[tokio::test]
     async fn delay_queue() {}
 
+    #[test]
+    fn test_queue_message_binary() {
+        let message = QueueMessage::new(1024, 66, "topic_oms", 1500);
+        let data = message.serialize_binary();
+        assert_eq!(data.len(), QueueMessage::len() as usize);
+
+        let decoded = QueueMessage::deserialize_binary(&data, 48);
+        assert_eq!(decoded.physical_offset, 1024);
+        assert_eq!(decoded.size, 66);
+        assert_eq!(decoded.delay_time, 1500);
+        assert_eq!(decoded.queue_offset, 48);
+        assert!(!decoded.fired);
+    }
+
+    #[test]
+    fn test_queue_message_fired() {
+        let mut message = QueueMessage::new(1024, 66, "topic_oms", 1500);
+        message.fired = true;
+        let decoded = QueueMessage::deserialize_binary(&message.serialize_binary(), 0);
+        assert!(decoded.fired);
+        assert_eq!(decoded.size, 66);
+    }
+
     #[test]
     fn test_init_writers() {
         log_init();
-        writers_init();
+        let base_dir = temp_dir("init_writers").join(BASE_DIR_NAME);
+        let base_dir = base_dir.to_str().unwrap();
+        let file_size = Config::new().unwrap().consume_queue_file_size;
+        let mut writer = ConsumeQueueWriter::consume_queue_new(
+            None,
+            &format!("{base_dir}/topic_oms/0"),
+            file_size,
+        )
+        .unwrap();
+        writer
+            .consume_queue_write(&QueueMessage::new(0, 66, "", 1500).serialize_binary())
+            .unwrap();
+
+        let writers = writers_init(base_dir, file_size).unwrap();
+        assert_eq!(writers.len(), 1);
+        assert_eq!(
+            writers["topic_oms/0"].prev_write_size,
+            QueueMessage::len() as usize
+        );
+    }
+
+    #[test]
+    fn test_writer_recover() {
+        log_init();
+        let base_dir = temp_dir("writer_recover").join(BASE_DIR_NAME);
+        let base_dir = base_dir.to_str().unwrap();
+        let file_size = Config::new().unwrap().consume_queue_file_size;
+        let len = QueueMessage::len() as u64;
+        let mut writer = queue_writer_create(base_dir, "topic_oms/0", file_size, 0).unwrap();
+        let data = QueueMessage::new(0, 66, "", 1500).serialize_binary();
+        writer.consume_queue_write(&data).unwrap();
+        update_checkpoint(base_dir, "topic_oms/0", writer.write_offset().unwrap(), 66).unwrap();
+        // checkpoint 之后写入的索引在打开时向后扫描恢复
+        writer.consume_queue_write(&data).unwrap();
+        writer.consume_queue_write(&data).unwrap();
+        let writers = writers_init(base_dir, file_size).unwrap();
+        assert_eq!(writers["topic_oms/0"].write_offset().unwrap(), 3 * len);
+
+        // checkpoint 超过已写入的位置时从文件开头恢复
+        update_checkpoint(base_dir, "topic_oms/0", 5 * len, 66).unwrap();
+        let writers = writers_init(base_dir, file_size).unwrap();
+        assert_eq!(writers["topic_oms/0"].write_offset().unwrap(), 3 * len);
+        let checkpoint = Checkpoint::load(&Path::new(base_dir).join("topic_oms")).unwrap();
+        assert_eq!(checkpoint.physical_offset, 66);
+    }
+
+    #[test]
+    fn test_migrate_legacy() {
+        let dir = temp_dir("migrate_legacy");
+        let config = Config::new().unwrap();
+        let file_size = config.consume_queue_file_size;
+        let len = QueueMessage::len() as usize;
+        // 旧版本每个文件最后 8 个字节存储写入位置
+        let per_file = (file_size as usize - 8) / len;
+        let legacy_file = |count: usize, first: u64| {
+            let mut data = vec![0; file_size as usize];
+            for i in 0..count {
+                let entry = QueueMessage::new(first + i as u64, 66, "", 1500).serialize_binary();
+                data[i * len..(i + 1) * len].copy_from_slice(&entry);
+            }
+            let tail = data.len() - 8;
+            data[tail..].copy_from_slice(&((count * len) as u64).to_le_bytes());
+            data
+        };
+        for base in [BASE_DIR_NAME, ready_queue::BASE_DIR_NAME] {
+            let queue_dir = dir.join(base).join("topic_legacy/0");
+            std::fs::create_dir_all(&queue_dir).unwrap();
+            std::fs::write(
+                queue_dir.join(format!("{:020}", 0)),
+                legacy_file(per_file, 0),
+            )
+            .unwrap();
+            std::fs::write(
+                queue_dir.join(format!("{:020}", file_size)),
+                legacy_file(2, per_file as u64),
+            )
+            .unwrap();
+        }
+        let legacy = ConsumerOffset {
+            offset: file_size,
+            acked: BTreeSet::from([file_size + len as u64]),
+        };
+        let table = HashMap::from([(
+            "topic_legacy/0".to_string(),
+            HashMap::from([("group".to_string(), legacy)]),
+        )]);
+        let offset_file = dir.join(CONFIG_DIR_NAME).join("consumer_offset.json");
+        std::fs::create_dir_all(dir.join(CONFIG_DIR_NAME)).unwrap();
+        std::fs::write(&offset_file, serde_json::to_string(&table).unwrap()).unwrap();
+
+        migrate_legacy(&dir, &config).unwrap();
+        let per_file = file_size as usize / len;
+        for base in [BASE_DIR_NAME, ready_queue::BASE_DIR_NAME] {
+            let dir_name = dir.join(base).join("topic_legacy/0");
+            let entries = queue_entries(dir_name.to_str().unwrap()).unwrap();
+            assert_eq!(entries.len(), 8);
+            for (i, entry) in entries.iter().enumerate() {
+                assert_eq!(entry.physical_offset, i as u64);
+                let queue_offset = (i / per_file) as u64 * file_size + (i % per_file * len) as u64;
+                assert_eq!(entry.queue_offset, queue_offset);
+            }
+            assert!(Checkpoint::exists(&dir.join(base).join("topic_legacy")));
+        }
+        // 消费进度按索引的位置转换
+        let table: HashMap<String, HashMap<String, ConsumerOffset>> =
+            serde_json::from_str(&std::fs::read_to_string(&offset_file).unwrap()).unwrap();
+        let offset = &table["topic_legacy/0"]["group"];
+        assert_eq!(offset.offset, 6 * len as u64);
+        assert_eq!(offset.acked, BTreeSet::from([file_size]));
+
+        // 已迁移的数据不会再次迁移
+        migrate_legacy(&dir, &config).unwrap();
+        let dir_name = dir.join(BASE_DIR_NAME).join("topic_legacy/0");
+        assert_eq!(queue_entries(dir_name.to_str().unwrap()).unwrap().len(), 8);
+    }
+
+    #[tokio::test]
+    async fn test_migrate_tag_hashcode() {
+        let dir = temp_dir("migrate_tag_hashcode");
+        let config = Config::new().unwrap();
+        let store = Store::open(config.clone(), &dir).await.unwrap();
+        let topic = "topic_legacy_tag";
+        for tag in ["TagA", "TagB"] {
+            let message = Message::new(topic, "hello", &format!("_delay-60;_tag-{tag}"));
+            put_message(&store, message).await.unwrap();
+        }
+        drop(store);
+
+        // 旧版本的 tag_hashcode 使用 DefaultHasher 计算，文件最后 8 个字节存储写入位置，没有 checkpoint
+        let base_dir = dir.join(BASE_DIR_NAME).to_string_lossy().to_string();
+        let file_size = config.consume_queue_file_size as usize;
+        let len = QueueMessage::len() as usize;
+        for dir_name in queue_dirs(&base_dir, topic) {
+            let entries = queue_entries(&dir_name).unwrap();
+            std::fs::remove_dir_all(&dir_name).unwrap();
+            std::fs::create_dir_all(&dir_name).unwrap();
+            let mut data = vec![0; file_size];
+            for (i, entry) in entries.iter().enumerate() {
+                let mut entry = entry.clone();
+                entry.tag_hashcode = hashcode(&topic);
+                data[i * len..(i + 1) * len].copy_from_slice(&entry.serialize_binary());
+            }
+            let tail = file_size - 8;
+            data[tail..].copy_from_slice(&((entries.len() * len) as u64).to_le_bytes());
+            std::fs::write(format!("{dir_name}/{:020}", 0), data).unwrap();
+        }
+        std::fs::remove_file(Path::new(&base_dir).join(topic).join(CHECKPOINT_FILE)).unwrap();
+
+        migrate_legacy(&dir, &config).unwrap();
+        let mut hashcodes = queue_dirs(&base_dir, topic)
+            .iter()
+            .flat_map(|dir_name| queue_entries(dir_name).unwrap())
+            .map(|entry| entry.tag_hashcode())
+            .collect::<Vec<_>>();
+        hashcodes.sort();
+        let mut expected = vec![str_hashcode("TagA"), str_hashcode("TagB")];
+        expected.sort();
+        assert_eq!(hashcodes, expected);
+    }
+
+    #[tokio::test]
+    async fn test_migrate_initial() {
+        let dir = temp_dir("migrate_initial");
+        let config = Config::new().unwrap();
+        let store = Store::open(config.clone(), &dir).await.unwrap();
+        let topic = "topic_initial";
+        for _ in 0..3 {
+            let message = Message::new(topic, "hello", "_delay-60");
+            put_message(&store, message).await.unwrap();
+        }
+        drop(store);
+
+        // 最初版本的索引为 24 个字节，延迟时间为秒，文件最后 8 个字节存储写入位置，没有 checkpoint
+        let base_dir = dir.join(BASE_DIR_NAME).to_string_lossy().to_string();
+        let file_size = config.consume_queue_file_size as usize;
+        let mut expected = Vec::new();
+        for dir_name in queue_dirs(&base_dir, topic) {
+            let entries = queue_entries(&dir_name).unwrap();
+            std::fs::remove_dir_all(&dir_name).unwrap();
+            std::fs::create_dir_all(&dir_name).unwrap();
+            let mut data = vec![0; file_size];
+            for (i, entry) in entries.iter().enumerate() {
+                let mut initial = entry.physical_offset.to_le_bytes().to_vec();
+                initial.extend(entry.size.to_le_bytes());
+                initial.extend(hashcode(&topic).to_le_bytes());
+                initial.extend(((entry.delay_time / 1000) as u32).to_le_bytes());
+                data[i * INITIAL_ENTRY_LEN..(i + 1) * INITIAL_ENTRY_LEN].copy_from_slice(&initial);
+            }
+            let tail = file_size - 8;
+            let end = (entries.len() * INITIAL_ENTRY_LEN) as u64;
+            data[tail..].copy_from_slice(&end.to_le_bytes());
+            std::fs::write(format!("{dir_name}/{:020}", 0), data).unwrap();
+            expected.extend(entries);
+        }
+        std::fs::remove_file(Path::new(&base_dir).join(topic).join(CHECKPOINT_FILE)).unwrap();
+
+        migrate_legacy(&dir, &config).unwrap();
+        let mut migrated = queue_dirs(&base_dir, topic)
+            .iter()
+            .flat_map(|dir_name| queue_entries(dir_name).unwrap())
+            .map(|entry| (entry.physical_offset, entry.size, entry.delay_time))
+            .collect::<Vec<_>>();
+        migrated.sort();
+        let mut expected = expected
+            .iter()
+            .map(|entry| (entry.physical_offset, entry.size, entry.delay_time))
+            .collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(migrated, expected);
+        assert!(migrated
+            .iter()
+            .all(|(_, _, delay_time)| *delay_time == 60_000));
+
+        let store = Store::open(config, &dir).await.unwrap();
+        assert_eq!(pending(&store, topic).0, 3);
+    }
+
+    #[test]
+    fn test_next_queue_offset() {
+        let file_size = Config::new().unwrap().consume_queue_file_size;
+        let len = QueueMessage::len() as u64;
+        assert_eq!(next_queue_offset(0, file_size), len);
+        // 文件剩余空间不足一条索引数据时滚动到下一个文件
+        let last = file_size / len * len - len;
+        assert_eq!(next_queue_offset(last, file_size), file_size);
+        assert_eq!(next_queue_offset(file_size, file_size), file_size + len);
+    }
+
+    #[test]
+    fn test_deadline_instant() {
+        let clock_base = (Instant::now(), now_millis());
+        let deadline = now_millis() + 1500;
+        // 相同的到期时间多次换算，结果相同
+        let first = deadline_instant(clock_base, deadline);
+        assert_eq!(deadline_instant(clock_base, deadline), first);
+        assert_eq!(
+            deadline_instant(clock_base, deadline + 1) - first,
+            Duration::from_millis(1)
+        );
+        // 已过去的时间统一为基准时刻
+        assert_eq!(
+            deadline_instant(clock_base, 0),
+            deadline_instant(clock_base, 1)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_same_deadline_order() {
+        let dir = temp_dir("same_deadline_order");
+        let config = Config::new().unwrap();
+        let store = Store::open(config.clone(), &dir).await.unwrap();
+        let topic = "topic_same_deadline";
+        let mut offsets = Vec::new();
+        for i in 0..5 {
+            let message = Message::new(topic, &i.to_string(), "_delay-60;_sharding_key-order_1");
+            offsets.push(put_message(&store, message).await.unwrap());
+        }
+
+        // 修改索引中的延迟时间，使所有消息在同一时刻到期
+        let base_dir = dir.join(BASE_DIR_NAME).to_string_lossy().to_string();
+        let [dir_name] = queue_dirs(&base_dir, topic).try_into().unwrap();
+        let deadline = now_millis() + 200;
+        let mut entries = queue_entries(&dir_name).unwrap();
+        for entry in entries.iter_mut() {
+            let message =
+                commit_log::read_message(&store, entry.physical_offset, entry.size).unwrap();
+            entry.delay_time = deadline - message.store_timestamp();
+        }
+        drop(store);
+        let topic_dir = Path::new(&base_dir).join(topic);
+        let physical_offset = Checkpoint::load(&topic_dir).unwrap().physical_offset;
+        let queue = dir_name.trim_start_matches(&format!("{base_dir}/"));
+        let file_size = config.consume_queue_file_size;
+        rewrite_queue(&base_dir, queue, file_size, &entries, physical_offset).unwrap();
+
+        // 同时到期的消息按写入顺序进入 ready_queue
+        let store = Store::open(config, &dir).await.unwrap();
+        wait_until(|| delivered_count(&store, topic) == offsets.len() as u64).await;
+        let (_, queue_id) = split_queue_name(queue).unwrap();
+        let delivered = ready_queue::peek(&store, topic, queue_id, 0, offsets.len())
+            .into_iter()
+            .map(|delivery| delivery.message.physical_offset)
+            .collect::<Vec<_>>();
+        assert_eq!(delivered, offsets);
+    }
+
+    #[tokio::test]
+    async fn test_rebuild() {
+        let dir = temp_dir("rebuild");
+        let config = Config::new().unwrap();
+        let store = Store::open(config.clone(), &dir).await.unwrap();
+        let topic = "topic_rebuild";
+        let mut offsets = Vec::new();
+        for prop in ["_delay-60", "_delay-60", "_level-1"] {
+            let message = Message::new(topic, "hello", prop);
+            offsets.push(put_message(&store, message).await.unwrap());
+        }
+        cancel(&store, offsets[0]).await.unwrap();
+        wait_until(|| pending(&store, topic).0 == 1).await;
+        drop(store);
+
+        // 之前的索引保留，取消的消息依然是已到期
+        let rebuilt = rebuild(&dir, &config, offsets[1]).unwrap();
+        assert_eq!(rebuilt.values().sum::<usize>(), 3);
+        assert_eq!(rebuilt["%LEVEL%1/0"], 1);
+
+        // 缺少 consume_queue 目录时打开存储实例自动重建，已写入 ready_queue 的消息不会再次投递
+        std::fs::remove_dir_all(dir.join(BASE_DIR_NAME)).unwrap();
+        let store = Store::open(config.clone(), &dir).await.unwrap();
+        assert_eq!(pending(&store, topic).0, 2);
+        assert_eq!(delivered_count(&store, topic), 1);
+        let message = Message::new(topic, "hello", "_delay-60");
+        put_message(&store, message).await.unwrap();
+        assert_eq!(pending(&store, topic).0, 3);
+    }
+
+    #[tokio::test]
+    async fn test_reschedule() {
+        let dir = temp_dir("reschedule");
+        let config = Config::new().unwrap();
+        let store = Store::open(config.clone(), &dir).await.unwrap();
+        let topic = "topic_reschedule";
+        let message = Message::new(topic, "hello", "_delay-60");
+        let physical_offset = put_message(&store, message).await.unwrap();
+
+        let start = now_millis();
+        reschedule(&store, physical_offset, 120_000).await.unwrap();
+        let end = now_millis();
+        let next_due = pending(&store, topic).1.unwrap();
+        assert!((start + 120_000..=end + 120_000).contains(&next_due));
+
+        // 固定延迟级别的消息与已到期的消息不支持修改
+        let level = Message::new("topic_reschedule_level", "hello", "_level-1");
+        let level_offset = put_message(&store, level).await.unwrap();
+        let fired = Message::new("topic_reschedule_fired", "hello", "_delay_ms-1");
+        let fired_offset = put_message(&store, fired).await.unwrap();
+        wait_until(|| delivered_count(&store, "topic_reschedule_fired") == 1).await;
+        for offset in [level_offset, fired_offset] {
+            assert!(matches!(
+                reschedule(&store, offset, 1000).await,
+                Err(ScheduleError::NotFound(not_found)) if not_found == offset
+            ));
+        }
+        drop(store);
+
+        // 修改后的到期时间写回 consume_queue，重启后依然生效
+        let store = Store::open(config, &dir).await.unwrap();
+        assert_eq!(pending(&store, topic), (1, Some(next_due)));
+    }
+
+    #[tokio::test]
+    async fn test_reschedule_persist_error() {
+        let store = Store::open(Config::new().unwrap(), temp_dir("reschedule_persist_error"))
+            .await
+            .unwrap();
+        let topic = "topic_reschedule_persist_error";
+        let message = Message::new(topic, "hello", "_delay-60");
+        let physical_offset = put_message(&store, message).await.unwrap();
+        let (_, next_due) = pending(&store, topic);
+
+        // 索引文件无法打开时返回错误，到期时间保持不变
+        let base_dir = store
+            .dir()
+            .join(BASE_DIR_NAME)
+            .to_string_lossy()
+            .to_string();
+        let dir_name = queue_dirs(&base_dir, topic).remove(0);
+        let file = Path::new(&dir_name).join(INIT_LOG_FILE_NAME);
+        let data = std::fs::read(&file).unwrap();
+        std::fs::remove_file(&file).unwrap();
+        assert!(matches!(
+            reschedule(&store, physical_offset, 0).await,
+            Err(ScheduleError::Persist(_))
+        ));
+        std::fs::write(&file, data).unwrap();
+        assert_eq!(pending(&store, topic).1, next_due);
+        reschedule(&store, physical_offset, 120_000).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cancel_persist_error() {
+        let store = Store::open(Config::new().unwrap(), temp_dir("cancel_persist_error"))
+            .await
+            .unwrap();
+        let topic = "topic_cancel_persist_error";
+        let message = Message::new(topic, "hello", "_delay-60");
+        let physical_offset = put_message(&store, message).await.unwrap();
+
+        // 索引文件无法打开时返回错误，消息依然可以取消
+        let base_dir = store
+            .dir()
+            .join(BASE_DIR_NAME)
+            .to_string_lossy()
+            .to_string();
+        let dir_name = queue_dirs(&base_dir, topic).remove(0);
+        let file = Path::new(&dir_name).join(INIT_LOG_FILE_NAME);
+        let data = std::fs::read(&file).unwrap();
+        std::fs::remove_file(&file).unwrap();
+        assert!(matches!(
+            cancel(&store, physical_offset).await,
+            Err(ScheduleError::Persist(_))
+        ));
+        std::fs::write(&file, data).unwrap();
+        assert_eq!(pending(&store, topic).0, 1);
+        cancel(&store, physical_offset).await.unwrap();
+        assert_eq!(pending(&store, topic).0, 0);
+    }
+
+    #[tokio::test]
+    async fn test_recover() {
+        let dir = temp_dir("recover");
+        let config = Config::new().unwrap();
+        let store = Store::open(config.clone(), &dir).await.unwrap();
+        let topic = "topic_recover";
+        let mut offsets = Vec::new();
+        for _ in 0..3 {
+            let message = Message::new(topic, "hello", "_delay-60");
+            offsets.push(put_message(&store, message).await.unwrap());
+        }
+        drop(store);
+
+        // 模拟最后一条消息写入 commit_log 后、写入索引前宕机
+        let base_dir = dir.join(BASE_DIR_NAME).to_string_lossy().to_string();
+        let file_size = config.consume_queue_file_size;
+        for dir_name in queue_dirs(&base_dir, topic) {
+            let mut entries = queue_entries(&dir_name).unwrap();
+            if entries
+                .iter()
+                .any(|entry| entry.physical_offset == offsets[2])
+            {
+                entries.retain(|entry| entry.physical_offset != offsets[2]);
+                let queue = dir_name.trim_start_matches(&format!("{base_dir}/"));
+                rewrite_queue(&base_dir, queue, file_size, &entries, offsets[2]).unwrap();
+            }
+        }
+
+        let store = Store::open(config.clone(), &dir).await.unwrap();
+        assert_eq!(pending(&store, topic).0, 3);
+        let other = "topic_recover_lost";
+        put_message(&store, Message::new(other, "hello", "_delay-60"))
+            .await
+            .unwrap();
+        drop(store);
+
+        // 单个 topic 的索引目录丢失，其它 topic 的索引完好
+        std::fs::remove_dir_all(Path::new(&base_dir).join(other)).unwrap();
+        let store = Store::open(config, &dir).await.unwrap();
+        assert_eq!(pending(&store, topic).0, 3);
+        assert_eq!(pending(&store, other).0, 1);
+    }
+
+    #[tokio::test]
+    async fn test_recover_from_checkpoint() {
+        let dir = temp_dir("recover_from_checkpoint");
+        let config = Config::new().unwrap();
+        let store = Store::open(config.clone(), &dir).await.unwrap();
+        let topic = "topic_recover_checkpoint";
+        for _ in 0..3 {
+            let message = Message::new(topic, "hello", "_delay-60");
+            put_message(&store, message).await.unwrap();
+        }
+        checkpoint(&store).await;
+        let dispatched = commit_log::dispatched_offset(&store);
+        assert!(dispatched > 0);
+        drop(store);
+
+        // checkpoint 完整时只检查 checkpoint 之后的 commit_log
+        assert_eq!(recover(&dir, &config).unwrap(), dispatched);
+
+        // topic 记录丢失时回退为全量检查
+        let base_dir = dir.join(BASE_DIR_NAME);
+        std::fs::remove_file(base_dir.join(TOPICS_FILE)).unwrap();
+        assert_eq!(recover(&dir, &config).unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_recover_invalid_checkpoint() {
+        let dir = temp_dir("recover_invalid_checkpoint");
+        let config = Config::new().unwrap();
+        let store = Store::open(config.clone(), &dir).await.unwrap();
+        let topic = "topic_invalid_checkpoint";
+        let mut offsets = Vec::new();
+        for _ in 0..3 {
+            let message = Message::new(topic, "hello", "_delay-60");
+            offsets.push(put_message(&store, message).await.unwrap());
+        }
+        drop(store);
+
+        // 最后一条消息未写入索引，同时 checkpoint 损坏
+        let base_dir = dir.join(BASE_DIR_NAME).to_string_lossy().to_string();
+        let file_size = config.consume_queue_file_size;
+        for dir_name in queue_dirs(&base_dir, topic) {
+            let mut entries = queue_entries(&dir_name).unwrap();
+            if entries
+                .iter()
+                .any(|entry| entry.physical_offset == offsets[2])
+            {
+                entries.retain(|entry| entry.physical_offset != offsets[2]);
+                let queue = dir_name.trim_start_matches(&format!("{base_dir}/"));
+                rewrite_queue(&base_dir, queue, file_size, &entries, offsets[2]).unwrap();
+            }
+        }
+        let topic_dir = Path::new(&base_dir).join(topic);
+        std::fs::write(topic_dir.join(CHECKPOINT_FILE), [0; 16]).unwrap();
+        assert!(Checkpoint::load(&topic_dir).is_none());
+
+        // 视为从 commit_log 的开头检查，不会当作旧版本数据迁移
+        let store = Store::open(config, &dir).await.unwrap();
+        assert_eq!(pending(&store, topic).0, 3);
+        assert!(Checkpoint::load(&topic_dir).is_some());
     }
 }
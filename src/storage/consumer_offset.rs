@@ -0,0 +1,263 @@
+//! 持久化消费进度，按 topic 的队列与消费者组记录 ready_queue 中已确认的位置
+//!
+//! 确认时只更新内存，由定时任务写入数据目录下的 config/consumer_offset.json
+
+use crate::cust_error::Error;
+use crate::file_util::file_path;
+use crate::storage::consume_queue::{next_queue_offset, queue_key};
+use crate::storage::store::{Store, CONFIG_DIR_NAME};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// 存储文件名
+const OFFSET_FILE: &str = "consumer_offset.json";
+
+/// topic/queue_id -> group -> 消费进度
+type OffsetTable = HashMap<String, HashMap<String, ConsumerOffset>>;
+
+/// 存储实例的消费进度
+pub(crate) struct ConsumerOffsets {
+    table: Mutex<OffsetTable>,
+    /// 存储文件路径
+    path: PathBuf,
+}
+
+impl ConsumerOffsets {
+    /// 从数据目录加载消费进度
+    pub(crate) fn load(dir: &Path) -> Result<Self, Error> {
+        let path = file_path(&dir.join(CONFIG_DIR_NAME).to_string_lossy())?.join(OFFSET_FILE);
+        Ok(ConsumerOffsets {
+            table: Mutex::new(load(&path)),
+            path,
+        })
+    }
+}
+
+/// 一个消费者组在一个 topic 队列上的消费进度
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConsumerOffset {
+    /// 此位置之前的消息均已确认
+    pub offset: u64,
+    /// offset 之后已确认的消息位置，乱序确认时暂存
+    pub acked: BTreeSet<u64>,
+}
+
+impl ConsumerOffset {
+    /// 是否已确认
+    pub fn is_acked(&self, queue_offset: u64) -> bool {
+        queue_offset < self.offset || self.acked.contains(&queue_offset)
+    }
+
+    /// 确认一条消息，连续确认的部分合并到 offset
+    pub fn ack(&mut self, queue_offset: u64, file_size: u64) {
+        if queue_offset < self.offset {
+            return;
+        }
+        self.acked.insert(queue_offset);
+        while self.acked.remove(&self.offset) {
+            self.offset = next_queue_offset(self.offset, file_size);
+        }
+    }
+
+    /// offset 之前的消息不再需要确认，已确认的位置随之合并
+    pub fn skip_to(&mut self, offset: u64, file_size: u64) {
+        if offset <= self.offset {
+            return;
+        }
+        self.offset = offset;
+        self.acked.retain(|acked| *acked >= offset);
+        while self.acked.remove(&self.offset) {
+            self.offset = next_queue_offset(self.offset, file_size);
+        }
+    }
+}
+
+/// 从磁盘加载消费进度
+fn load(path: &Path) -> OffsetTable {
+    match fs::read_to_string(path) {
+        Ok(json) => serde_json::from_str(&json).unwrap_or_else(|err| {
+            error!("解析消费进度文件错误 \n{:?},返回默认值", err);
+            OffsetTable::new()
+        }),
+        Err(_) => OffsetTable::new(),
+    }
+}
+
+/// 消费者组已确认的位置，从此位置开始拉取
+pub fn committed(store: &Store, topic: &str, queue_id: u32, group: &str) -> u64 {
+    store
+        .consumer_offset
+        .table
+        .lock()
+        .unwrap()
+        .get(&queue_key(topic, queue_id))
+        .and_then(|groups| groups.get(group))
+        .map_or(0, |offset| offset.offset)
+}
+
+/// 消费者组是否在任何队列上有消费进度
+pub fn has_group(store: &Store, group: &str) -> bool {
+    store
+        .consumer_offset
+        .table
+        .lock()
+        .unwrap()
+        .values()
+        .any(|groups| groups.contains_key(group))
+}
+
+/// 设置消费者组在队列上的消费进度，已有消费进度时不做处理
+pub(crate) fn start_at(store: &Store, topic: &str, queue_id: u32, group: &str, offset: u64) {
+    store
+        .consumer_offset
+        .table
+        .lock()
+        .unwrap()
+        .entry(queue_key(topic, queue_id))
+        .or_default()
+        .entry(group.to_string())
+        .or_insert_with(|| ConsumerOffset {
+            offset,
+            acked: BTreeSet::new(),
+        });
+}
+
+/// 消息是否已被消费者组确认
+pub fn is_acked(store: &Store, topic: &str, queue_id: u32, group: &str, queue_offset: u64) -> bool {
+    store
+        .consumer_offset
+        .table
+        .lock()
+        .unwrap()
+        .get(&queue_key(topic, queue_id))
+        .and_then(|groups| groups.get(group))
+        .is_some_and(|offset| offset.is_acked(queue_offset))
+}
+
+/// 确认消息，持久化由定时任务完成
+pub fn ack(store: &Store, topic: &str, queue_id: u32, group: &str, queue_offset: u64) {
+    let file_size = store.consume_queue.file_size();
+    store
+        .consumer_offset
+        .table
+        .lock()
+        .unwrap()
+        .entry(queue_key(topic, queue_id))
+        .or_default()
+        .entry(group.to_string())
+        .or_default()
+        .ack(queue_offset, file_size);
+}
+
+/// 队列中 offset 之前的消息已随 ready_queue 文件过期删除，消费进度跳到 offset，持久化由定时任务完成
+///
+/// group 为 None 时处理队列上所有已有消费进度的消费者组
+pub(crate) fn skip_to(store: &Store, topic: &str, queue_id: u32, group: Option<&str>, offset: u64) {
+    let file_size = store.consume_queue.file_size();
+    let mut table = store.consumer_offset.table.lock().unwrap();
+    let groups = table.entry(queue_key(topic, queue_id)).or_default();
+    if let Some(group) = group {
+        groups.entry(group.to_string()).or_default();
+    }
+    groups
+        .iter_mut()
+        .filter(|(name, _)| group.is_none_or(|group| group == name.as_str()))
+        .for_each(|(_, consumer_offset)| consumer_offset.skip_to(offset, file_size));
+}
+
+/// 删除 topic 所有队列的消费进度，持久化由定时任务完成
+pub fn remove_topic(store: &Store, topic: &str) {
+    let prefix = format!("{topic}/");
+    store
+        .consumer_offset
+        .table
+        .lock()
+        .unwrap()
+        .retain(|key, _| !key.starts_with(&prefix));
+}
+
+/// 将消费进度写入磁盘，先写临时文件再替换，避免写入中途宕机损坏文件
+pub fn flush(store: &Store) {
+    let offsets = &store.consumer_offset;
+    let json = serde_json::to_string_pretty(&*offsets.table.lock().unwrap()).unwrap();
+    if let Err(err) = write(&offsets.path, json) {
+        error!("持久化消费进度错误 \n{:?}", err);
+    }
+}
+
+/// 先写临时文件再替换
+fn write(path: &Path, json: String) -> std::io::Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, json).and_then(|_| fs::rename(&tmp, path))
+}
+
+/// 转换 queues 中队列的消费进度，用于迁移旧版本的 ready_queue 文件格式，需要在加载消费进度之前调用
+pub(crate) fn migrate(
+    dir: &Path,
+    queues: &BTreeSet<String>,
+    convert: impl Fn(u64) -> u64,
+) -> Result<(), Error> {
+    let path = dir.join(CONFIG_DIR_NAME).join(OFFSET_FILE);
+    if !path.exists() {
+        return Ok(());
+    }
+    let mut table = load(&path);
+    for (key, groups) in table.iter_mut().filter(|(key, _)| queues.contains(*key)) {
+        for offset in groups.values_mut() {
+            offset.offset = convert(offset.offset);
+            offset.acked = offset.acked.iter().map(|&acked| convert(acked)).collect();
+        }
+        info!("迁移旧版本的消费进度：{key}");
+    }
+    write(&path, serde_json::to_string_pretty(&table).unwrap())?;
+    Ok(())
+}
+
+/// 启动定时持久化消费进度的任务，存储实例释放后任务退出
+pub(crate) fn flush_task(store: &Arc<Store>) {
+    let interval = Duration::from_millis(store.config().consumer_offset_flush_interval);
+    let store = Arc::downgrade(store);
+    tokio::spawn(async move {
+        info!("消费进度定时持久化，间隔：{interval:?}");
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let Some(store) = store.upgrade() else {
+                break;
+            };
+            flush(&store);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::config::Config;
+    use crate::consume_queue::next_queue_offset;
+    use crate::storage::consumer_offset::ConsumerOffset;
+
+    #[test]
+    fn test_ack() {
+        let file_size = Config::new().unwrap().consume_queue_file_size;
+        let first = 0;
+        let second = next_queue_offset(first, file_size);
+        let third = next_queue_offset(second, file_size);
+
+        let mut offset = ConsumerOffset::default();
+        offset.ack(second, file_size);
+        assert_eq!(offset.offset, first);
+        assert!(offset.is_acked(second));
+        assert!(!offset.is_acked(first));
+
+        offset.ack(first, file_size);
+        assert_eq!(offset.offset, third);
+        assert!(offset.acked.is_empty());
+        assert!(offset.is_acked(first));
+        assert!(!offset.is_acked(third));
+    }
+}
@@ -0,0 +1,96 @@
+//! 消息过滤，消费者订阅时指定过滤条件，不满足条件的消息对该消费者组视为已消费
+//!
+//! tag 表达式形如 `TagA || TagB`，`*` 或空表示不过滤
+//!
+//! 先比较 consume_queue 中的 tag_hashcode，不需要读取 commit_log，hash 相同时再比较 tag 字符串，避免 hash 冲突
+//!
+//! 消费者组还可以注册 SQL92 风格的属性过滤表达式，读取消息后计算
+
+pub mod sql;
+
+use crate::data_process_util::str_hashcode;
+use crate::storage::filter::sql::Expr;
+use crate::storage::message::Message;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// 订阅所有 tag 的表达式
+const ALL_TAGS: &str = "*";
+/// tag 表达式的分隔符
+const TAG_SEPARATOR: &str = "||";
+
+/// 消息过滤条件
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MessageFilter {
+    /// 订阅的 tag，None 表示订阅所有 tag
+    tags: Option<HashSet<String>>,
+    /// 订阅的 tag 的 hash_code
+    tag_hashcodes: HashSet<u64>,
+    /// 属性过滤表达式
+    sql: Option<Arc<Expr>>,
+}
+
+impl MessageFilter {
+    /// 根据 tag 表达式创建过滤条件
+    pub fn tags(expression: &str) -> Self {
+        let expression = expression.trim();
+        if expression.is_empty() || expression == ALL_TAGS {
+            return MessageFilter::default();
+        }
+        let tags = expression
+            .split(TAG_SEPARATOR)
+            .map(str::trim)
+            .filter(|tag| !tag.is_empty())
+            .map(str::to_string)
+            .collect::<HashSet<_>>();
+        MessageFilter {
+            tag_hashcodes: tags.iter().map(|tag| str_hashcode(tag)).collect(),
+            tags: Some(tags),
+            sql: None,
+        }
+    }
+
+    /// 追加属性过滤表达式
+    pub fn with_sql(mut self, sql: Option<Arc<Expr>>) -> Self {
+        self.sql = sql;
+        self
+    }
+
+    /// 根据 consume_queue 中的 tag_hashcode 判断是否可能满足条件
+    pub fn is_match_hashcode(&self, tag_hashcode: u64) -> bool {
+        self.tags.is_none() || self.tag_hashcodes.contains(&tag_hashcode)
+    }
+
+    /// 读取消息后判断是否满足条件
+    pub fn is_match(&self, message: &Message) -> bool {
+        let tag_match = match &self.tags {
+            None => true,
+            Some(tags) => message.tag().is_some_and(|tag| tags.contains(tag)),
+        };
+        tag_match && self.sql.as_ref().is_none_or(|sql| sql.is_match(message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data_process_util::str_hashcode;
+    use crate::storage::filter::MessageFilter;
+    use crate::storage::message::Message;
+
+    #[test]
+    fn test_tags() {
+        assert_eq!(MessageFilter::tags("*"), MessageFilter::default());
+        assert_eq!(MessageFilter::tags(" "), MessageFilter::default());
+
+        let filter = MessageFilter::tags("TagA || TagB");
+        assert!(filter.is_match_hashcode(str_hashcode("TagA")));
+        assert!(filter.is_match_hashcode(str_hashcode("TagB")));
+        assert!(!filter.is_match_hashcode(str_hashcode("TagC")));
+        assert!(!filter.is_match_hashcode(str_hashcode("")));
+
+        assert!(filter.is_match(&Message::new("topic_oms", "", "_tag-TagB")));
+        assert!(!filter.is_match(&Message::new("topic_oms", "", "_tag-TagC")));
+        assert!(!filter.is_match(&Message::new("topic_oms", "", "")));
+        assert!(MessageFilter::default().is_match(&Message::new("topic_oms", "", "")));
+    }
+}
@@ -0,0 +1,477 @@
+//! SQL92 风格的属性过滤表达式，例如 `region = 'eu' AND priority > 3`
+//!
+//! 支持 AND、OR、NOT、括号、比较运算 `= <> != < <= > >=`、IS [NOT] NULL、[NOT] IN、[NOT] BETWEEN
+//!
+//! 属性值均以字符串存储，与数字比较时按数字解析，属性不存在或无法解析时结果为未知，
+//! 与 SQL 的 NULL 语义一致，只有结果为真的消息满足条件
+//!
+//! 表达式的嵌套层数不超过 [`MAX_DEPTH`]，避免解析和计算时递归过深导致栈溢出
+
+use crate::cust_error::FilterError;
+use crate::storage::message::Message;
+
+/// 表达式最大的嵌套层数，括号、NOT、AND、OR 各算一层
+pub const MAX_DEPTH: usize = 64;
+
+/// 表达式中的常量
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Str(String),
+    Num(f64),
+    Bool(bool),
+}
+
+/// 比较运算符
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// 过滤表达式
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Const(bool),
+    Cmp(String, CmpOp, Value),
+    IsNull(String),
+    In(String, Vec<Value>),
+    Between(String, Value, Value),
+}
+
+impl Expr {
+    /// 解析表达式
+    pub fn parse(sql: &str) -> Result<Self, FilterError> {
+        let mut parser = Parser {
+            tokens: tokenize(sql)?,
+            pos: 0,
+            depth: 0,
+        };
+        let expr = parser.or()?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(token) => Err(FilterError::Syntax(format!("多余的内容: {token:?}"))),
+        }
+    }
+
+    /// 表达式树的层数
+    fn depth(&self) -> usize {
+        match self {
+            Expr::And(left, right) | Expr::Or(left, right) => left.depth().max(right.depth()) + 1,
+            Expr::Not(expr) => expr.depth() + 1,
+            _ => 1,
+        }
+    }
+
+    /// 消息是否满足条件
+    pub fn is_match(&self, message: &Message) -> bool {
+        self.eval(message) == Some(true)
+    }
+
+    /// 计算表达式，None 表示结果未知
+    fn eval(&self, message: &Message) -> Option<bool> {
+        match self {
+            Expr::And(left, right) => match (left.eval(message), right.eval(message)) {
+                (Some(false), _) | (_, Some(false)) => Some(false),
+                (Some(true), Some(true)) => Some(true),
+                _ => None,
+            },
+            Expr::Or(left, right) => match (left.eval(message), right.eval(message)) {
+                (Some(true), _) | (_, Some(true)) => Some(true),
+                (Some(false), Some(false)) => Some(false),
+                _ => None,
+            },
+            Expr::Not(expr) => expr.eval(message).map(|result| !result),
+            Expr::Const(result) => Some(*result),
+            Expr::Cmp(key, op, value) => compare(message.prop_value(key)?, *op, value),
+            Expr::IsNull(key) => Some(message.prop_value(key).is_none()),
+            Expr::In(key, values) => {
+                let prop = message.prop_value(key)?;
+                let mut result = Some(false);
+                for value in values {
+                    match compare(prop, CmpOp::Eq, value) {
+                        Some(true) => return Some(true),
+                        None => result = None,
+                        Some(false) => {}
+                    }
+                }
+                result
+            }
+            Expr::Between(key, low, high) => {
+                let prop = message.prop_value(key)?;
+                Some(compare(prop, CmpOp::Ge, low)? && compare(prop, CmpOp::Le, high)?)
+            }
+        }
+    }
+}
+
+/// 比较属性值与常量，类型不匹配时结果未知
+fn compare(prop: &str, op: CmpOp, value: &Value) -> Option<bool> {
+    let ordering = match value {
+        Value::Num(num) => prop.trim().parse::<f64>().ok()?.partial_cmp(num)?,
+        Value::Str(str) => match op {
+            CmpOp::Eq | CmpOp::Ne => prop.cmp(str.as_str()),
+            _ => return None,
+        },
+        Value::Bool(bool) => match op {
+            CmpOp::Eq | CmpOp::Ne => prop.parse::<bool>().ok()?.cmp(bool),
+            _ => return None,
+        },
+    };
+    Some(match op {
+        CmpOp::Eq => ordering.is_eq(),
+        CmpOp::Ne => ordering.is_ne(),
+        CmpOp::Lt => ordering.is_lt(),
+        CmpOp::Le => ordering.is_le(),
+        CmpOp::Gt => ordering.is_gt(),
+        CmpOp::Ge => ordering.is_ge(),
+    })
+}
+
+/// 词法单元
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    /// 关键字，统一为大写
+    Keyword(&'static str),
+    Str(String),
+    Num(f64),
+    Op(CmpOp),
+    LParen,
+    RParen,
+    Comma,
+}
+
+const KEYWORDS: [&str; 9] = [
+    "AND", "OR", "NOT", "IS", "NULL", "IN", "BETWEEN", "TRUE", "FALSE",
+];
+
+/// 词法分析
+fn tokenize(sql: &str) -> Result<Vec<Token>, FilterError> {
+    let chars = sql.chars().collect::<Vec<_>>();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            c if c.is_whitespace() => i += 1,
+            '(' | ')' | ',' => {
+                tokens.push(match c {
+                    '(' => Token::LParen,
+                    ')' => Token::RParen,
+                    _ => Token::Comma,
+                });
+                i += 1;
+            }
+            '=' | '<' | '>' | '!' => {
+                let next = chars.get(i + 1).copied();
+                let (op, len) = match (c, next) {
+                    ('=', _) => (CmpOp::Eq, 1),
+                    ('<', Some('>')) | ('!', Some('=')) => (CmpOp::Ne, 2),
+                    ('<', Some('=')) => (CmpOp::Le, 2),
+                    ('>', Some('=')) => (CmpOp::Ge, 2),
+                    ('<', _) => (CmpOp::Lt, 1),
+                    ('>', _) => (CmpOp::Gt, 1),
+                    _ => return Err(FilterError::Syntax(format!("无法识别的字符: {c}"))),
+                };
+                tokens.push(Token::Op(op));
+                i += len;
+            }
+            '\'' => {
+                // 字符串中的 '' 表示一个单引号
+                let mut str = String::new();
+                i += 1;
+                loop {
+                    match (chars.get(i), chars.get(i + 1)) {
+                        (Some('\''), Some('\'')) => {
+                            str.push('\'');
+                            i += 2;
+                        }
+                        (Some('\''), _) => {
+                            i += 1;
+                            break;
+                        }
+                        (Some(c), _) => {
+                            str.push(*c);
+                            i += 1;
+                        }
+                        (None, _) => return Err(FilterError::Syntax("字符串未结束".to_string())),
+                    }
+                }
+                tokens.push(Token::Str(str));
+            }
+            c if c.is_ascii_digit() || c == '-' || c == '.' => {
+                let start = i;
+                i += 1;
+                while chars
+                    .get(i)
+                    .is_some_and(|c| c.is_ascii_digit() || *c == '.')
+                {
+                    i += 1;
+                }
+                let num = chars[start..i].iter().collect::<String>();
+                let num = num
+                    .parse::<f64>()
+                    .map_err(|_| FilterError::Syntax(format!("数字格式错误: {num}")))?;
+                tokens.push(Token::Num(num));
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let start = i;
+                while chars
+                    .get(i)
+                    .is_some_and(|c| c.is_alphanumeric() || *c == '_' || *c == '.')
+                {
+                    i += 1;
+                }
+                let word = chars[start..i].iter().collect::<String>();
+                let upper = word.to_ascii_uppercase();
+                tokens.push(match KEYWORDS.iter().find(|keyword| **keyword == upper) {
+                    Some(keyword) => Token::Keyword(keyword),
+                    None => Token::Ident(word),
+                });
+            }
+            c => return Err(FilterError::Syntax(format!("无法识别的字符: {c}"))),
+        }
+    }
+    Ok(tokens)
+}
+
+/// 递归下降语法分析，优先级从低到高为 OR、AND、NOT、比较
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    /// 当前递归的层数
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Token, FilterError> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| FilterError::Syntax("表达式不完整".to_string()))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    /// 下一个是指定的关键字时跳过并返回 true
+    fn keyword(&mut self, keyword: &str) -> bool {
+        let matched = self.peek() == Some(&Token::Keyword(keyword_ref(keyword)));
+        if matched {
+            self.pos += 1;
+        }
+        matched
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), FilterError> {
+        let token = self.next()?;
+        if token == expected {
+            Ok(())
+        } else {
+            Err(FilterError::Syntax(format!(
+                "期望 {expected:?}，实际为 {token:?}"
+            )))
+        }
+    }
+
+    /// 检查嵌套层数是否超出限制
+    fn check_depth(depth: usize) -> Result<(), FilterError> {
+        if depth > MAX_DEPTH {
+            return Err(FilterError::Syntax(format!(
+                "表达式嵌套超过 {MAX_DEPTH} 层"
+            )));
+        }
+        Ok(())
+    }
+
+    /// 进入一层递归，解析完成后退出
+    fn nested(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<Expr, FilterError>,
+    ) -> Result<Expr, FilterError> {
+        self.depth += 1;
+        Self::check_depth(self.depth)?;
+        let expr = parse(self);
+        self.depth -= 1;
+        expr
+    }
+
+    fn or(&mut self) -> Result<Expr, FilterError> {
+        let mut expr = self.and()?;
+        let mut depth = expr.depth();
+        while self.keyword("OR") {
+            let right = self.and()?;
+            // 连续的 OR 构成左深的表达式树，每个运算符增加一层
+            depth = depth.max(right.depth()) + 1;
+            Self::check_depth(depth)?;
+            expr = Expr::Or(Box::new(expr), Box::new(right));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, FilterError> {
+        let mut expr = self.not()?;
+        let mut depth = expr.depth();
+        while self.keyword("AND") {
+            let right = self.not()?;
+            depth = depth.max(right.depth()) + 1;
+            Self::check_depth(depth)?;
+            expr = Expr::And(Box::new(expr), Box::new(right));
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<Expr, FilterError> {
+        if self.keyword("NOT") {
+            return self.nested(|parser| Ok(Expr::Not(Box::new(parser.not()?))));
+        }
+        self.predicate()
+    }
+
+    fn predicate(&mut self) -> Result<Expr, FilterError> {
+        let key = match self.next()? {
+            Token::LParen => {
+                return self.nested(|parser| {
+                    let expr = parser.or()?;
+                    parser.expect(Token::RParen)?;
+                    Ok(expr)
+                });
+            }
+            Token::Keyword("TRUE") => return Ok(Expr::Const(true)),
+            Token::Keyword("FALSE") => return Ok(Expr::Const(false)),
+            Token::Ident(key) => key,
+            token => return Err(FilterError::Syntax(format!("期望属性名，实际为 {token:?}"))),
+        };
+
+        if self.keyword("IS") {
+            let negated = self.keyword("NOT");
+            self.expect(Token::Keyword(keyword_ref("NULL")))?;
+            let expr = Expr::IsNull(key);
+            return Ok(negate(expr, negated));
+        }
+        let negated = self.keyword("NOT");
+        if self.keyword("IN") {
+            self.expect(Token::LParen)?;
+            let mut values = vec![self.value()?];
+            while self.peek() == Some(&Token::Comma) {
+                self.pos += 1;
+                values.push(self.value()?);
+            }
+            self.expect(Token::RParen)?;
+            return Ok(negate(Expr::In(key, values), negated));
+        }
+        if self.keyword("BETWEEN") {
+            let low = self.value()?;
+            self.expect(Token::Keyword(keyword_ref("AND")))?;
+            let high = self.value()?;
+            return Ok(negate(Expr::Between(key, low, high), negated));
+        }
+        if negated {
+            return Err(FilterError::Syntax(format!(
+                "NOT 之后期望 IN 或 BETWEEN: {key}"
+            )));
+        }
+        match self.next()? {
+            Token::Op(op) => Ok(Expr::Cmp(key, op, self.value()?)),
+            token => Err(FilterError::Syntax(format!(
+                "期望比较运算符，实际为 {token:?}"
+            ))),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, FilterError> {
+        match self.next()? {
+            Token::Str(str) => Ok(Value::Str(str)),
+            Token::Num(num) => Ok(Value::Num(num)),
+            Token::Keyword("TRUE") => Ok(Value::Bool(true)),
+            Token::Keyword("FALSE") => Ok(Value::Bool(false)),
+            token => Err(FilterError::Syntax(format!("期望常量，实际为 {token:?}"))),
+        }
+    }
+}
+
+/// 获取关键字的静态引用
+fn keyword_ref(keyword: &str) -> &'static str {
+    KEYWORDS
+        .iter()
+        .find(|item| **item == keyword)
+        .expect("未定义的关键字")
+}
+
+fn negate(expr: Expr, negated: bool) -> Expr {
+    if negated {
+        Expr::Not(Box::new(expr))
+    } else {
+        expr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::filter::sql::{Expr, MAX_DEPTH};
+    use crate::storage::message::Message;
+
+    fn is_match(sql: &str, prop: &str) -> bool {
+        Expr::parse(sql)
+            .unwrap()
+            .is_match(&Message::new("topic_oms", "", prop))
+    }
+
+    #[test]
+    fn test_is_match() {
+        let prop = "region-eu;priority-5;vip-true";
+        assert!(is_match("region = 'eu' AND priority > 3", prop));
+        assert!(!is_match("region = 'eu' AND priority > 5", prop));
+        assert!(is_match("region <> 'us' or priority < 1", prop));
+        assert!(is_match("priority BETWEEN 1 AND 5 AND vip = TRUE", prop));
+        assert!(is_match("region IN ('us', 'eu') AND city IS NULL", prop));
+        assert!(is_match("NOT (region NOT IN ('eu'))", prop));
+        assert!(is_match("region IS NOT NULL AND priority >= 5.0", prop));
+    }
+
+    #[test]
+    fn test_null() {
+        // 属性不存在时比较结果未知，取反后依然未知
+        assert!(!is_match("city = 'paris'", "region-eu"));
+        assert!(!is_match("NOT city = 'paris'", "region-eu"));
+        assert!(is_match("city = 'paris' OR region = 'eu'", "region-eu"));
+        assert!(!is_match("priority > 3", "priority-high"));
+    }
+
+    #[test]
+    fn test_parse_error() {
+        assert!(Expr::parse("region = ").is_err());
+        assert!(Expr::parse("region = 'eu").is_err());
+        assert!(Expr::parse("region 'eu'").is_err());
+        assert!(Expr::parse("(region = 'eu'").is_err());
+        assert!(Expr::parse("region = 'eu' priority").is_err());
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let prop = "region-eu";
+        let nested =
+            |depth: usize| format!("{}region = 'eu'{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(is_match(&nested(MAX_DEPTH), prop));
+        assert!(Expr::parse(&nested(MAX_DEPTH + 1)).is_err());
+        assert!(Expr::parse(&nested(100_000)).is_err());
+        assert!(Expr::parse(&"NOT ".repeat(100_000)).is_err());
+
+        let chain = |len: usize| vec!["region = 'eu'"; len].join(" AND ");
+        assert!(is_match(&chain(MAX_DEPTH), prop));
+        assert!(Expr::parse(&chain(100_000)).is_err());
+        let chain = vec!["region = 'us'"; 100_000].join(" OR ");
+        assert!(Expr::parse(&chain).is_err());
+    }
+}
@@ -0,0 +1,739 @@
+//! 离线检查数据目录，直接读取 commit_log 与 consume_queue 文件
+//!
+//! 只读取文件，不启动存储实例，用于排查消息丢失或索引异常
+//!
+//! 修复会改写 commit_log 尾部与索引文件，需要先停止服务端
+
+use crate::common::config::Config;
+use crate::cust_error::{Error, MmapError};
+use crate::data_process_util::crc32;
+use crate::file_util::sorted_commit_log_files;
+use crate::storage::commit_log;
+use crate::storage::consume_queue::{self, queue_entries, BASE_DIR_NAME, LEVEL_TOPIC_PREFIX};
+use crate::storage::message::Message;
+use crate::storage::mmap::MmapWriter;
+use crate::storage::start_offset::{StartOffset, START_OFFSET_FILE};
+use crate::storage::topic::Tombstones;
+use log::warn;
+use memmap2::{Mmap, MmapOptions};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::path::Path;
+use std::str::FromStr;
+
+/// commit_log 记录的校验状态
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordStatus {
+    Ok,
+    /// 消息体的 CRC 校验失败
+    CrcMismatch,
+    /// 记录长度超出文件范围、数据不完整或物理偏移量与所在位置不一致
+    Corrupt,
+}
+
+/// commit_log 中的一条记录
+#[derive(Serialize, Debug, Clone)]
+pub struct LogRecord {
+    pub physical_offset: u64,
+    /// 记录总大小，包括 msg_len 自身的 4 字节
+    pub size: u32,
+    pub status: RecordStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// 无法解析时为 None
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<Message>,
+}
+
+impl LogRecord {
+    /// 损坏的记录
+    fn corrupt(physical_offset: u64, size: u32, error: String) -> Self {
+        LogRecord {
+            physical_offset,
+            size,
+            status: RecordStatus::Corrupt,
+            error: Some(error),
+            message: None,
+        }
+    }
+
+    /// 记录所属的 topic，无法解析时为 None
+    pub fn topic(&self) -> Option<&str> {
+        self.message.as_ref().map(|message| message.topic.as_str())
+    }
+}
+
+/// consume_queue 中的一条索引
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct QueueEntry {
+    /// 所在队列，即相对 consume_queue 目录的路径，如 topic/0
+    pub queue: String,
+    pub queue_offset: u64,
+    pub physical_offset: u64,
+    pub size: u32,
+    pub tag_hashcode: u64,
+    pub delay_time: u64,
+    /// 已到期或已取消
+    pub fired: bool,
+}
+
+/// 索引与 commit_log 的不一致
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Issue {
+    /// 索引指向的物理偏移量处没有记录
+    MissingRecord {
+        queue: String,
+        queue_offset: u64,
+        physical_offset: u64,
+    },
+    /// 索引指向的记录已损坏
+    CorruptRecord {
+        queue: String,
+        queue_offset: u64,
+        physical_offset: u64,
+        error: String,
+    },
+    /// 索引中的大小与记录的大小不一致
+    SizeMismatch {
+        queue: String,
+        queue_offset: u64,
+        physical_offset: u64,
+        size: u32,
+        record_size: u32,
+    },
+    /// 索引所在队列的 topic 与记录的 topic 不一致
+    TopicMismatch {
+        queue: String,
+        queue_offset: u64,
+        physical_offset: u64,
+        topic: String,
+    },
+    /// 记录没有对应的索引，queue 为应当写入的队列
+    Unindexed { physical_offset: u64, queue: String },
+    /// commit_log 中校验失败的记录
+    InvalidRecord {
+        physical_offset: u64,
+        status: RecordStatus,
+        error: String,
+    },
+    /// 最后一个 commit_log 文件中有效数据之后存在残留数据，或 start_offset 记录的写入位置与有效数据的结束位置不一致
+    CorruptTail {
+        physical_offset: u64,
+        write_offset: u64,
+    },
+}
+
+impl Issue {
+    /// 问题所在的队列，commit_log 的问题为 None
+    pub fn queue(&self) -> Option<&str> {
+        match self {
+            Issue::MissingRecord { queue, .. }
+            | Issue::CorruptRecord { queue, .. }
+            | Issue::SizeMismatch { queue, .. }
+            | Issue::TopicMismatch { queue, .. }
+            | Issue::Unindexed { queue, .. } => Some(queue),
+            Issue::InvalidRecord { .. } | Issue::CorruptTail { .. } => None,
+        }
+    }
+}
+
+/// 按物理偏移量顺序遍历 dir 下 commit_log 中物理偏移量不小于 from 的记录
+///
+/// 每个文件从头开始按长度链读取，遇到 msg_len 为 0 时文件结束，长度超出文件范围时输出一条损坏的记录并跳到下一个文件
+pub fn walk_commit_log(dir: &Path, from: u64, mut f: impl FnMut(LogRecord)) -> Result<(), Error> {
+    for (base, mmap) in commit_log_segments(dir)? {
+        if base + mmap.len() as u64 > from {
+            walk_segment(base, &mmap, |record| {
+                if record.physical_offset >= from {
+                    f(record);
+                }
+            });
+        }
+    }
+    Ok(())
+}
+
+/// 按长度链遍历起始物理偏移量为 base 的一个 commit_log 文件
+fn walk_segment(base: u64, data: &[u8], mut f: impl FnMut(LogRecord)) {
+    let mut pos = 0_usize;
+    while let Some(head) = data.get(pos..pos + 4) {
+        let msg_len = u32::from_le_bytes([head[0], head[1], head[2], head[3]]);
+        if msg_len == 0 {
+            break;
+        }
+        let physical_offset = base + pos as u64;
+        let size = msg_len.saturating_add(4);
+        let record = data
+            .get(pos..pos + size as usize)
+            .filter(|_| size >= Message::mix_len());
+        let Some(record) = record else {
+            f(LogRecord::corrupt(
+                physical_offset,
+                size,
+                format!("记录长度 {size} 超出文件范围或小于最小长度"),
+            ));
+            break;
+        };
+        f(parse_record(record, physical_offset));
+        pos += size as usize;
+    }
+}
+
+/// dir 下所有 commit_log 文件的起始物理偏移量与只读映射，按物理偏移量排序
+fn commit_log_segments(dir: &Path) -> Result<Vec<(u64, Mmap)>, Error> {
+    let dir_name = dir.join(commit_log::DIR_NAME).to_string_lossy().to_string();
+    if !Path::new(&dir_name).exists() {
+        return Ok(Vec::new());
+    }
+    let mut segments = Vec::new();
+    for file in sorted_commit_log_files(&dir_name)? {
+        let file_name = file.file_name().to_string_lossy().to_string();
+        let Ok(base) = u64::from_str(&file_name) else {
+            warn!("忽略不合法的 commit_log 文件：{file_name}");
+            continue;
+        };
+        let file = File::open(file.path()).map_err(|err| MmapError::OpenErr(err.to_string()))?;
+        if file.metadata()?.len() == 0 {
+            continue;
+        }
+        let mmap = unsafe { MmapOptions::new().map(&file) }
+            .map_err(|err| MmapError::MmapErr(err.to_string()))?;
+        segments.push((base, mmap));
+    }
+    Ok(segments)
+}
+
+/// 解析一条完整的记录，data 包括 msg_len 自身的 4 字节
+fn parse_record(data: &[u8], physical_offset: u64) -> LogRecord {
+    let size = data.len() as u32;
+    let mut body = data[4..].to_vec();
+    match Message::deserialize_binary(&mut body, size - 4) {
+        Ok(message) if message.physical_offset != physical_offset => LogRecord {
+            message: Some(message.clone()),
+            ..LogRecord::corrupt(
+                physical_offset,
+                size,
+                format!(
+                    "记录中的物理偏移量 {} 与所在位置不一致",
+                    message.physical_offset
+                ),
+            )
+        },
+        Ok(message) => LogRecord {
+            physical_offset,
+            size,
+            status: RecordStatus::Ok,
+            error: None,
+            message: Some(message),
+        },
+        Err(err) => {
+            let mut record = LogRecord::corrupt(physical_offset, size, err.to_string());
+            if crc_mismatch(data) {
+                record.status = RecordStatus::CrcMismatch;
+            }
+            record
+        }
+    }
+}
+
+/// 消息体完整但 CRC 与存储值不一致
+fn crc_mismatch(data: &[u8]) -> bool {
+    let field = |start: usize| {
+        data.get(start..start + 4)
+            .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    };
+    let (Some(body_crc), Some(body_len)) = (field(4), field(32)) else {
+        return false;
+    };
+    data.get(36..36 + body_len as usize)
+        .is_some_and(|body| crc32(body) != body_crc)
+}
+
+/// dir 下 topic 所有队列的索引，按队列与逻辑偏移量排序
+pub fn topic_entries(dir: &Path, topic: &str) -> Result<Vec<QueueEntry>, Error> {
+    let base_dir = dir.join(BASE_DIR_NAME).to_string_lossy().to_string();
+    let mut dirs = consume_queue::queue_dirs(&base_dir, topic);
+    dirs.sort();
+    let mut entries = Vec::new();
+    for dir_name in dirs {
+        let queue = dir_name
+            .strip_prefix(&format!("{base_dir}/"))
+            .unwrap_or(&dir_name)
+            .to_string();
+        for entry in queue_entries(&dir_name)? {
+            entries.push(QueueEntry {
+                queue: queue.clone(),
+                queue_offset: entry.queue_offset(),
+                physical_offset: entry.physical_offset(),
+                size: entry.size(),
+                tag_hashcode: entry.tag_hashcode(),
+                delay_time: entry.delay_time,
+                fired: entry.fired(),
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// dir 下所有延迟级别队列的索引
+fn level_entries(dir: &Path) -> Result<Vec<QueueEntry>, Error> {
+    let base_dir = dir.join(BASE_DIR_NAME).to_string_lossy().to_string();
+    let mut entries = Vec::new();
+    for topic in consume_queue::topic_names(&base_dir) {
+        if topic.starts_with(LEVEL_TOPIC_PREFIX) {
+            entries.extend(topic_entries(dir, &topic)?);
+        }
+    }
+    Ok(entries)
+}
+
+/// 检查 topic 的索引与 commit_log 是否一致
+///
+/// topic 的消息使用延迟级别时索引位于级别队列，级别队列中属于 topic 的索引同样参与检查
+pub fn check_topic(dir: &Path, topic: &str) -> Result<Vec<Issue>, Error> {
+    let mut records = HashMap::new();
+    walk_commit_log(dir, 0, |record| {
+        records.insert(record.physical_offset, record);
+    })?;
+    let mut issues = Vec::new();
+    let mut indexed = HashSet::new();
+    for entry in topic_entries(dir, topic)? {
+        indexed.insert(entry.physical_offset);
+        issues.extend(check_entry(
+            &entry,
+            records.get(&entry.physical_offset),
+            Some(topic),
+        ));
+    }
+    for entry in level_entries(dir)? {
+        let Some(record) = records.get(&entry.physical_offset) else {
+            continue;
+        };
+        if record.topic() == Some(topic) {
+            indexed.insert(entry.physical_offset);
+            issues.extend(check_entry(&entry, Some(record), None));
+        }
+    }
+    let tombstones = Tombstones::load(dir);
+    let mut unindexed = records
+        .values()
+        .filter(|record| record.status == RecordStatus::Ok && record.topic() == Some(topic))
+        .filter(|record| !indexed.contains(&record.physical_offset))
+        .filter_map(|record| record.message.as_ref())
+        .filter(|message| !tombstones.covers(topic, message.physical_offset))
+        .collect::<Vec<_>>();
+    unindexed.sort_by_key(|message| message.physical_offset);
+    issues.extend(unindexed.into_iter().map(|message| Issue::Unindexed {
+        physical_offset: message.physical_offset,
+        queue: consume_queue::queue_name(message),
+    }));
+    Ok(issues)
+}
+
+/// 检查一条索引是否指向大小与 topic 都一致的有效记录，topic 为 None 时不检查 topic
+fn check_entry(
+    entry: &QueueEntry,
+    record: Option<&LogRecord>,
+    topic: Option<&str>,
+) -> Option<Issue> {
+    let (queue, queue_offset, physical_offset) = (
+        entry.queue.clone(),
+        entry.queue_offset,
+        entry.physical_offset,
+    );
+    let Some(record) = record else {
+        return Some(Issue::MissingRecord {
+            queue,
+            queue_offset,
+            physical_offset,
+        });
+    };
+    if record.status != RecordStatus::Ok {
+        return Some(Issue::CorruptRecord {
+            queue,
+            queue_offset,
+            physical_offset,
+            error: record.error.clone().unwrap_or_default(),
+        });
+    }
+    if record.size != entry.size {
+        return Some(Issue::SizeMismatch {
+            queue,
+            queue_offset,
+            physical_offset,
+            size: entry.size,
+            record_size: record.size,
+        });
+    }
+    match (record.topic(), topic) {
+        (Some(record_topic), Some(topic)) if record_topic != topic => Some(Issue::TopicMismatch {
+            queue,
+            queue_offset,
+            physical_offset,
+            topic: record_topic.to_string(),
+        }),
+        _ => None,
+    }
+}
+
+/// 最后一个 commit_log 文件的尾部
+struct Tail {
+    /// 文件的起始物理偏移量
+    base: u64,
+    /// 最后一条有效记录的结束位置，相对文件起始位置
+    valid_end: u64,
+    /// 有效数据之后是否存在非零数据
+    dirty: bool,
+    /// start_offset 记录的写入位置，相对文件起始位置
+    write_offset: u64,
+}
+
+impl Tail {
+    /// 是否需要截断
+    fn corrupt(&self) -> bool {
+        self.dirty || self.write_offset != self.valid_end
+    }
+}
+
+/// 读取最后一个 commit_log 文件的尾部，没有 commit_log 文件时为 None
+fn commit_log_tail(dir: &Path) -> Result<Option<Tail>, Error> {
+    let Some((base, mmap)) = commit_log_segments(dir)?.pop() else {
+        return Ok(None);
+    };
+    let mut valid_end = 0;
+    walk_segment(base, &mmap, |record| {
+        if record.status == RecordStatus::Ok {
+            valid_end = record.physical_offset - base + record.size as u64;
+        }
+    });
+    let write_offset = fs::read(dir.join(START_OFFSET_FILE))
+        .ok()
+        .and_then(|data| data.get(..8).map(|bytes| bytes.try_into().unwrap()))
+        .map_or(0, u64::from_le_bytes);
+    Ok(Some(Tail {
+        base,
+        valid_end,
+        dirty: mmap[valid_end as usize..].iter().any(|byte| *byte != 0),
+        write_offset,
+    }))
+}
+
+/// 检查整个数据目录
+///
+/// 检查 commit_log 中每条记录的 CRC 与长度链、最后一个文件的尾部，以及所有队列的索引是否指向大小与 topic 一致的有效记录
+///
+/// 有效记录没有索引时视为缺少索引，包括整个 topic 目录丢失的情况，
+/// 已删除的 topic 在删除前写入的消息根据 config/deleted_topics.json 中的记录跳过
+pub fn verify(dir: &Path) -> Result<Vec<Issue>, Error> {
+    let mut issues = Vec::new();
+    let mut records = HashMap::new();
+    walk_commit_log(dir, 0, |record| {
+        if record.status != RecordStatus::Ok {
+            issues.push(Issue::InvalidRecord {
+                physical_offset: record.physical_offset,
+                status: record.status,
+                error: record.error.clone().unwrap_or_default(),
+            });
+        }
+        records.insert(record.physical_offset, record);
+    })?;
+    if let Some(tail) = commit_log_tail(dir)?.filter(Tail::corrupt) {
+        issues.push(Issue::CorruptTail {
+            physical_offset: tail.base + tail.valid_end,
+            write_offset: tail.base + tail.write_offset,
+        });
+    }
+
+    let base_dir = dir.join(BASE_DIR_NAME).to_string_lossy().to_string();
+    let mut topics = consume_queue::topic_names(&base_dir);
+    topics.sort();
+    let mut indexed = HashSet::new();
+    for topic in topics {
+        let expected = (!topic.starts_with(LEVEL_TOPIC_PREFIX)).then_some(topic.as_str());
+        for entry in topic_entries(dir, &topic)? {
+            indexed.insert(entry.physical_offset);
+            let record = records.get(&entry.physical_offset);
+            issues.extend(check_entry(&entry, record, expected));
+        }
+    }
+    let tombstones = Tombstones::load(dir);
+    let mut unindexed = records
+        .values()
+        .filter(|record| !indexed.contains(&record.physical_offset))
+        .filter_map(|record| {
+            record
+                .message
+                .as_ref()
+                .filter(|_| record.status == RecordStatus::Ok)
+        })
+        .filter(|message| !tombstones.covers(&message.topic, message.physical_offset))
+        .map(|message| (message.physical_offset, consume_queue::queue_name(message)))
+        .collect::<Vec<_>>();
+    unindexed.sort();
+    issues.extend(
+        unindexed
+            .into_iter()
+            .map(|(physical_offset, queue)| Issue::Unindexed {
+                physical_offset,
+                queue,
+            }),
+    );
+    Ok(issues)
+}
+
+/// 修复的结果
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct Repair {
+    /// 截断的 commit_log 尾部的起始物理偏移量
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub truncated: Option<u64>,
+    /// 重新生成索引的队列
+    pub rebuilt: Vec<String>,
+}
+
+/// 修复数据目录，只能在服务端停止时执行
+///
+/// 先迁移旧版本的索引文件格式，再截断最后一个 commit_log 文件中损坏的尾部并修正 start_offset，再根据 commit_log 重新生成存在问题的队列
+///
+/// 重新生成的队列只包含有效的记录，原有索引的到期标记与修改过的延迟时间会被保留，已写入 ready_queue 的消息标记为已到期，
+/// 索引丢失的已取消消息无法恢复取消状态
+///
+/// 中间文件中损坏的记录无法修复，修复后依然会被 verify 报告
+pub fn repair(dir: &Path, config: &Config) -> Result<Repair, Error> {
+    let mut repair = Repair::default();
+    consume_queue::migrate_legacy(dir, config)?;
+    if let Some(tail) = commit_log_tail(dir)?.filter(Tail::corrupt) {
+        truncate_tail(dir, &tail)?;
+        repair.truncated = Some(tail.base + tail.valid_end);
+    }
+    let queues = verify(dir)?
+        .iter()
+        .filter_map(|issue| issue.queue().map(str::to_string))
+        .collect::<BTreeSet<_>>();
+    if !queues.is_empty() {
+        consume_queue::rebuild_queues(dir, config, 0, Some(&queues))?;
+    }
+    repair.rebuilt = queues.into_iter().collect();
+    Ok(repair)
+}
+
+/// 清零最后一个 commit_log 文件中有效数据之后的内容，并将 start_offset 修正为有效数据的结束位置
+fn truncate_tail(dir: &Path, tail: &Tail) -> Result<(), Error> {
+    let path = dir
+        .join(commit_log::DIR_NAME)
+        .join(format!("{:020}", tail.base));
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .map_err(|err| MmapError::OpenErr(err.to_string()))?;
+    let mut mmap = MmapWriter::mmap_mut_create(&file, file.metadata()?.len())?;
+    mmap[tail.valid_end as usize..].fill(0);
+    mmap.flush()?;
+    StartOffset::open(dir)?.write(tail.valid_end);
+    warn!(
+        "截断 commit_log 尾部：物理偏移量 {}",
+        tail.base + tail.valid_end
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::config::Config;
+    use crate::file_util::temp_dir;
+    use crate::storage::commit_log::{self, put_message};
+    use crate::storage::consume_queue::BASE_DIR_NAME;
+    use crate::storage::inspect::{
+        check_topic, repair, topic_entries, verify, walk_commit_log, Issue, RecordStatus,
+    };
+    use crate::storage::message::Message;
+    use crate::storage::start_offset::StartOffset;
+    use crate::storage::store::Store;
+    use crate::storage::topic;
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+
+    #[tokio::test]
+    async fn test_inspect() {
+        let dir = temp_dir("inspect");
+        let config = Config::new().unwrap();
+        let store = Store::open(config.clone(), &dir).await.unwrap();
+        let topic = "topic_inspect";
+        let mut offsets = Vec::new();
+        for body in ["a", "b"] {
+            let message = Message::new(topic, body, "_delay-60");
+            offsets.push(put_message(&store, message).await.unwrap());
+        }
+
+        let mut records = Vec::new();
+        walk_commit_log(&dir, 0, |record| records.push(record)).unwrap();
+        assert_eq!(records.len(), 2);
+        assert!(records
+            .iter()
+            .all(|record| record.status == RecordStatus::Ok));
+        let entries = topic_entries(&dir, topic).unwrap();
+        let mut indexed = entries
+            .iter()
+            .map(|entry| entry.physical_offset)
+            .collect::<Vec<_>>();
+        indexed.sort();
+        assert_eq!(indexed, offsets);
+        assert_eq!(check_topic(&dir, topic).unwrap(), Vec::new());
+
+        // 修改第一条消息的消息体
+        let file_size = config.commit_log_file_size;
+        let path = dir
+            .join(commit_log::DIR_NAME)
+            .join(format!("{:020}", offsets[0] / file_size * file_size));
+        let mut file = OpenOptions::new().write(true).open(path).unwrap();
+        file.seek(SeekFrom::Start(offsets[0] % file_size + 36))
+            .unwrap();
+        file.write_all(b"x").unwrap();
+        let mut records = Vec::new();
+        walk_commit_log(&dir, offsets[1], |record| records.push(record)).unwrap();
+        assert_eq!(records.len(), 1);
+        records.clear();
+        walk_commit_log(&dir, 0, |record| records.push(record)).unwrap();
+        assert_eq!(records[0].status, RecordStatus::CrcMismatch);
+        let issues = check_topic(&dir, topic).unwrap();
+        assert!(matches!(
+            issues.as_slice(),
+            [Issue::CorruptRecord { physical_offset, .. }] if *physical_offset == offsets[0]
+        ));
+
+        // 删除索引后，完好的消息没有对应的索引
+        std::fs::remove_dir_all(dir.join("consume_queue").join(topic)).unwrap();
+        let issues = check_topic(&dir, topic).unwrap();
+        assert!(matches!(
+            issues.as_slice(),
+            [Issue::Unindexed { physical_offset, .. }] if *physical_offset == offsets[1]
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_verify_read_only() {
+        let dir = temp_dir("verify_read_only");
+        let config = Config::new().unwrap();
+        let store = Store::open(config.clone(), &dir).await.unwrap();
+        let message = Message::new("topic_read_only", "a", "_delay-60");
+        put_message(&store, message).await.unwrap();
+        drop(store);
+
+        // 检查不能创建 consume_queue 目录，否则存储实例打开时不会重建索引
+        std::fs::remove_dir_all(dir.join(BASE_DIR_NAME)).unwrap();
+        verify(&dir).unwrap();
+        check_topic(&dir, "topic_read_only").unwrap();
+        assert!(!dir.join(BASE_DIR_NAME).exists());
+    }
+
+    #[tokio::test]
+    async fn test_verify_lost_topic() {
+        let dir = temp_dir("verify_lost_topic");
+        let config = Config::new().unwrap();
+        let store = Store::open(config.clone(), &dir).await.unwrap();
+        let (lost, deleted) = ("topic_lost", "topic_deleted");
+        for topic in [lost, lost, deleted] {
+            let message = Message::new(topic, "a", "_delay-60");
+            put_message(&store, message).await.unwrap();
+        }
+        // 删除后再次写入，删除前的消息不需要索引
+        topic::delete(&store, deleted).await.unwrap();
+        let message = Message::new(deleted, "b", "_delay-60");
+        let recreated = put_message(&store, message).await.unwrap();
+        drop(store);
+        assert_eq!(verify(&dir).unwrap(), Vec::new());
+
+        // 整个 topic 目录丢失
+        std::fs::remove_dir_all(dir.join(BASE_DIR_NAME).join(lost)).unwrap();
+        std::fs::remove_dir_all(dir.join(BASE_DIR_NAME).join(deleted)).unwrap();
+        let issues = verify(&dir).unwrap();
+        assert_eq!(issues.len(), 3);
+        assert!(issues
+            .iter()
+            .all(|issue| matches!(issue, Issue::Unindexed { .. })));
+
+        let result = repair(&dir, &config).unwrap();
+        assert_eq!(result.rebuilt.len(), 3);
+        assert_eq!(verify(&dir).unwrap(), Vec::new());
+        assert_eq!(topic_entries(&dir, lost).unwrap().len(), 2);
+        let entries = topic_entries(&dir, deleted).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].physical_offset, recreated);
+    }
+
+    #[tokio::test]
+    async fn test_verify_repair() {
+        let dir = temp_dir("verify_repair");
+        let config = Config::new().unwrap();
+        let store = Store::open(config.clone(), &dir).await.unwrap();
+        let topic = "topic_verify";
+        for body in ["a", "b", "c"] {
+            let message = Message::new(topic, body, "_delay-60");
+            put_message(&store, message).await.unwrap();
+        }
+        let level = put_message(&store, Message::new(topic, "d", "_level-1"))
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+        let end = commit_log::max_offset(&store);
+        drop(store);
+        assert_eq!(verify(&dir).unwrap(), Vec::new());
+
+        // 写入中途宕机留下的残留数据，start_offset 已经更新
+        let file_size = config.commit_log_file_size;
+        let path = dir
+            .join(commit_log::DIR_NAME)
+            .join(format!("{:020}", end / file_size * file_size));
+        let mut file = OpenOptions::new().write(true).open(path).unwrap();
+        file.seek(SeekFrom::Start(end % file_size)).unwrap();
+        file.write_all(&[0xff; 8]).unwrap();
+        StartOffset::open(&dir).unwrap().write(end % file_size + 8);
+        // 删除一个队列，修改延迟级别队列中索引的大小
+        std::fs::remove_dir_all(dir.join("consume_queue").join(topic).join("1")).unwrap();
+        let level_file = dir
+            .join("consume_queue")
+            .join("%LEVEL%1")
+            .join("0")
+            .join(format!("{:020}", 0));
+        let mut file = OpenOptions::new().write(true).open(level_file).unwrap();
+        file.seek(SeekFrom::Start(8)).unwrap();
+        file.write_all(&1_u32.to_le_bytes()).unwrap();
+
+        let issues = verify(&dir).unwrap();
+        assert!(issues.iter().any(|issue| matches!(
+            issue,
+            Issue::CorruptTail { physical_offset, .. } if *physical_offset == end
+        )));
+        assert!(issues.iter().any(|issue| matches!(
+            issue,
+            Issue::Unindexed { queue, .. } if queue == "topic_verify/1"
+        )));
+        assert!(issues
+            .iter()
+            .any(|issue| matches!(issue, Issue::SizeMismatch { .. })));
+
+        let result = repair(&dir, &config).unwrap();
+        assert_eq!(result.truncated, Some(end));
+        assert_eq!(result.rebuilt, vec!["%LEVEL%1/0", "topic_verify/1"]);
+        assert_eq!(verify(&dir).unwrap(), Vec::new());
+        // 已写入 ready_queue 的延迟级别消息依然标记为已到期
+        let entries = topic_entries(&dir, "%LEVEL%1").unwrap();
+        assert!(entries
+            .iter()
+            .all(|entry| entry.physical_offset == level && entry.fired));
+
+        // 修复后从有效数据的结束位置继续写入
+        assert_eq!(
+            StartOffset::open(&dir).unwrap().read() as u64,
+            end % file_size
+        );
+        let store = Store::open(config.clone(), &dir).await.unwrap();
+        let message = Message::new(topic, "e", "_delay-60");
+        put_message(&store, message).await.unwrap();
+        assert_eq!(verify(&dir).unwrap(), Vec::new());
+    }
+}
@@ -1,10 +1,38 @@
 //! 消息对象
 
+use crate::common::config::{Config, PastDeliverPolicy};
 use crate::common::data_process_util::{crc32, crc_check};
+use crate::common::time_util::now_millis;
+use crate::cust_error::{DelayError, Error};
 use byteorder::{LittleEndian, ReadBytesExt};
 use serde::{Deserialize, Serialize};
-use std::io::{BufReader, Read};
-use std::time::SystemTime;
+use std::io::{self, BufReader, Read};
+use std::str::FromStr;
+
+/// 延迟时间属性，单位秒
+pub const PROP_DELAY: &str = "_delay";
+/// 延迟时间属性，单位毫秒，优先于 PROP_DELAY
+pub const PROP_DELAY_MS: &str = "_delay_ms";
+/// 指定投递时间属性，毫秒时间戳，优先于 PROP_DELAY_MS
+pub const PROP_DELIVER_AT: &str = "_deliver_at";
+/// 固定延迟级别属性，取值 1..N 对应配置的 delay_levels，优先于 PROP_DELIVER_AT
+pub const PROP_DELAY_LEVEL: &str = "_level";
+/// 重试消息的原始 topic
+pub const PROP_ORIGIN_TOPIC: &str = "_origin_topic";
+/// 消息已重新消费的次数
+pub const PROP_RECONSUME: &str = "_reconsume";
+/// 进入死信队列的原因
+pub const PROP_DLQ_REASON: &str = "_dlq_reason";
+/// 消息的 tag，消费者可以按 tag 过滤消息
+pub const PROP_TAG: &str = "_tag";
+/// 分片键，如订单号，相同分片键且到期时间相同的消息按写入顺序投递
+pub const PROP_SHARDING_KEY: &str = "_sharding_key";
+/// 消息所在的队列，写入时选择
+pub const PROP_QUEUE_ID: &str = "_queue_id";
+/// 最初版本以秒存储时间戳，小于此值的时间戳视为秒，毫秒时间戳在 1970 年 4 月之后均大于此值
+const SECONDS_TIMESTAMP_LIMIT: u64 = 10_000_000_000;
+/// 所有的延迟属性，重新投递时需要替换
+const DELAY_PROPS: [&str; 4] = [PROP_DELAY, PROP_DELAY_MS, PROP_DELIVER_AT, PROP_DELAY_LEVEL];
 
 /// 从文件中获取一条消息的方式：
 ///
@@ -19,9 +47,9 @@ pub struct Message {
     body_crc: u32,
     /// 在log 文件中的偏移量，物理偏移量 8
     pub physical_offset: u64,
-    /// 消息在客户端发送的时间戳 8
+    /// 消息在客户端发送的时间戳 毫秒 8
     send_timestamp: u64,
-    /// 消息在服务端存储的时间戳 8
+    /// 消息在服务端存储的时间戳 毫秒 8
     store_timestamp: u64,
     /// 消息体的长度 4
     body_len: u32,
@@ -33,11 +61,22 @@ pub struct Message {
     pub topic: String,
     /// 消息属性长度 2
     prop_len: u16,
-    /// 消息属性 <_delay-10>
+    /// 消息属性，多个属性以 ; 分隔，键值以第一个 - 分隔 <_delay-10;_delay_ms-1500;_level-3;_deliver_at-1700000000000>
     pub prop: String,
 }
 
 impl Message {
+    /// 创建一条待发送的消息
+    pub fn new(topic: &str, body: &str, prop: &str) -> Self {
+        Message {
+            send_timestamp: now_millis(),
+            body: body.to_string(),
+            topic: topic.to_string(),
+            prop: prop.to_string(),
+            ..Default::default()
+        }
+    }
+
     /// 消息固定长度大小
     pub fn mix_len() -> u32 {
         40
@@ -48,14 +87,159 @@ impl Message {
         self.msg_len + 4
     }
 
+    /// 消息在服务端存储的时间戳 毫秒
+    pub fn store_timestamp(&self) -> u64 {
+        self.store_timestamp
+    }
+
+    /// 写入 commit_log 前补全各字段长度与存储时间戳，客户端传入的长度不可信
+    ///
+    /// topic 与属性的长度以 u16 存储，超出时返回协议错误，不能截断后写入
+    pub fn store_init(&mut self) -> Result<(), Error> {
+        let field_len = |name: &str, len: usize| {
+            u16::try_from(len)
+                .map_err(|_| Error::Protocol(format!("消息{name}长度 {len} 超过上限 {}", u16::MAX)))
+        };
+        self.topic_len = field_len("topic", self.topic.len())?;
+        self.prop_len = field_len("属性", self.prop.len())?;
+        let msg_len = (Self::mix_len() - 4) as usize
+            + self.body.len()
+            + self.topic_len as usize
+            + self.prop_len as usize;
+        self.msg_len = u32::try_from(msg_len)
+            .map_err(|_| Error::Protocol(format!("消息长度 {msg_len} 超过上限")))?;
+        self.body_len = self.body.len() as u32;
+        self.store_timestamp = now_millis();
+        Ok(())
+    }
+
+    /// 获取指定的消息属性
+    pub fn prop_value(&self, key: &str) -> Option<&str> {
+        self.prop
+            .split(';')
+            .filter_map(|kv| kv.split_once('-'))
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v)
+    }
+
+    /// 设置消息属性，已存在时覆盖，属性值中的 ; 会被替换为 ,
+    pub fn set_prop(&mut self, key: &str, value: &str) {
+        self.remove_prop(key);
+        if !self.prop.is_empty() {
+            self.prop.push(';');
+        }
+        self.prop
+            .push_str(&format!("{key}-{}", value.replace(';', ",")));
+    }
+
+    /// 删除消息属性
+    pub fn remove_prop(&mut self, key: &str) {
+        self.prop = self
+            .prop
+            .split(';')
+            .filter(|kv| !kv.is_empty() && kv.split_once('-').is_none_or(|(k, _)| k != key))
+            .collect::<Vec<_>>()
+            .join(";");
+    }
+
+    /// 以固定延迟级别重新投递，替换原有的延迟属性
+    pub fn set_delay_level(&mut self, level: u8) {
+        self.clear_delay();
+        self.set_prop(PROP_DELAY_LEVEL, &level.to_string());
+    }
+
+    /// 清除所有延迟属性
+    pub fn clear_delay(&mut self) {
+        DELAY_PROPS.iter().for_each(|key| self.remove_prop(key));
+    }
+
+    /// 消息体内容
+    pub fn body(&self) -> &str {
+        &self.body
+    }
+
+    /// 消息的 tag
+    pub fn tag(&self) -> Option<&str> {
+        self.prop_value(PROP_TAG)
+    }
+
+    /// 消息的分片键
+    pub fn sharding_key(&self) -> Option<&str> {
+        self.prop_value(PROP_SHARDING_KEY)
+    }
+
+    /// 消息所在的队列
+    pub fn queue_id(&self) -> u32 {
+        self.prop_value(PROP_QUEUE_ID)
+            .and_then(|queue_id| queue_id.parse::<u32>().ok())
+            .unwrap_or_default()
+    }
+
+    /// 消息已重新消费的次数
+    pub fn reconsume_times(&self) -> u32 {
+        self.prop_value(PROP_RECONSUME)
+            .and_then(|times| times.parse::<u32>().ok())
+            .unwrap_or(0)
+    }
+
+    /// 消息指定的固定延迟级别，格式错误时视为未指定，写入前由 delay_millis 校验
+    pub fn delay_level(&self) -> Option<u8> {
+        self.prop_value(PROP_DELAY_LEVEL)
+            .and_then(|level| level.parse::<u8>().ok())
+    }
+
+    /// 解析并校验消息的延迟属性，返回相对 now 的延迟毫秒数
+    ///
+    /// 优先级：_level > _deliver_at > _delay_ms > _delay
+    ///
+    /// 延迟级别与过去时间的处理使用 config 中的设置，max 为消息所属 topic 的最大延迟毫秒数，对所有延迟属性生效
+    pub fn delay_millis(&self, now: u64, config: &Config, max: u64) -> Result<u64, DelayError> {
+        let delay = if let Some(level) = self.prop_value(PROP_DELAY_LEVEL) {
+            let level = Self::parse_prop::<u8>(level)?;
+            config
+                .delay_level_millis(level)
+                .ok_or(DelayError::InvalidLevel(level))?
+        } else if let Some(deliver_at) = self.prop_value(PROP_DELIVER_AT) {
+            let deliver_at = Self::parse_prop::<u64>(deliver_at)?;
+            if deliver_at <= now {
+                return match config.past_deliver_policy {
+                    PastDeliverPolicy::Immediate => Ok(0),
+                    PastDeliverPolicy::Reject => Err(DelayError::InPast(deliver_at)),
+                };
+            }
+            deliver_at - now
+        } else {
+            let delay = match (self.prop_value(PROP_DELAY_MS), self.prop_value(PROP_DELAY)) {
+                (Some(delay_ms), _) => Self::parse_prop::<i64>(delay_ms)?,
+                (None, Some(delay)) => Self::parse_prop::<i64>(delay)?.saturating_mul(1000),
+                (None, None) => return Err(DelayError::Missing),
+            };
+            if delay <= 0 {
+                return Err(DelayError::NotPositive(delay));
+            }
+            delay as u64
+        };
+        if delay > max {
+            return Err(DelayError::TooLong(delay, max));
+        }
+        Ok(delay)
+    }
+
+    /// 解析数字类型的属性值
+    fn parse_prop<T: FromStr>(value: &str) -> Result<T, DelayError> {
+        value
+            .parse::<T>()
+            .map_err(|_| DelayError::Invalid(value.to_string()))
+    }
+
     /// 序列化为 JSON
     pub fn serialize_json(&self) -> String {
         serde_json::to_string(self).unwrap()
     }
 
     /// 将客户端网络传输的JSON 反序列化为 message
-    pub fn deserialize_json(json: &str) -> Self {
-        serde_json::from_str::<Message>(json).unwrap()
+    pub fn deserialize_json(json: &str) -> Result<Self, Error> {
+        serde_json::from_str::<Message>(json).map_err(|err| Error::Protocol(err.to_string()))
     }
 
     /// 将对象序列化为文件存储的字节编码,使用小端序列化
@@ -66,13 +250,7 @@ impl Message {
         v.extend(crc32(self.body.as_bytes()).to_le_bytes());
         v.extend(self.physical_offset.to_le_bytes());
         v.extend(self.send_timestamp.to_le_bytes());
-        v.extend(
-            SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_secs()
-                .to_le_bytes(),
-        );
+        v.extend(self.store_timestamp.to_le_bytes());
 
         v.extend(self.body_len.to_le_bytes());
         v.extend(self.body.as_bytes());
@@ -86,17 +264,21 @@ impl Message {
     }
 
     /// 从文件夹中读取一个message出来
-    pub fn deserialize_binary(data: &mut Vec<u8>, msg_len: u32) -> Option<Message> {
+    ///
+    /// 数据不完整或 body 的 CRC 校验失败时返回 Error::Corrupt
+    pub fn deserialize_binary(data: &mut Vec<u8>, msg_len: u32) -> Result<Message, Error> {
         let mut reader = BufReader::new(data.as_slice());
-        let body_crc = reader.read_u32::<LittleEndian>().unwrap();
-        let physical_offset = reader.read_u64::<LittleEndian>().unwrap();
-        let send_timestamp = reader.read_u64::<LittleEndian>().unwrap();
-        let store_timestamp = reader.read_u64::<LittleEndian>().unwrap();
-        let (body_len, body) = Self::deserialize_binary_body(&mut reader, body_crc);
-        let (topic_len, topic) = Self::deserialize_binary_topic(&mut reader);
-        let (prop_len, prop) = Self::deserialize_binary_prop(&mut reader);
-
-        Some(Message {
+        let body_crc = reader.read_u32::<LittleEndian>().map_err(truncated)?;
+        let physical_offset = reader.read_u64::<LittleEndian>().map_err(truncated)?;
+        let send_timestamp = reader.read_u64::<LittleEndian>().map_err(truncated)?;
+        let send_timestamp = timestamp_millis(send_timestamp);
+        let store_timestamp = reader.read_u64::<LittleEndian>().map_err(truncated)?;
+        let store_timestamp = timestamp_millis(store_timestamp);
+        let (body_len, body) = Self::deserialize_binary_body(&mut reader, body_crc)?;
+        let (topic_len, topic) = Self::deserialize_binary_topic(&mut reader)?;
+        let (prop_len, prop) = Self::deserialize_binary_prop(&mut reader)?;
+
+        Ok(Message {
             msg_len,
             body_crc,
             physical_offset,
@@ -112,46 +294,71 @@ impl Message {
     }
 
     // body 处理
-    fn deserialize_binary_body(reader: &mut BufReader<&[u8]>, body_crc: u32) -> (u32, String) {
-        let body_len = reader.read_u32::<LittleEndian>().unwrap();
-        let mut body = vec![0u8; body_len as usize];
-        reader.read_exact(&mut body).unwrap();
-        crc_check(body_crc, body.as_slice());
+    fn deserialize_binary_body(
+        reader: &mut BufReader<&[u8]>,
+        body_crc: u32,
+    ) -> Result<(u32, String), Error> {
+        let body_len = reader.read_u32::<LittleEndian>().map_err(truncated)?;
+        let body = read_bytes(reader, body_len as usize)?;
+        crc_check(body_crc, body.as_slice())?;
         let body = String::from_utf8_lossy(body.as_slice()).to_string();
-        (body_len, body)
+        Ok((body_len, body))
     }
 
     // topic 处理
-    fn deserialize_binary_topic(reader: &mut BufReader<&[u8]>) -> (u16, String) {
-        let topic_len = reader.read_u16::<LittleEndian>().unwrap();
-        let mut topic = vec![0u8; topic_len as usize];
-        reader.read_exact(&mut topic).unwrap();
+    fn deserialize_binary_topic(reader: &mut BufReader<&[u8]>) -> Result<(u16, String), Error> {
+        let topic_len = reader.read_u16::<LittleEndian>().map_err(truncated)?;
+        let topic = read_bytes(reader, topic_len as usize)?;
         let topic = String::from_utf8_lossy(topic.as_slice()).to_string();
-        (topic_len, topic)
+        Ok((topic_len, topic))
     }
 
     // prop 处理
-    fn deserialize_binary_prop(reader: &mut BufReader<&[u8]>) -> (u16, String) {
-        let prop_len = reader.read_u16::<LittleEndian>().unwrap();
-        let mut prop = vec![0u8; prop_len as usize];
-        reader.read_exact(&mut prop).unwrap();
+    fn deserialize_binary_prop(reader: &mut BufReader<&[u8]>) -> Result<(u16, String), Error> {
+        let prop_len = reader.read_u16::<LittleEndian>().map_err(truncated)?;
+        let prop = read_bytes(reader, prop_len as usize)?;
         let prop = String::from_utf8_lossy(prop.as_slice()).to_string();
-        (prop_len, prop)
+        Ok((prop_len, prop))
+    }
+}
+
+/// 最初版本写入的秒时间戳转换为毫秒
+fn timestamp_millis(timestamp: u64) -> u64 {
+    if timestamp < SECONDS_TIMESTAMP_LIMIT {
+        timestamp * 1000
+    } else {
+        timestamp
     }
 }
 
+/// 读取 len 个字节
+fn read_bytes(reader: &mut BufReader<&[u8]>, len: usize) -> Result<Vec<u8>, Error> {
+    let mut data = vec![0u8; len];
+    reader.read_exact(&mut data).map_err(truncated)?;
+    Ok(data)
+}
+
+/// 数据长度不足，说明记录被截断
+fn truncated(err: io::Error) -> Error {
+    Error::Corrupt(format!("消息数据不完整: {err}"))
+}
+
 #[cfg(test)]
 mod tests {
+    use crate::common::config::{Config, PastDeliverPolicy};
     use crate::common::log_util::log_init;
-    use crate::storage::message::Message;
+    use crate::common::time_util::now_millis;
+    use crate::cust_error::{DelayError, Error};
+    use crate::storage::message::{
+        Message, PROP_DELAY, PROP_DELAY_MS, PROP_DLQ_REASON, PROP_RECONSUME,
+    };
     use log::info;
-    use std::time::SystemTime;
 
     #[test]
     fn test_json() {
         log_init();
         let json = String::from("{\"msg_len\":40,\"body_crc\":342342,\"physical_offset\":0,\"send_timestamp\":1232432443,\"store_timestamp\":1232432999,\"body_len\":0,\"body\":\"\",\"topic_len\":0,\"topic\":\"\",\"prop_len\":0,\"prop\":\"\"}");
-        let message = Message::deserialize_json(&json);
+        let message = Message::deserialize_json(&json).unwrap();
         info!("{:?}", message);
 
         let string = message.serialize_json();
@@ -160,19 +367,130 @@ mod tests {
 
     #[test]
     fn test_word_len() {
-        let timestamp = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+        let timestamp = now_millis();
         println!("timestamp: {timestamp}");
     }
 
+    #[test]
+    fn test_delay_millis() {
+        let now = now_millis();
+        let config = Config::new().unwrap();
+        let max = config.max_delay_millis();
+        let delay =
+            |prop: &str| Message::new("topic_oms", "", prop).delay_millis(now, &config, max);
+        assert_eq!(delay("_delay-10"), Ok(10_000));
+        assert_eq!(delay("_delay-10;_delay_ms-1500"), Ok(1500));
+        assert_eq!(delay("_level-2;_delay-10"), Ok(5000));
+        assert_eq!(delay(&format!("_deliver_at-{}", now + 300)), Ok(300));
+        assert_eq!(delay(&format!("_deliver_at-{}", now - 300)), Ok(0));
+
+        assert_eq!(delay(""), Err(DelayError::Missing));
+        assert_eq!(
+            delay("_delay-abc"),
+            Err(DelayError::Invalid("abc".to_string()))
+        );
+        assert_eq!(delay("_level-99"), Err(DelayError::InvalidLevel(99)));
+        assert_eq!(delay("_delay-0"), Err(DelayError::NotPositive(0)));
+        assert_eq!(delay("_delay_ms--5"), Err(DelayError::NotPositive(-5)));
+        assert_eq!(
+            delay(&format!("_delay_ms-{}", max + 1)),
+            Err(DelayError::TooLong(max + 1, max))
+        );
+        // 延迟级别同样受 topic 最大延迟的限制
+        let message = Message::new("topic_oms", "", "_level-2");
+        assert_eq!(
+            message.delay_millis(now, &config, 1000),
+            Err(DelayError::TooLong(5000, 1000))
+        );
+
+        let mut config = config;
+        config.past_deliver_policy = PastDeliverPolicy::Reject;
+        let message = Message::new("topic_oms", "", &format!("_deliver_at-{}", now - 300));
+        assert_eq!(
+            message.delay_millis(now, &config, max),
+            Err(DelayError::InPast(now - 300))
+        );
+    }
+
+    #[test]
+    fn test_set_prop() {
+        let mut message = Message::new("topic_oms", "", "_delay-10;region-eu");
+        message.set_prop(PROP_RECONSUME, "1");
+        assert_eq!(message.prop, "_delay-10;region-eu;_reconsume-1");
+        message.set_prop(PROP_RECONSUME, "2");
+        assert_eq!(message.reconsume_times(), 2);
+        message.set_delay_level(4);
+        assert_eq!(message.prop, "region-eu;_reconsume-2;_level-4");
+        message.set_prop(PROP_DLQ_REASON, "timeout;retry");
+        assert_eq!(message.prop_value(PROP_DLQ_REASON), Some("timeout,retry"));
+        message.clear_delay();
+        assert_eq!(message.delay_level(), None);
+    }
+
+    #[test]
+    fn test_prop_value() {
+        let json = String::from("{\"msg_len\":0,\"body_crc\":0,\"physical_offset\":0,\"send_timestamp\":0,\"store_timestamp\":0,\"body_len\":0,\"body\":\"\",\"topic_len\":0,\"topic\":\"\",\"prop_len\":0,\"prop\":\"_delay-10;_delay_ms-1500\"}");
+        let message = Message::deserialize_json(&json).unwrap();
+        assert_eq!(message.prop_value(PROP_DELAY), Some("10"));
+        assert_eq!(message.prop_value(PROP_DELAY_MS), Some("1500"));
+        assert_eq!(message.prop_value("_tag"), None);
+    }
+
     #[test]
     fn test_byte() {
         log_init();
         let json = String::from("{\"msg_len\":40,\"body_crc\":342342,\"physical_offset\":0,\"send_timestamp\":1232432443,\"store_timestamp\":1232432999,\"body_len\":21,\"body\":\"此情可待成追忆\",\"topic_len\":9,\"topic\":\"topic_oms\",\"prop_len\":0,\"prop\":\"\"}");
-        let message = Message::deserialize_json(&json);
+        let message = Message::deserialize_json(&json).unwrap();
         let serialized = bincode::serialize(&message).unwrap();
         info!("长度：{}", serialized.len());
     }
+
+    #[test]
+    fn test_deserialize_binary() {
+        let mut message = Message::new("topic_oms", "此情可待成追忆", "_delay-10");
+        message.store_init().unwrap();
+        let data = message.serialize_binary();
+        let msg_len = message.msg_len() - 4;
+        let decoded = Message::deserialize_binary(&mut data[4..].to_vec(), msg_len).unwrap();
+        assert_eq!(decoded.body, message.body);
+        assert_eq!(decoded.prop, message.prop);
+        assert_eq!(decoded.store_timestamp(), message.store_timestamp());
+
+        // 最初版本以秒存储时间戳，读取时转换为毫秒
+        let mut legacy = data[4..].to_vec();
+        let seconds = message.store_timestamp() / 1000;
+        legacy[20..28].copy_from_slice(&seconds.to_le_bytes());
+        let decoded = Message::deserialize_binary(&mut legacy, msg_len).unwrap();
+        assert_eq!(decoded.store_timestamp(), seconds * 1000);
+
+        let mut corrupted = data[4..].to_vec();
+        corrupted[40] ^= 0xff;
+        assert!(matches!(
+            Message::deserialize_binary(&mut corrupted, msg_len),
+            Err(Error::Corrupt(_))
+        ));
+        let mut truncated = data[4..data.len() - 3].to_vec();
+        assert!(matches!(
+            Message::deserialize_binary(&mut truncated, msg_len),
+            Err(Error::Corrupt(_))
+        ));
+        assert!(matches!(
+            Message::deserialize_json("{"),
+            Err(Error::Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn test_store_init() {
+        let topic = "t".repeat(u16::MAX as usize);
+        let mut message = Message::new(&topic, "", &"p".repeat(u16::MAX as usize));
+        message.store_init().unwrap();
+        assert_eq!(message.topic_len, u16::MAX);
+
+        // 超出 u16 的长度不能截断写入
+        let mut message = Message::new(&format!("{topic}t"), "", "_delay-10");
+        assert!(matches!(message.store_init(), Err(Error::Protocol(_))));
+        let mut message = Message::new("topic_oms", "", &"p".repeat(u16::MAX as usize + 1));
+        assert!(matches!(message.store_init(), Err(Error::Protocol(_))));
+    }
 }
@@ -0,0 +1,1040 @@
+//! 到期消息队列，延迟消息到期后写入对应 topic 队列的 ready_queue 等待消费
+//!
+//! 消费者组按 ready_queue 的逻辑偏移量拉取消息，确认后记录到 consumer_offset，每个队列的位置相互独立
+//!
+//! 同组的消费者共享拉取位置，每条消息只投递给其中一个，消费者下线时其未确认的消息释放给组内其他消费者
+//!
+//! 消息写入、释放或确认超时时发出通知，推送模式的订阅据此拉取并推送消息
+//!
+//! 拒绝或超时未确认的消息以递增的延迟级别写入重试 topic，借助延迟队列实现退避重试
+//!
+//! 超过最大重试次数的消息写入消费者组的死信 topic，可以查看并重放
+
+use crate::common::config::Config;
+use crate::cust_error::Error;
+use crate::file_util::sorted_commit_log_files;
+use crate::storage::commit_log::{self, PutResult};
+use crate::storage::consume_queue::{
+    consume_queue_read, current_files, next_queue_offset, persist_checkpoints, queue_dir_count,
+    queue_dirs, queue_entries, queue_key, queue_writer_create, remove_expired_files,
+    split_queue_name, topic_names, writers_init, ConsumeQueueWriter, QueueMessage,
+};
+use crate::storage::consumer_offset;
+use crate::storage::filter::MessageFilter;
+use crate::storage::message::{Message, PROP_DLQ_REASON, PROP_ORIGIN_TOPIC, PROP_RECONSUME};
+use crate::storage::store::Store;
+use crate::storage::topic;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, RwLock};
+
+/// 文件存储目录，相对数据目录，最终的目录还需要拼接对应topic的名称与队列
+///
+/// |ready_queue
+///     |topic_test
+///         |checkpoint
+///         |queue_id
+///             |filename
+pub(crate) const BASE_DIR_NAME: &str = "ready_queue";
+/// 重试 topic 前缀，完整名称为 %RETRY%group%topic
+const RETRY_TOPIC_PREFIX: &str = "%RETRY%";
+/// 第一次重试使用的延迟级别，之后每次重试加一，与 RocketMQ 一致
+const RETRY_DELAY_LEVEL: u8 = 3;
+/// 死信 topic 前缀，完整名称为 %DLQ%group
+const DLQ_TOPIC_PREFIX: &str = "%DLQ%";
+/// 确认超时的失败原因
+const TIMEOUT_REASON: &str = "确认超时";
+/// 消息可消费通知的缓冲数量，订阅者落后时只会丢失通知，下次通知时依然会拉取全部消息
+const NOTIFY_CAPACITY: usize = 1024;
+
+/// 消费者组在一个 topic 队列上的状态的 key：(topic, queue_id, group)
+type GroupQueue = (String, u32, String);
+
+/// 存储实例的 ready_queue 与消费者组的拉取状态
+pub(crate) struct ReadyQueue {
+    /// 存储目录
+    base_dir: String,
+    /// 每个文件的大小，与 consume_queue 相同
+    file_size: u64,
+    /// topic 队列区分的 writer，key 为 topic/queue_id
+    writers: RwLock<HashMap<String, ConsumeQueueWriter>>,
+    /// 消费者组在内存中的拉取位置，重启后从已确认的位置开始
+    cursors: Mutex<HashMap<GroupQueue, u64>>,
+    /// 已投递未确认的消息
+    inflight: Mutex<HashMap<GroupQueue, HashMap<u64, Inflight>>>,
+    /// 消费者下线后释放的未确认消息，优先投递给组内其他消费者
+    released: Mutex<HashMap<GroupQueue, BTreeSet<u64>>>,
+    /// 有新的消息可消费或消息确认超时时发送对应的 ready_queue topic
+    notify: broadcast::Sender<String>,
+    /// 每次拉取时第一个拉取的队列，轮换起始队列避免靠后的队列一直得不到消费
+    pull_start: AtomicU32,
+}
+
+impl ReadyQueue {
+    /// 打开数据目录下的 ready_queue
+    pub(crate) fn new(config: &Config, dir: &Path) -> Result<Self, Error> {
+        let base_dir = dir.join(BASE_DIR_NAME).to_string_lossy().to_string();
+        let file_size = config.consume_queue_file_size;
+        Ok(ReadyQueue {
+            writers: RwLock::new(writers_init(&base_dir, file_size)?),
+            base_dir,
+            file_size,
+            cursors: Mutex::new(HashMap::new()),
+            inflight: Mutex::new(HashMap::new()),
+            released: Mutex::new(HashMap::new()),
+            notify: broadcast::channel(NOTIFY_CAPACITY).0,
+            pull_start: AtomicU32::new(0),
+        })
+    }
+
+    /// topic 队列对应的存储目录
+    fn queue_dir(&self, topic: &str, queue_id: u32) -> String {
+        format!("{}/{}", self.base_dir, queue_key(topic, queue_id))
+    }
+
+    /// 队列中第一个文件的起始逻辑偏移量，之前的文件已过期删除，队列不存在时为 0
+    fn first_offset(&self, topic: &str, queue_id: u32) -> u64 {
+        sorted_commit_log_files(&self.queue_dir(topic, queue_id))
+            .ok()
+            .and_then(|files| {
+                files
+                    .first()
+                    .and_then(|file| u64::from_str(&file.file_name().to_string_lossy()).ok())
+            })
+            .unwrap_or_default()
+    }
+
+    /// 读取队列中 queue_offset 位置的索引
+    fn read(&self, topic: &str, queue_id: u32, queue_offset: u64) -> Option<QueueMessage> {
+        consume_queue_read(
+            &self.queue_dir(topic, queue_id),
+            self.file_size,
+            queue_offset,
+        )
+    }
+}
+
+/// 已投递未确认的消息
+#[derive(Debug)]
+struct Inflight {
+    /// 确认截止时间
+    deadline: Instant,
+    /// 拉取此消息的消费者
+    client_id: String,
+}
+
+/// 投递给消费者的消息
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Delivery {
+    /// 消息所在的 ready_queue topic，重试消息为重试 topic，确认时使用
+    pub topic: String,
+    /// 消息所在的队列，确认时使用
+    #[serde(default)]
+    pub queue_id: u32,
+    /// 在 ready_queue 中的逻辑偏移量，确认时使用
+    pub queue_offset: u64,
+    pub message: Message,
+}
+
+/// 消费者组在 topic 上的重试 topic
+pub fn retry_topic(group: &str, topic: &str) -> String {
+    format!("{RETRY_TOPIC_PREFIX}{group}%{topic}")
+}
+
+/// ready_queue topic 是否是 topic 本身或其重试 topic
+pub(crate) fn belongs_to_topic(name: &str, topic: &str) -> bool {
+    name == topic || name.starts_with(RETRY_TOPIC_PREFIX) && name.ends_with(&format!("%{topic}"))
+}
+
+/// 消费者组的死信 topic
+pub fn dlq_topic(group: &str) -> String {
+    format!("{DLQ_TOPIC_PREFIX}{group}")
+}
+
+/// 写入一条到期消息的索引，返回在 ready_queue 中的逻辑偏移量
+pub(crate) async fn append(
+    store: &Store,
+    topic: &str,
+    queue_id: u32,
+    queue_message: &QueueMessage,
+) -> Result<u64, Error> {
+    let ready_queue = &store.ready_queue;
+    let mut writers = ready_queue.writers.write().await;
+    let writer = match writers.entry(queue_key(topic, queue_id)) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => entry.insert(queue_writer_create(
+            &ready_queue.base_dir,
+            &queue_key(topic, queue_id),
+            ready_queue.file_size,
+            commit_log::dispatched_offset(store),
+        )?),
+    };
+    let queue_offset = writer.consume_queue_write(&queue_message.copy_to(0).serialize_binary())?;
+    info!("到期消息写入 ready_queue[{topic}/{queue_id}]：{queue_offset}");
+    let _ = ready_queue.notify.send(topic.to_string());
+    Ok(queue_offset)
+}
+
+/// 持久化 ready_queue 的 checkpoint，physical_offset 只用于记录
+pub(crate) async fn checkpoint(store: &Store, physical_offset: u64) {
+    let ready_queue = &store.ready_queue;
+    persist_checkpoints(
+        &ready_queue.base_dir,
+        &*ready_queue.writers.read().await,
+        physical_offset,
+    );
+}
+
+/// 所有存在到期消息的 topic，包括系统 topic
+pub(crate) fn topics(store: &Store) -> Vec<String> {
+    topic_names(&store.ready_queue.base_dir)
+}
+
+/// topic 已存在的到期消息队列数量
+pub(crate) fn existing_queue_count(store: &Store, topic: &str) -> u32 {
+    queue_dir_count(&store.ready_queue.base_dir, topic)
+}
+
+/// 删除 topic 所有队列中到期时间不晚于 expire_before 的文件，正在写入的文件不会删除
+///
+/// 删除的消息无论是否已消费都不再投递，消费者组的拉取位置与消费进度跳到剩余的第一个文件
+pub(crate) async fn clean_expired(store: &Store, topic: &str, expire_before: u64) {
+    let ready_queue = &store.ready_queue;
+    let queues = current_files(&*ready_queue.writers.read().await, topic);
+    for (queue_name, current_base) in queues {
+        let Some((_, queue_id)) = split_queue_name(&queue_name) else {
+            continue;
+        };
+        let dir_name = ready_queue.queue_dir(topic, queue_id);
+        match remove_expired_files(
+            store,
+            &dir_name,
+            current_base,
+            ready_queue.file_size,
+            expire_before,
+            false,
+        ) {
+            Ok(Some(first)) => skip_to(store, topic, queue_id, first),
+            Ok(None) => {}
+            Err(err) => warn!("删除过期的索引文件失败：{dir_name} {err}"),
+        }
+    }
+}
+
+/// 队列中 first 之前的文件已删除，所有消费者组的拉取位置、消费进度与未确认的消息跳过之前的消息
+fn skip_to(store: &Store, topic: &str, queue_id: u32, first: u64) {
+    let ready_queue = &store.ready_queue;
+    let in_queue = |key: &GroupQueue| key.0 == topic && key.1 == queue_id;
+    consumer_offset::skip_to(store, topic, queue_id, None, first);
+    for (_, cursor) in ready_queue
+        .cursors
+        .lock()
+        .unwrap()
+        .iter_mut()
+        .filter(|(key, _)| in_queue(key))
+    {
+        *cursor = (*cursor).max(first);
+    }
+    for (_, inflight) in ready_queue
+        .inflight
+        .lock()
+        .unwrap()
+        .iter_mut()
+        .filter(|(key, _)| in_queue(key))
+    {
+        inflight.retain(|queue_offset, _| *queue_offset >= first);
+    }
+    for (_, released) in ready_queue
+        .released
+        .lock()
+        .unwrap()
+        .iter_mut()
+        .filter(|(key, _)| in_queue(key))
+    {
+        released.retain(|queue_offset| *queue_offset >= first);
+    }
+    info!("ready_queue[{topic}/{queue_id}] 过期的消息已删除，消费进度跳到 {first}");
+}
+
+/// topic 已到期写入 ready_queue 的消息数量，不包括重试消息
+pub(crate) fn delivered_count(store: &Store, topic: &str) -> u64 {
+    let ready_queue = &store.ready_queue;
+    queue_dirs(&ready_queue.base_dir, topic)
+        .iter()
+        .map(|dir_name| match queue_entries(dir_name) {
+            Ok(entries) => entries.len() as u64,
+            Err(err) => {
+                warn!("读取 ready_queue 失败：{dir_name} {err}");
+                0
+            }
+        })
+        .sum()
+}
+
+/// 删除 topic 及其重试 topic 的 ready_queue 文件、拉取位置与消费进度
+pub(crate) async fn remove_topic(store: &Store, topic: &str) {
+    let ready_queue = &store.ready_queue;
+    let mut writers = ready_queue.writers.write().await;
+    for name in topic_names(&ready_queue.base_dir) {
+        if !belongs_to_topic(&name, topic) {
+            continue;
+        }
+        writers.retain(|key, _| !key.starts_with(&format!("{name}/")));
+        if let Err(err) = fs::remove_dir_all(Path::new(&ready_queue.base_dir).join(&name)) {
+            warn!("删除 ready_queue 目录失败：{name} {err}");
+        }
+        consumer_offset::remove_topic(store, &name);
+    }
+    let retain = |key: &GroupQueue| !belongs_to_topic(&key.0, topic);
+    ready_queue
+        .cursors
+        .lock()
+        .unwrap()
+        .retain(|key, _| retain(key));
+    ready_queue
+        .inflight
+        .lock()
+        .unwrap()
+        .retain(|key, _| retain(key));
+    ready_queue
+        .released
+        .lock()
+        .unwrap()
+        .retain(|key, _| retain(key));
+}
+
+/// 订阅消息可消费的通知，通知内容为 ready_queue topic
+pub fn notified(store: &Store) -> broadcast::Receiver<String> {
+    store.ready_queue.notify.subscribe()
+}
+
+/// 没有消费进度的消费者组从所有队列的末尾开始消费，不再投递之前到期的消息，返回设置的队列数量
+///
+/// 用于第一次加入的广播模式消费者，之后创建的队列从头开始消费
+pub async fn start_at_tail(store: &Store, group: &str) -> usize {
+    if consumer_offset::has_group(store, group) {
+        return 0;
+    }
+    let writers = store.ready_queue.writers.read().await;
+    let mut count = 0;
+    for (queue, writer) in writers.iter() {
+        let (Some((topic, queue_id)), Ok(offset)) =
+            (split_queue_name(queue), writer.write_offset())
+        else {
+            continue;
+        };
+        consumer_offset::start_at(store, topic, queue_id, group, offset);
+        count += 1;
+    }
+    info!("消费者组[{group}]从 {count} 个队列的末尾开始消费");
+    count
+}
+
+/// 消费者组内的一个消费者拉取最多 max 条到期消息，优先拉取重试消息
+///
+/// 拉取的消息需要在 visibility_timeout 内确认，否则将重新投递
+///
+/// 不满足过滤条件的消息对该消费者组视为已消费
+///
+/// topic 的多个队列依次拉取，每次拉取轮换起始队列
+pub fn pull(
+    store: &Store,
+    topic: &str,
+    group: &str,
+    client_id: &str,
+    max: usize,
+    filter: &MessageFilter,
+) -> Vec<Delivery> {
+    let start = store.ready_queue.pull_start.fetch_add(1, Ordering::Relaxed);
+    let mut deliveries = Vec::<Delivery>::with_capacity(max);
+    for topic in [retry_topic(group, topic), topic.to_string()] {
+        let count = topic::queue_count(store, &topic);
+        for i in 0..count {
+            let queue_id = (start + i) % count;
+            let remain = max - deliveries.len();
+            deliveries.extend(pull_queue(
+                store, &topic, queue_id, group, client_id, remain, filter,
+            ));
+        }
+    }
+    deliveries
+}
+
+/// 读取 ready_queue 中的一条消息，消息无法读取时记录日志并返回 None
+fn delivery(store: &Store, topic: &str, queue_id: u32, queue_offset: u64) -> Option<Delivery> {
+    let queue_message = store.ready_queue.read(topic, queue_id, queue_offset)?;
+    read_delivery(store, topic, queue_id, &queue_message)
+        .inspect_err(|err| error!("读取到期消息失败：{topic}/{queue_id} {queue_offset} {err}"))
+        .ok()
+}
+
+/// 根据 ready_queue 中的索引读取消息
+fn read_delivery(
+    store: &Store,
+    topic: &str,
+    queue_id: u32,
+    queue_message: &QueueMessage,
+) -> Result<Delivery, Error> {
+    let message =
+        commit_log::read_message(store, queue_message.physical_offset(), queue_message.size())?;
+    Ok(Delivery {
+        topic: topic.to_string(),
+        queue_id,
+        queue_offset: queue_message.queue_offset(),
+        message,
+    })
+}
+
+/// 读取满足过滤条件的消息，先比较索引中的 tag_hashcode，可能满足时才读取消息
+fn filtered_delivery(
+    store: &Store,
+    topic: &str,
+    queue_id: u32,
+    queue_message: &QueueMessage,
+    filter: &MessageFilter,
+) -> Result<Option<Delivery>, Error> {
+    if !filter.is_match_hashcode(queue_message.tag_hashcode()) {
+        return Ok(None);
+    }
+    let delivery = read_delivery(store, topic, queue_id, queue_message)?;
+    Ok(filter.is_match(&delivery.message).then_some(delivery))
+}
+
+/// 从 queue_offset 开始查看最多 max 条消息，不影响拉取位置与确认状态，用于查看死信
+pub fn peek(
+    store: &Store,
+    topic: &str,
+    queue_id: u32,
+    queue_offset: u64,
+    max: usize,
+) -> Vec<Delivery> {
+    let mut cursor = queue_offset;
+    let mut deliveries = Vec::<Delivery>::with_capacity(max);
+    while deliveries.len() < max {
+        let Some(delivery) = delivery(store, topic, queue_id, cursor) else {
+            break;
+        };
+        deliveries.push(delivery);
+        cursor = next_queue_offset(cursor, store.ready_queue.file_size);
+    }
+    deliveries
+}
+
+/// 从一个 ready_queue 队列拉取消息，先投递释放的消息，已确认的消息会被跳过
+fn pull_queue(
+    store: &Store,
+    topic: &str,
+    queue_id: u32,
+    group: &str,
+    client_id: &str,
+    max: usize,
+    filter: &MessageFilter,
+) -> Vec<Delivery> {
+    let ready_queue = &store.ready_queue;
+    let key = (topic.to_string(), queue_id, group.to_string());
+    let mut deliveries = pull_released(store, &key, max, filter);
+    if deliveries.len() < max {
+        let remain = max - deliveries.len();
+        deliveries.extend(pull_cursor(store, &key, remain, filter));
+    }
+
+    let deadline = Instant::now() + Duration::from_millis(store.config().visibility_timeout);
+    let mut inflight = ready_queue.inflight.lock().unwrap();
+    let inflight = inflight.entry(key).or_default();
+    deliveries.iter().for_each(|delivery| {
+        inflight.insert(
+            delivery.queue_offset,
+            Inflight {
+                deadline,
+                client_id: client_id.to_string(),
+            },
+        );
+    });
+    deliveries
+}
+
+/// 拉取消费者下线后释放的消息，取出的消息由本次拉取独占，读取时不持有锁
+///
+/// 读取失败的消息放回，下次拉取时重试
+fn pull_released(
+    store: &Store,
+    key: &GroupQueue,
+    max: usize,
+    filter: &MessageFilter,
+) -> Vec<Delivery> {
+    let ready_queue = &store.ready_queue;
+    let (topic, queue_id, group) = key;
+    let claimed = match ready_queue.released.lock().unwrap().get_mut(key) {
+        Some(released) => (0..max).map_while(|_| released.pop_first()).collect(),
+        None => Vec::new(),
+    };
+    let mut deliveries = Vec::<Delivery>::with_capacity(claimed.len());
+    let mut failed = Vec::new();
+    for queue_offset in claimed {
+        // 所在的文件已过期删除
+        let Some(queue_message) = ready_queue.read(topic, *queue_id, queue_offset) else {
+            continue;
+        };
+        match pull_entry(store, topic, *queue_id, group, &queue_message, filter) {
+            Ok(delivery) => deliveries.extend(delivery),
+            Err(err) => {
+                error!("读取到期消息失败，稍后重试：{topic}/{queue_id} {queue_offset} {err}");
+                failed.push(queue_offset);
+            }
+        }
+    }
+    if !failed.is_empty() {
+        let mut released = ready_queue.released.lock().unwrap();
+        released.entry(key.clone()).or_default().extend(failed);
+    }
+    deliveries
+}
+
+/// 从消费者组的拉取位置开始拉取消息，读取时不持有锁，读取失败的消息不会跳过，下次拉取时重试
+///
+/// 同组的消费者同时拉取时，只有拉取位置未被改变的结果生效，否则从新的拉取位置重新拉取
+fn pull_cursor(
+    store: &Store,
+    key: &GroupQueue,
+    max: usize,
+    filter: &MessageFilter,
+) -> Vec<Delivery> {
+    let ready_queue = &store.ready_queue;
+    let (topic, queue_id, group) = key;
+    let mut start = cursor(store, key);
+    loop {
+        let mut cursor = start;
+        let mut deliveries = Vec::<Delivery>::with_capacity(max);
+        while deliveries.len() < max {
+            let Some(queue_message) = ready_queue.read(topic, *queue_id, cursor) else {
+                break;
+            };
+            match pull_entry(store, topic, *queue_id, group, &queue_message, filter) {
+                Ok(delivery) => deliveries.extend(delivery),
+                Err(err) => {
+                    error!("读取到期消息失败，稍后重试：{topic}/{queue_id} {cursor} {err}");
+                    break;
+                }
+            }
+            cursor = next_queue_offset(cursor, ready_queue.file_size);
+        }
+
+        let mut cursors = ready_queue.cursors.lock().unwrap();
+        let current = cursors.entry(key.clone()).or_insert(start);
+        if *current == start {
+            *current = cursor;
+            return deliveries;
+        }
+        start = *current;
+    }
+}
+
+/// 消费者组在队列上的拉取位置，第一次拉取时从已确认的位置开始，之前的文件已删除时跳到剩余的第一个文件
+fn cursor(store: &Store, key: &GroupQueue) -> u64 {
+    let ready_queue = &store.ready_queue;
+    let cached = ready_queue.cursors.lock().unwrap().get(key).copied();
+    if let Some(cursor) = cached {
+        return cursor;
+    }
+    let (topic, queue_id, group) = key;
+    let committed = consumer_offset::committed(store, topic, *queue_id, group);
+    let first = ready_queue.first_offset(topic, *queue_id);
+    if committed < first {
+        consumer_offset::skip_to(store, topic, *queue_id, Some(group), first);
+    }
+    let mut cursors = ready_queue.cursors.lock().unwrap();
+    *cursors.entry(key.clone()).or_insert(committed.max(first))
+}
+
+/// 拉取一条未确认的消息，不满足过滤条件的消息直接确认
+///
+/// 集群模式下同组的消费者使用相同的 tag 表达式，不满足条件的消息组内其他消费者也不会消费
+///
+/// 无法读取的消息返回错误，不确认也不投递
+fn pull_entry(
+    store: &Store,
+    topic: &str,
+    queue_id: u32,
+    group: &str,
+    queue_message: &QueueMessage,
+    filter: &MessageFilter,
+) -> Result<Option<Delivery>, Error> {
+    let queue_offset = queue_message.queue_offset();
+    if consumer_offset::is_acked(store, topic, queue_id, group, queue_offset) {
+        return Ok(None);
+    }
+    let delivery = filtered_delivery(store, topic, queue_id, queue_message, filter)?;
+    if delivery.is_none() {
+        consumer_offset::ack(store, topic, queue_id, group, queue_offset);
+    }
+    Ok(delivery)
+}
+
+/// 释放消费者在组内所有未确认的消息，由组内其他消费者重新拉取，返回释放的数量
+///
+/// 消费者断开连接或心跳超时时调用，释放的消息不计入重试次数
+pub fn release(store: &Store, group: &str, client_id: &str) -> usize {
+    let ready_queue = &store.ready_queue;
+    let mut released = Vec::new();
+    for ((topic, queue_id, inflight_group), inflight) in
+        ready_queue.inflight.lock().unwrap().iter_mut()
+    {
+        if inflight_group != group {
+            continue;
+        }
+        inflight.retain(|queue_offset, inflight| {
+            let owned = inflight.client_id == client_id;
+            if owned {
+                released.push((topic.clone(), *queue_id, *queue_offset));
+            }
+            !owned
+        });
+    }
+
+    let count = released.len();
+    let mut table = ready_queue.released.lock().unwrap();
+    for (topic, queue_id, queue_offset) in released {
+        let _ = ready_queue.notify.send(topic.clone());
+        table
+            .entry((topic, queue_id, group.to_string()))
+            .or_default()
+            .insert(queue_offset);
+    }
+    if count > 0 {
+        info!("释放消费者[{client_id}]在组[{group}]中未确认的消息：{count}");
+    }
+    count
+}
+
+/// 消息是否已投递给 client_id 且尚未确认，确认超时重新投递或被释放后返回 false
+pub fn is_inflight(
+    store: &Store,
+    topic: &str,
+    queue_id: u32,
+    group: &str,
+    client_id: &str,
+    queue_offset: u64,
+) -> bool {
+    store
+        .ready_queue
+        .inflight
+        .lock()
+        .unwrap()
+        .get(&(topic.to_string(), queue_id, group.to_string()))
+        .and_then(|inflight| inflight.get(&queue_offset))
+        .is_some_and(|inflight| inflight.client_id == client_id)
+}
+
+/// 确认消息已消费完成，topic 与 queue_id 为 Delivery 中的 topic 与 queue_id
+pub fn ack(store: &Store, topic: &str, queue_id: u32, group: &str, queue_offset: u64) {
+    if let Some(inflight) = store.ready_queue.inflight.lock().unwrap().get_mut(&(
+        topic.to_string(),
+        queue_id,
+        group.to_string(),
+    )) {
+        inflight.remove(&queue_offset);
+    }
+    consumer_offset::ack(store, topic, queue_id, group, queue_offset);
+}
+
+/// 拒绝消息，消息将以递增的延迟级别写入重试 topic 后重新投递
+///
+/// 超过最大重试次数后写入死信 topic，reason 作为失败原因记录在消息属性中
+pub async fn nack(
+    store: &Store,
+    topic: &str,
+    queue_id: u32,
+    group: &str,
+    queue_offset: u64,
+    reason: &str,
+) {
+    if let Some(inflight) = store.ready_queue.inflight.lock().unwrap().get_mut(&(
+        topic.to_string(),
+        queue_id,
+        group.to_string(),
+    )) {
+        inflight.remove(&queue_offset);
+    }
+    retry(store, topic, queue_id, group, queue_offset, reason).await;
+}
+
+/// 将消息写入重试 topic 或死信 topic，写入成功后确认原消息
+async fn retry(
+    store: &Store,
+    topic: &str,
+    queue_id: u32,
+    group: &str,
+    queue_offset: u64,
+    reason: &str,
+) {
+    let Some(queue_message) = store.ready_queue.read(topic, queue_id, queue_offset) else {
+        warn!("重试消息不存在：{topic}/{queue_id} {queue_offset}");
+        return;
+    };
+    let mut message = match commit_log::read_message(
+        store,
+        queue_message.physical_offset(),
+        queue_message.size(),
+    ) {
+        Ok(message) => message,
+        Err(err) => {
+            error!("重试消息无法读取：{topic}/{queue_id} {queue_offset} {err}");
+            return;
+        }
+    };
+    let origin_topic = message
+        .prop_value(PROP_ORIGIN_TOPIC)
+        .unwrap_or(&message.topic)
+        .to_string();
+    let reconsume_times = message.reconsume_times() + 1;
+    message.set_prop(PROP_ORIGIN_TOPIC, &origin_topic);
+    message.set_prop(PROP_RECONSUME, &reconsume_times.to_string());
+
+    let config = store.config();
+    let result = if reconsume_times > config.max_reconsume_times {
+        message.topic = dlq_topic(group);
+        message.set_prop(PROP_DLQ_REASON, reason);
+        warn!(
+            "消息超过最大重试次数，写入死信 topic：{}，原因：{reason}",
+            message.topic
+        );
+        commit_log::put_immediate(store, message).await
+    } else {
+        let level = (RETRY_DELAY_LEVEL as u32 + reconsume_times - 1)
+            .min(config.delay_level_count() as u32) as u8;
+        message.topic = retry_topic(group, &origin_topic);
+        info!(
+            "消息第{reconsume_times}次重试，延迟级别：{level}，重试 topic：{}，原因：{reason}",
+            message.topic
+        );
+        if level == 0 {
+            // 没有配置延迟级别时立即重新投递
+            commit_log::put_immediate(store, message).await
+        } else {
+            message.set_delay_level(level);
+            commit_log::put_system(store, message).await
+        }
+    };
+    match result {
+        Ok(_) => consumer_offset::ack(store, topic, queue_id, group, queue_offset),
+        Err(err) => error!("重试消息写入失败：{err}"),
+    }
+}
+
+/// 重放一条死信消息，消息立即重新投递到原始 topic，并确认该死信
+///
+/// 死信 topic 只有一个队列，死信不存在时返回 [`Error::NotFound`]
+pub async fn replay_dead_letter(store: &Store, group: &str, queue_offset: u64) -> PutResult {
+    let topic = dlq_topic(group);
+    let queue_message = store
+        .ready_queue
+        .read(&topic, 0, queue_offset)
+        .ok_or_else(|| Error::NotFound(format!("死信消息 {topic} {queue_offset}")))?;
+    let mut message =
+        commit_log::read_message(store, queue_message.physical_offset(), queue_message.size())?;
+    message.topic = message
+        .prop_value(PROP_ORIGIN_TOPIC)
+        .ok_or_else(|| Error::NotFound(format!("死信消息的原始 topic {topic} {queue_offset}")))?
+        .to_string();
+    [PROP_ORIGIN_TOPIC, PROP_RECONSUME, PROP_DLQ_REASON]
+        .iter()
+        .for_each(|key| message.remove_prop(key));
+    info!("重放死信消息：{topic} {queue_offset} -> {}", message.topic);
+
+    let result = commit_log::put_immediate(store, message).await;
+    if result.is_ok() {
+        consumer_offset::ack(store, &topic, 0, group, queue_offset);
+    }
+    result
+}
+
+/// 启动超时未确认消息的重新投递任务，存储实例释放后任务退出
+pub(crate) fn redelivery_task(store: &Arc<Store>) {
+    let store = Arc::downgrade(store);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            ticker.tick().await;
+            let Some(store) = store.upgrade() else {
+                break;
+            };
+            for ((topic, queue_id, group), queue_offset) in expired_inflight(&store) {
+                warn!("消息确认超时，重新投递：{topic}/{queue_id} {group} {queue_offset}");
+                retry(
+                    &store,
+                    &topic,
+                    queue_id,
+                    &group,
+                    queue_offset,
+                    TIMEOUT_REASON,
+                )
+                .await;
+                // 推送模式的订阅据此归还超时消息占用的 credit
+                let _ = store.ready_queue.notify.send(topic);
+            }
+        }
+    });
+}
+
+/// 取出所有确认超时的消息
+fn expired_inflight(store: &Store) -> Vec<(GroupQueue, u64)> {
+    let now = Instant::now();
+    let mut expired = Vec::new();
+    for (key, inflight) in store.ready_queue.inflight.lock().unwrap().iter_mut() {
+        inflight.retain(|queue_offset, inflight| {
+            let alive = inflight.deadline > now;
+            if !alive {
+                expired.push((key.clone(), *queue_offset));
+            }
+            alive
+        });
+    }
+    expired
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::config::Config;
+    use crate::file_util::temp_dir;
+    use crate::storage::commit_log::put_message;
+    use crate::storage::consume_queue::QueueMessage;
+    use crate::storage::filter::MessageFilter;
+    use crate::storage::message::{Message, PROP_DLQ_REASON, PROP_ORIGIN_TOPIC};
+    use crate::storage::ready_queue::{
+        ack, append, dlq_topic, is_inflight, nack, peek, pull, release, replay_dead_letter,
+        retry_topic, Delivery,
+    };
+    use crate::storage::store::Store;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// 延迟级别较短的配置，重试消息很快到期
+    fn config() -> Config {
+        let mut config = Config::new().unwrap();
+        config.delay_levels = String::from("10ms 20ms 30ms 40ms");
+        config
+    }
+
+    async fn put(store: &Store, topic: &str) -> u64 {
+        let message = Message::new(topic, "hello", "_delay_ms-1");
+        put_message(store, message).await.unwrap()
+    }
+
+    /// 等待消息到期后拉取
+    async fn pull_due(store: &Store, topic: &str, group: &str, client_id: &str) -> Vec<Delivery> {
+        for _ in 0..150 {
+            let deliveries = pull(
+                store,
+                topic,
+                group,
+                client_id,
+                10,
+                &MessageFilter::default(),
+            );
+            if !deliveries.is_empty() {
+                return deliveries;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("消息未到期：{topic}");
+    }
+
+    async fn nack_delivery(store: &Store, group: &str, delivery: &Delivery) {
+        let (topic, queue_id, queue_offset) =
+            (&delivery.topic, delivery.queue_id, delivery.queue_offset);
+        nack(store, topic, queue_id, group, queue_offset, "failed").await;
+    }
+
+    async fn store(name: &str, config: Config) -> Arc<Store> {
+        Store::open(config, temp_dir(name)).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_nack_retry() {
+        let store = store("ready_nack_retry", config()).await;
+        let (topic, group) = ("topic_nack_retry", "group_nack_retry");
+        let physical_offset = put(&store, topic).await;
+
+        // 每次重试的延迟级别递增，不超过配置的级别数量
+        let mut delivery = pull_due(&store, topic, group, "c1").await.remove(0);
+        assert_eq!(delivery.topic, topic);
+        for (times, level) in [(1, 3), (2, 4), (3, 4)] {
+            nack_delivery(&store, group, &delivery).await;
+            delivery = pull_due(&store, topic, group, "c1").await.remove(0);
+            assert_eq!(delivery.topic, retry_topic(group, topic));
+            assert_eq!(delivery.message.topic, retry_topic(group, topic));
+            assert_eq!(delivery.message.delay_level(), Some(level));
+            assert_eq!(delivery.message.reconsume_times(), times);
+        }
+        assert_ne!(delivery.message.physical_offset, physical_offset);
+        ack(
+            &store,
+            &delivery.topic,
+            delivery.queue_id,
+            group,
+            delivery.queue_offset,
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(pull(&store, topic, group, "c1", 10, &MessageFilter::default()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_visibility_timeout() {
+        let mut config = config();
+        config.visibility_timeout = 100;
+        let store = store("ready_visibility_timeout", config).await;
+        let (topic, group) = ("topic_visibility_timeout", "group_visibility_timeout");
+        put(&store, topic).await;
+
+        let delivery = pull_due(&store, topic, group, "c1").await.remove(0);
+        let (queue_id, queue_offset) = (delivery.queue_id, delivery.queue_offset);
+        assert!(is_inflight(
+            &store,
+            topic,
+            queue_id,
+            group,
+            "c1",
+            queue_offset
+        ));
+
+        // 超时未确认的消息写入重试 topic 重新投递
+        let retried = pull_due(&store, topic, group, "c2").await.remove(0);
+        assert_eq!(retried.topic, retry_topic(group, topic));
+        assert_eq!(retried.message.reconsume_times(), 1);
+        assert!(!is_inflight(
+            &store,
+            topic,
+            queue_id,
+            group,
+            "c1",
+            queue_offset
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_release() {
+        let store = store("ready_release", config()).await;
+        let (topic, group) = ("topic_release", "group_release");
+        put(&store, topic).await;
+
+        let delivery = pull_due(&store, topic, group, "c1").await.remove(0);
+        let filter = MessageFilter::default();
+        assert!(pull(&store, topic, group, "c2", 10, &filter).is_empty());
+        assert_eq!(release(&store, group, "c2"), 0);
+
+        // 离开的消费者未确认的消息释放给组内其他消费者，不计入重试次数
+        assert_eq!(release(&store, group, "c1"), 1);
+        let released = pull(&store, topic, group, "c2", 10, &filter);
+        assert_eq!(released.len(), 1);
+        assert_eq!(released[0].topic, topic);
+        assert_eq!(released[0].queue_offset, delivery.queue_offset);
+        assert_eq!(released[0].message.reconsume_times(), 0);
+        let (queue_id, queue_offset) = (delivery.queue_id, delivery.queue_offset);
+        assert!(is_inflight(
+            &store,
+            topic,
+            queue_id,
+            group,
+            "c2",
+            queue_offset
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_unreadable() {
+        let store = store("ready_unreadable", config()).await;
+        let (topic, group) = ("topic_unreadable", "group_unreadable");
+        let filter = MessageFilter::default();
+        // 索引指向的消息无法读取
+        let queue_message = QueueMessage::new(0, 1, "", 0);
+        let queue_offset = append(&store, topic, 0, &queue_message).await.unwrap();
+
+        // 拉取位置停在无法读取的消息上，之后的拉取依然重试
+        for _ in 0..2 {
+            assert!(pull(&store, topic, group, "c1", 10, &filter).is_empty());
+            let key = (topic.to_string(), 0, group.to_string());
+            let cursors = store.ready_queue.cursors.lock().unwrap();
+            assert_eq!(cursors[&key], queue_offset);
+        }
+
+        // 释放的消息读取失败时放回，下次拉取时重试
+        let key = (topic.to_string(), 0, group.to_string());
+        let released = || store.ready_queue.released.lock().unwrap()[&key].clone();
+        store
+            .ready_queue
+            .released
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .insert(queue_offset);
+        assert!(pull(&store, topic, group, "c1", 10, &filter).is_empty());
+        assert!(released().contains(&queue_offset));
+    }
+
+    #[tokio::test]
+    async fn test_retry_without_delay_levels() {
+        let mut config = config();
+        config.delay_levels = String::new();
+        let store = store("ready_retry_without_levels", config).await;
+        let (topic, group) = ("topic_retry_without_levels", "group_retry_without_levels");
+        put(&store, topic).await;
+
+        // 没有配置延迟级别时立即重新投递
+        let delivery = pull_due(&store, topic, group, "c1").await.remove(0);
+        nack_delivery(&store, group, &delivery).await;
+        let delivery = pull_due(&store, topic, group, "c1").await.remove(0);
+        assert_eq!(delivery.topic, retry_topic(group, topic));
+        assert_eq!(delivery.message.delay_level(), None);
+        assert_eq!(delivery.message.reconsume_times(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_dead_letter() {
+        let mut config = config();
+        config.max_reconsume_times = 1;
+        let store = store("ready_dead_letter", config).await;
+        let (topic, group) = ("topic_dead_letter", "group_dead_letter");
+        put(&store, topic).await;
+
+        // 超过最大重试次数后写入死信 topic，不再投递
+        let delivery = pull_due(&store, topic, group, "c1").await.remove(0);
+        nack_delivery(&store, group, &delivery).await;
+        let delivery = pull_due(&store, topic, group, "c1").await.remove(0);
+        assert_eq!(delivery.message.reconsume_times(), 1);
+        nack_delivery(&store, group, &delivery).await;
+        let dead_letters = loop {
+            let dead_letters = peek(&store, &dlq_topic(group), 0, 0, 10);
+            if !dead_letters.is_empty() {
+                break dead_letters;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        };
+        assert_eq!(dead_letters.len(), 1);
+        let dead_letter = &dead_letters[0].message;
+        assert_eq!(dead_letter.topic, dlq_topic(group));
+        assert_eq!(dead_letter.prop_value(PROP_ORIGIN_TOPIC), Some(topic));
+        assert_eq!(dead_letter.prop_value(PROP_DLQ_REASON), Some("failed"));
+        assert_eq!(dead_letter.reconsume_times(), 2);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(pull(&store, topic, group, "c1", 10, &MessageFilter::default()).is_empty());
+
+        // 重放后立即投递到原始 topic，重试次数清零
+        replay_dead_letter(&store, group, dead_letters[0].queue_offset)
+            .await
+            .unwrap();
+        let replayed = pull_due(&store, topic, group, "c1").await.remove(0);
+        assert_eq!(replayed.topic, topic);
+        assert_eq!(replayed.message.body(), "hello");
+        assert_eq!(replayed.message.reconsume_times(), 0);
+        assert_eq!(replayed.message.prop_value(PROP_DLQ_REASON), None);
+    }
+}
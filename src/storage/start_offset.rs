@@ -7,63 +7,71 @@ use std::fs::OpenOptions;
 use std::io::Cursor;
 pub use std::io::Write;
 use std::ops::DerefMut;
+use std::path::Path;
 
+use crate::cust_error::MmapError;
 use crate::storage::mmap::MmapWriter;
 use memmap2::MmapMut;
 
-
 /// 存储文件名
-const START_OFFSET_FILE: &str = "start_offset";
-/// 存储映射引用
-static mut START_OFFSET: Option<MmapMut> = None;
+pub(crate) const START_OFFSET_FILE: &str = "start_offset";
 
-/// 单例获取 START_OFFSET
-fn instance() -> &'static mut MmapMut {
-    unsafe {
-        if START_OFFSET.is_none() {
-            let file = OpenOptions::new()
-                .create(true)
-                .write(true)
-                .read(true)
-                .open(START_OFFSET_FILE)
-                .expect("打开 start_offset 存储文件失败");
-            START_OFFSET = Some(MmapWriter::mmap_mut_create(&file, 8));
-        }
-        START_OFFSET.as_mut().unwrap()
-    }
+/// commit_log 当前文件写入的位置，由 commit_log 写入任务独占
+#[derive(Debug)]
+pub(crate) struct StartOffset {
+    /// 存储映射引用
+    mmap: MmapMut,
 }
 
-/// 持久化 start_offset
-pub fn write(offset: u64) {
-    instance()
-        .deref_mut()
-        .write_u64::<LittleEndian>(offset)
-        .unwrap_or_else(|err| {
-            error!("持久化 start_offset 文件错误 \n{:?}", err);
-        });
-}
+impl StartOffset {
+    /// 打开 dir 目录下的存储文件，不存在时创建
+    pub(crate) fn open(dir: &Path) -> Result<Self, MmapError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .read(true)
+            .open(dir.join(START_OFFSET_FILE))
+            .map_err(|err| MmapError::OpenErr(err.to_string()))?;
+        Ok(StartOffset {
+            mmap: MmapWriter::mmap_mut_create(&file, 8)?,
+        })
+    }
 
-/// 获取文件存储的 start_offset
-pub fn read() -> usize {
-    let mut reader = Cursor::new(instance().deref_mut());
-    let offset = reader.read_u64::<LittleEndian>().unwrap_or_else(|err| {
-        error!("读取 start_offset 文件错误 \n{:?},返回默认 0", err);
-        0_u64
-    });
-    offset as usize
+    /// 持久化 start_offset
+    pub(crate) fn write(&mut self, offset: u64) {
+        self.mmap
+            .deref_mut()
+            .write_u64::<LittleEndian>(offset)
+            .unwrap_or_else(|err| {
+                error!("持久化 start_offset 文件错误 \n{:?}", err);
+            });
+    }
+
+    /// 获取文件存储的 start_offset
+    pub(crate) fn read(&mut self) -> usize {
+        let mut reader = Cursor::new(self.mmap.deref_mut());
+        let offset = reader.read_u64::<LittleEndian>().unwrap_or_else(|err| {
+            error!("读取 start_offset 文件错误 \n{:?},返回默认 0", err);
+            0_u64
+        });
+        offset as usize
+    }
 }
 
 #[cfg(test)]
-
 mod tests {
     use crate::common::log_util::log_init;
-    use crate::storage::start_offset::read;
-    use log::info;
+    use crate::file_util::temp_dir;
+    use crate::storage::start_offset::StartOffset;
 
     #[test]
     fn test_start_offset_read() {
         log_init();
-        let i = read();
-        info!("{i}");
+        let dir = temp_dir("start_offset");
+        let mut start_offset = StartOffset::open(&dir).unwrap();
+        assert_eq!(start_offset.read(), 0);
+        start_offset.write(66);
+        assert_eq!(StartOffset::open(&dir).unwrap().read(), 66);
     }
 }
@@ -0,0 +1,113 @@
+//! 存储实例，持有一个数据目录下的 commit_log、consume_queue、ready_queue 与元数据
+//!
+//! 数据目录结构：
+//!
+//! |dir
+//!     |commit_log
+//!     |consume_queue
+//!     |ready_queue
+//!     |config
+//!     |start_offset
+//!
+//! 后台任务只持有存储实例的弱引用，存储实例释放后任务退出，同一进程中可以打开多个不同数据目录的实例
+
+use crate::common::config::Config;
+use crate::cust_error::Error;
+use crate::file_util::file_path;
+use crate::storage::commit_log::{self, CommitLog};
+use crate::storage::consume_queue::{self, ConsumeQueue};
+use crate::storage::consumer_offset::{self, ConsumerOffsets};
+use crate::storage::ready_queue::{self, ReadyQueue};
+use crate::storage::start_offset::START_OFFSET_FILE;
+use crate::storage::topic::{self, Topics};
+use log::{info, warn};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// 元数据存储目录，相对数据目录
+pub(crate) const CONFIG_DIR_NAME: &str = "config";
+
+/// 存储实例
+pub struct Store {
+    config: Config,
+    /// 数据目录
+    dir: PathBuf,
+    pub(crate) commit_log: CommitLog,
+    pub(crate) consume_queue: ConsumeQueue,
+    pub(crate) ready_queue: ReadyQueue,
+    pub(crate) consumer_offset: ConsumerOffsets,
+    pub(crate) topic: Topics,
+}
+
+impl Store {
+    /// 打开数据目录，不存在时创建，缺少索引时根据 commit_log 重建，
+    /// 根据 checkpoint 恢复宕机前未写入索引的消息，恢复未到期的延迟消息并启动后台任务
+    ///
+    /// 需要在 tokio 运行时中调用
+    pub async fn open(config: Config, dir: impl AsRef<Path>) -> Result<Arc<Store>, Error> {
+        let dir = dir.as_ref().to_str().ok_or_else(|| {
+            Error::Config(format!(
+                "数据目录不是有效的 UTF-8 路径：{}",
+                dir.as_ref().display()
+            ))
+        })?;
+        let dir = file_path(dir)?;
+        migrate_start_offset(&dir);
+        info!("打开数据目录：{}", dir.display());
+        consume_queue::migrate_legacy(&dir, &config)?;
+        if consume_queue::index_missing(&dir) {
+            warn!("数据目录缺少 consume_queue，根据 commit_log 重建索引");
+            consume_queue::rebuild(&dir, &config, 0)?;
+        } else {
+            consume_queue::recover(&dir, &config)?;
+        }
+        let (commit_log, put_task) = CommitLog::open(&config, &dir)?;
+        let (consume_queue, schedule_task) = ConsumeQueue::open(&config, &dir)?;
+        let store = Arc::new(Store {
+            commit_log,
+            consume_queue,
+            ready_queue: ReadyQueue::new(&config, &dir)?,
+            consumer_offset: ConsumerOffsets::load(&dir)?,
+            topic: Topics::load(&dir)?,
+            config,
+            dir,
+        });
+        put_task.spawn(Arc::downgrade(&store));
+        schedule_task.spawn(Arc::downgrade(&store));
+        consume_queue::init(&store).await?;
+        consumer_offset::flush_task(&store);
+        consume_queue::checkpoint_task(&store);
+        ready_queue::redelivery_task(&store);
+        topic::retention_task(&store);
+        Ok(store)
+    }
+
+    /// 创建存储实例时传入的配置
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// 数据目录
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+/// 旧版本的 start_offset 存储在工作目录下，数据目录中已有 commit_log 但缺少 start_offset 时从上级目录复制
+fn migrate_start_offset(dir: &Path) {
+    let target = dir.join(START_OFFSET_FILE);
+    if target.exists() {
+        return;
+    }
+    let Some(legacy) = dir.parent().map(|parent| parent.join(START_OFFSET_FILE)) else {
+        return;
+    };
+    if !legacy.exists() || !dir.join(commit_log::DIR_NAME).exists() {
+        return;
+    }
+    match fs::copy(&legacy, &target) {
+        Ok(_) => info!("迁移旧版本的 start_offset：{}", legacy.display()),
+        Err(err) => warn!("迁移旧版本的 start_offset 失败：{err}"),
+    }
+}
@@ -0,0 +1,600 @@
+//! topic 的队列与元数据，每个 topic 由多个队列组成，消息写入时选择其中一个
+//!
+//! 带有分片键的消息按分片键的 hash 选择队列，保证相同分片键的消息在同一个队列中有序，其余消息轮询选择队列
+//!
+//! topic 可以通过管理命令创建并指定队列数量、保留时长与最大延迟，元数据持久化在数据目录下的 config/topics.json
+//!
+//! 未创建的 topic 在第一次写入时自动出现，使用配置文件中的默认值
+//!
+//! 设置了保留时长的 topic 由定时任务删除过期的索引文件，commit_log 中的消息不会删除
+
+use crate::common::config::Config;
+use crate::common::cust_error::{Error, TopicError};
+use crate::common::time_util::now_millis;
+use crate::data_process_util::str_hashcode;
+use crate::file_util::file_path;
+use crate::storage::message::Message;
+use crate::storage::ready_queue;
+use crate::storage::store::{Store, CONFIG_DIR_NAME};
+use crate::storage::{commit_log, consume_queue};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// 系统 topic 的前缀，重试、死信与延迟级别的 topic 都以此开头
+const SYSTEM_TOPIC_PREFIX: &str = "%";
+/// 元数据存储文件名
+const TOPIC_FILE: &str = "topics.json";
+/// 已删除 topic 的记录文件名，topic -> 删除时 commit_log 的写入位置
+const DELETED_FILE: &str = "deleted_topics.json";
+/// 索引文件过期删除的记录文件名，topic -> 之前的消息不再需要索引的 commit_log 位置
+const EXPIRED_FILE: &str = "expired_topics.json";
+
+/// 存储实例的 topic 元数据
+pub(crate) struct Topics {
+    /// 每个 topic 下一次轮询选择的队列
+    round_robin: Mutex<HashMap<String, u32>>,
+    /// 已创建的 topic 的元数据
+    topics: Mutex<HashMap<String, TopicConfig>>,
+    /// 元数据存储文件路径
+    path: PathBuf,
+}
+
+impl Topics {
+    /// 从数据目录加载 topic 元数据
+    pub(crate) fn load(dir: &Path) -> Result<Self, Error> {
+        let path = file_path(&dir.join(CONFIG_DIR_NAME).to_string_lossy())?.join(TOPIC_FILE);
+        Ok(Topics {
+            round_robin: Mutex::new(HashMap::new()),
+            topics: Mutex::new(load(&path)),
+            path,
+        })
+    }
+}
+
+/// topic 的设置，未指定的设置在创建时使用配置文件中的默认值
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TopicConfig {
+    /// 队列数量
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queue_count: Option<u32>,
+    /// 消息的保留时长 秒，0 表示永久保留
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention_time: Option<u32>,
+    /// 最大延迟时间 秒
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_delay_time: Option<u32>,
+}
+
+impl TopicConfig {
+    /// 使用配置文件中的默认值补全未指定的设置
+    pub fn with_defaults(self, config: &Config) -> Self {
+        TopicConfig {
+            queue_count: self.queue_count.or(Some(config.queue_count)),
+            retention_time: self.retention_time.or(Some(config.retention_time)),
+            max_delay_time: self.max_delay_time.or(Some(config.max_delay_time)),
+        }
+    }
+}
+
+/// topic 的设置与消息数量
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TopicInfo {
+    pub topic: String,
+    #[serde(flatten)]
+    pub config: TopicConfig,
+    /// 是否通过管理命令创建，自动出现的 topic 使用默认设置
+    pub created: bool,
+    /// 尚未到期的消息数量
+    pub pending: u64,
+    /// 最早的到期时间 毫秒时间戳，没有未到期的消息时为 None
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_due: Option<u64>,
+    /// 已到期写入 ready_queue 的消息数量
+    pub delivered: u64,
+}
+
+/// 从磁盘加载 topic 元数据
+fn load(path: &Path) -> HashMap<String, TopicConfig> {
+    match fs::read_to_string(path) {
+        Ok(json) => serde_json::from_str(&json).unwrap_or_else(|err| {
+            error!("解析 topic 元数据文件错误 \n{:?},返回默认值", err);
+            HashMap::new()
+        }),
+        Err(_) => HashMap::new(),
+    }
+}
+
+/// 将 topic 元数据写入磁盘，先写临时文件再替换，避免写入中途宕机损坏文件
+fn persist(path: &Path, topics: &impl Serialize) {
+    let json = serde_json::to_string_pretty(topics).unwrap();
+    let tmp = path.with_extension("json.tmp");
+    if let Err(err) = fs::write(&tmp, json).and_then(|_| fs::rename(&tmp, path)) {
+        error!("持久化 topic 元数据错误 \n{:?}", err);
+    }
+}
+
+/// 已删除的 topic 及删除时 commit_log 的写入位置，topic 删除前写入的消息及其重试消息不再需要索引
+///
+/// 索引文件过期删除的 topic 同样记录之前的消息不再需要索引的位置，只包括 topic 自身的消息
+///
+/// 检查与重建索引时据此跳过已删除的消息，而不是根据索引目录是否存在判断
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct Tombstones {
+    deleted: HashMap<String, u64>,
+    expired: HashMap<String, u64>,
+}
+
+impl Tombstones {
+    /// 读取数据目录下的删除记录，只读取文件，不会创建目录
+    pub(crate) fn load(dir: &Path) -> Self {
+        let dir = dir.join(CONFIG_DIR_NAME);
+        Tombstones {
+            deleted: load_positions(&dir.join(DELETED_FILE)),
+            expired: load_positions(&dir.join(EXPIRED_FILE)),
+        }
+    }
+
+    /// 物理偏移量为 physical_offset 的 topic 消息是否已随 topic 删除或索引已过期删除
+    pub(crate) fn covers(&self, topic: &str, physical_offset: u64) -> bool {
+        let deleted = self.deleted.iter().any(|(deleted, position)| {
+            physical_offset < *position && ready_queue::belongs_to_topic(topic, deleted)
+        });
+        deleted
+            || self
+                .expired
+                .get(topic)
+                .is_some_and(|position| physical_offset < *position)
+    }
+
+    /// topic 是否被删除过，删除后重新出现的 topic 同样返回 true
+    pub(crate) fn is_deleted(&self, topic: &str) -> bool {
+        self.deleted
+            .keys()
+            .any(|deleted| ready_queue::belongs_to_topic(topic, deleted))
+    }
+}
+
+/// 读取 topic -> commit_log 位置的记录文件，文件不存在或解析失败时返回空
+fn load_positions(path: &Path) -> HashMap<String, u64> {
+    match fs::read_to_string(path) {
+        Ok(json) => serde_json::from_str(&json).unwrap_or_else(|err| {
+            error!("解析记录文件错误 {} \n{:?},返回默认值", path.display(), err);
+            HashMap::new()
+        }),
+        Err(_) => HashMap::new(),
+    }
+}
+
+/// 记录删除的 topic，position 为删除时 commit_log 的写入位置
+fn record_tombstone(store: &Store, topic: &str, position: u64) {
+    let mut tombstones = Tombstones::load(store.dir());
+    let deleted = tombstones.deleted.entry(topic.to_string()).or_default();
+    *deleted = (*deleted).max(position);
+    let path = store.topic.path.with_file_name(DELETED_FILE);
+    persist(&path, &tombstones.deleted);
+}
+
+/// 记录 topic 在 position 之前的消息索引已过期删除
+fn record_expired(store: &Store, topic: &str, position: u64) {
+    let mut tombstones = Tombstones::load(store.dir());
+    let expired = tombstones.expired.entry(topic.to_string()).or_default();
+    *expired = (*expired).max(position);
+    let path = store.topic.path.with_file_name(EXPIRED_FILE);
+    persist(&path, &tombstones.expired);
+}
+
+/// 是否是系统 topic
+pub fn is_system_topic(topic: &str) -> bool {
+    topic.starts_with(SYSTEM_TOPIC_PREFIX)
+}
+
+/// topic 的设置，未创建的 topic 使用默认设置
+pub fn config(store: &Store, topic: &str) -> TopicConfig {
+    store
+        .topic
+        .topics
+        .lock()
+        .unwrap()
+        .get(topic)
+        .cloned()
+        .unwrap_or_default()
+        .with_defaults(store.config())
+}
+
+/// topic 的队列数量，系统 topic 只有一个队列
+pub fn queue_count(store: &Store, topic: &str) -> u32 {
+    if is_system_topic(topic) {
+        1
+    } else {
+        config(store, topic).queue_count.unwrap_or(1).max(1)
+    }
+}
+
+/// topic 的最大延迟时间 毫秒
+pub fn max_delay_millis(store: &Store, topic: &str) -> u64 {
+    config(store, topic).max_delay_time.unwrap_or_default() as u64 * 1000
+}
+
+/// topic 的消息保留时长 毫秒，0 表示永久保留
+pub fn retention_millis(store: &Store, topic: &str) -> u64 {
+    config(store, topic).retention_time.unwrap_or_default() as u64 * 1000
+}
+
+/// 为写入的消息选择队列
+pub fn select_queue(store: &Store, message: &Message) -> u32 {
+    let count = queue_count(store, &message.topic);
+    if let Some(sharding_key) = message.sharding_key() {
+        return (str_hashcode(sharding_key) % count as u64) as u32;
+    }
+    let mut round_robin = store.topic.round_robin.lock().unwrap();
+    let next = round_robin.entry(message.topic.clone()).or_default();
+    let queue_id = *next % count;
+    *next = (queue_id + 1) % count;
+    queue_id
+}
+
+/// 校验 topic 名称能否作为存储目录，系统 topic 中包含消费者组名称，同样需要校验
+pub(crate) fn check_path(topic: &str) -> Result<(), TopicError> {
+    let invalid = topic.is_empty() || topic.contains(['/', '\\']) || topic == "." || topic == "..";
+    if invalid {
+        Err(TopicError::InvalidName(topic.to_string()))
+    } else {
+        Ok(())
+    }
+}
+
+/// 校验客户端使用的 topic 名称，系统 topic 只能由服务端写入
+pub(crate) fn check_name(topic: &str) -> Result<(), TopicError> {
+    if is_system_topic(topic) {
+        return Err(TopicError::InvalidName(topic.to_string()));
+    }
+    check_path(topic)
+}
+
+/// 创建 topic，已自动出现但未创建的 topic 可以创建以指定设置
+pub fn create(store: &Store, topic: &str, config: TopicConfig) -> Result<(), TopicError> {
+    check_name(topic)?;
+    if config.queue_count == Some(0) {
+        return Err(TopicError::InvalidConfig("队列数量必须大于0".to_string()));
+    }
+    if config.max_delay_time == Some(0) {
+        return Err(TopicError::InvalidConfig(
+            "最大延迟时间必须大于0".to_string(),
+        ));
+    }
+    let config = config.with_defaults(store.config());
+    let mut topics = store.topic.topics.lock().unwrap();
+    if topics.contains_key(topic) {
+        return Err(TopicError::Exists(topic.to_string()));
+    }
+    // 自动出现的 topic 已按默认设置写入了队列，减少队列数量会使多出的队列不再被消费
+    let existing = consume_queue::existing_queue_count(store, topic)
+        .max(ready_queue::existing_queue_count(store, topic));
+    if config.queue_count.unwrap_or_default() < existing {
+        return Err(TopicError::InvalidConfig(format!(
+            "队列数量不能少于已存在的队列数量 {existing}"
+        )));
+    }
+    info!("创建 topic：{topic} {config:?}");
+    topics.insert(topic.to_string(), config);
+    persist(&store.topic.path, &*topics);
+    Ok(())
+}
+
+/// 所有已创建或自动出现的 topic 名称，不包括系统 topic
+fn names(store: &Store) -> BTreeSet<String> {
+    let mut names = store
+        .topic
+        .topics
+        .lock()
+        .unwrap()
+        .keys()
+        .cloned()
+        .collect::<BTreeSet<_>>();
+    names.extend(consume_queue::topics(store));
+    names.extend(ready_queue::topics(store));
+    names.retain(|topic| !is_system_topic(topic));
+    names
+}
+
+/// 查看 topic 的设置与消息数量，统计需要读取索引文件
+pub fn describe(store: &Store, topic: &str) -> Option<TopicInfo> {
+    if !names(store).contains(topic) {
+        return None;
+    }
+    Some(info(store, topic, &consume_queue::level_pending(store)))
+}
+
+/// topic 的设置与消息数量，levels 为延迟级别队列中各 topic 未到期的消息
+fn info(store: &Store, topic: &str, levels: &HashMap<String, (u64, Option<u64>)>) -> TopicInfo {
+    let created = store.topic.topics.lock().unwrap().contains_key(topic);
+    let (pending, next_due) = consume_queue::pending_with(store, topic, levels);
+    TopicInfo {
+        topic: topic.to_string(),
+        created,
+        config: config(store, topic),
+        pending,
+        next_due,
+        delivered: ready_queue::delivered_count(store, topic),
+    }
+}
+
+/// 列出所有 topic 的设置与消息数量，延迟级别队列只读取一次
+pub fn list(store: &Store) -> Vec<TopicInfo> {
+    let levels = consume_queue::level_pending(store);
+    names(store)
+        .iter()
+        .map(|topic| info(store, topic, &levels))
+        .collect()
+}
+
+/// 删除 topic 的元数据、索引文件与消费进度，未到期的消息不再投递
+///
+/// commit_log 中的消息不会删除，删除的位置记录在 config/deleted_topics.json 中，检查与重建索引时跳过之前的消息
+pub async fn delete(store: &Store, topic: &str) -> Result<(), TopicError> {
+    check_name(topic)?;
+    if !names(store).contains(topic) {
+        return Err(TopicError::NotFound(topic.to_string()));
+    }
+    record_tombstone(store, topic, commit_log::max_offset(store));
+    consume_queue::remove_topic(store, topic).await;
+    ready_queue::remove_topic(store, topic).await;
+    store.topic.round_robin.lock().unwrap().remove(topic);
+    let mut topics = store.topic.topics.lock().unwrap();
+    if topics.remove(topic).is_some() {
+        persist(&store.topic.path, &*topics);
+    }
+    info!("删除 topic：{topic}");
+    Ok(())
+}
+
+/// 删除设置了保留时长的 topic 中过期的索引文件，now 为当前的毫秒时间戳
+///
+/// 延迟消息的索引文件中所有消息都已到期且到期时间超过保留时长后删除，
+/// ready_queue 中到期时间超过保留时长的消息无论是否已消费都会删除，消费者组的消费进度随之跳过
+pub(crate) async fn clean_expired(store: &Store, now: u64) {
+    for topic in names(store) {
+        let retention = retention_millis(store, &topic);
+        if retention == 0 {
+            continue;
+        }
+        let expire_before = now.saturating_sub(retention);
+        if let Some(position) = consume_queue::clean_expired(store, &topic, expire_before).await {
+            record_expired(store, &topic, position);
+        }
+        ready_queue::clean_expired(store, &topic, expire_before).await;
+    }
+}
+
+/// 启动定时删除过期索引文件的任务，存储实例释放后任务退出
+pub(crate) fn retention_task(store: &Arc<Store>) {
+    let interval = Duration::from_millis(store.config().retention_check_interval);
+    let store = Arc::downgrade(store);
+    tokio::spawn(async move {
+        info!("过期索引文件定时删除，间隔：{interval:?}");
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let Some(store) = store.upgrade() else {
+                break;
+            };
+            clean_expired(&store, now_millis()).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::config::Config;
+    use crate::common::cust_error::TopicError;
+    use crate::common::time_util::now_millis;
+    use crate::file_util::temp_dir;
+    use crate::storage::commit_log::put_message;
+    use crate::storage::filter::MessageFilter;
+    use crate::storage::inspect::verify;
+    use crate::storage::message::Message;
+    use crate::storage::store::Store;
+    use crate::storage::topic::{
+        clean_expired, create, delete, describe, max_delay_millis, queue_count, select_queue,
+        Tombstones, TopicConfig,
+    };
+    use crate::storage::{consume_queue, ready_queue};
+    use std::path::Path;
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn test_select_queue() {
+        let store = Store::open(Config::new().unwrap(), temp_dir("select_queue"))
+            .await
+            .unwrap();
+        let count = queue_count(&store, "test_select_queue");
+        assert_eq!(count, store.config().queue_count);
+        assert_eq!(queue_count(&store, "%RETRY%g1%topic_oms"), 1);
+
+        let message = Message::new("test_select_queue", "", "");
+        let selected = (0..count)
+            .map(|_| select_queue(&store, &message))
+            .collect::<Vec<_>>();
+        assert_eq!(selected, (0..count).collect::<Vec<_>>());
+
+        let message = Message::new("test_select_queue", "", "_sharding_key-order_1");
+        let queue_id = select_queue(&store, &message);
+        assert!(queue_id < count);
+        assert!((0..10).all(|_| select_queue(&store, &message) == queue_id));
+    }
+
+    #[tokio::test]
+    async fn test_create_delete() {
+        let store = Store::open(Config::new().unwrap(), temp_dir("create_delete"))
+            .await
+            .unwrap();
+        let topic = "test_create_delete";
+        let config = TopicConfig {
+            queue_count: Some(2),
+            retention_time: Some(72 * 3600),
+            max_delay_time: Some(60),
+        };
+        create(&store, topic, config.clone()).unwrap();
+        assert_eq!(
+            create(&store, topic, config.clone()),
+            Err(TopicError::Exists(topic.to_string()))
+        );
+        assert_eq!(
+            create(&store, "%DLQ%g1", TopicConfig::default()),
+            Err(TopicError::InvalidName("%DLQ%g1".to_string()))
+        );
+        let zero_delay = TopicConfig {
+            max_delay_time: Some(0),
+            ..TopicConfig::default()
+        };
+        assert!(matches!(
+            create(&store, "test_zero_delay", zero_delay),
+            Err(TopicError::InvalidConfig(_))
+        ));
+        assert_eq!(queue_count(&store, topic), 2);
+        assert_eq!(max_delay_millis(&store, topic), 60_000);
+
+        let info = describe(&store, topic).unwrap();
+        assert_eq!(info.config, config);
+        assert!(info.created);
+        assert_eq!((info.pending, info.delivered), (0, 0));
+        assert_eq!(info.next_due, None);
+
+        delete(&store, topic).await.unwrap();
+        assert_eq!(describe(&store, topic), None);
+        assert_eq!(queue_count(&store, topic), store.config().queue_count);
+        assert_eq!(
+            delete(&store, topic).await,
+            Err(TopicError::NotFound(topic.to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_existing_queues() {
+        let store = Store::open(Config::new().unwrap(), temp_dir("create_existing_queues"))
+            .await
+            .unwrap();
+        let topic = "test_create_existing_queues";
+        let count = store.config().queue_count;
+        for _ in 0..count {
+            put_message(&store, Message::new(topic, "a", "_delay-60"))
+                .await
+                .unwrap();
+        }
+        let config = |queue_count| TopicConfig {
+            queue_count: Some(queue_count),
+            ..TopicConfig::default()
+        };
+        assert!(matches!(
+            create(&store, topic, config(count - 1)),
+            Err(TopicError::InvalidConfig(_))
+        ));
+        create(&store, topic, config(count + 1)).unwrap();
+        assert_eq!(queue_count(&store, topic), count + 1);
+    }
+
+    /// 目录中的文件数量
+    fn file_count(dir: &Path) -> usize {
+        std::fs::read_dir(dir).map_or(0, |files| files.count())
+    }
+
+    /// 等待 topic 的消息全部到期写入 ready_queue
+    async fn wait_delivered(store: &Store, topic: &str, count: u64) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while ready_queue::delivered_count(store, topic) < count {
+            assert!(Instant::now() < deadline, "消息未到期：{topic}");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retention() {
+        let store = Store::open(Config::new().unwrap(), temp_dir("retention"))
+            .await
+            .unwrap();
+        let config = TopicConfig {
+            queue_count: Some(1),
+            retention_time: Some(1),
+            ..TopicConfig::default()
+        };
+        // 每个文件存储 7 条索引，20 条消息占用 3 个文件
+        let (topic, pending_topic) = ("test_retention", "test_retention_pending");
+        create(&store, topic, config.clone()).unwrap();
+        create(&store, pending_topic, config).unwrap();
+        let first = put_message(&store, Message::new(topic, "a", "_delay_ms-1"))
+            .await
+            .unwrap();
+        for _ in 1..20 {
+            put_message(&store, Message::new(topic, "a", "_delay_ms-1"))
+                .await
+                .unwrap();
+        }
+        // 未到期的消息所在的文件不能删除
+        put_message(&store, Message::new(pending_topic, "a", "_delay-60"))
+            .await
+            .unwrap();
+        for _ in 1..14 {
+            put_message(&store, Message::new(pending_topic, "a", "_delay_ms-1"))
+                .await
+                .unwrap();
+        }
+        wait_delivered(&store, topic, 20).await;
+        wait_delivered(&store, pending_topic, 13).await;
+        let filter = MessageFilter::default();
+        let pulled = ready_queue::pull(&store, topic, "g1", "c1", 3, &filter);
+        assert_eq!(pulled.len(), 3);
+
+        let queue_dir = |base: &str, topic: &str| store.dir().join(base).join(topic).join("0");
+        let files = |topic| {
+            (
+                file_count(&queue_dir(consume_queue::BASE_DIR_NAME, topic)),
+                file_count(&queue_dir(ready_queue::BASE_DIR_NAME, topic)),
+            )
+        };
+        assert_eq!(files(topic), (3, 3));
+        assert_eq!(files(pending_topic), (2, 2));
+        // 到期时间未超过保留时长
+        clean_expired(&store, now_millis()).await;
+        assert_eq!(files(topic), (3, 3));
+
+        clean_expired(&store, now_millis() + 2000).await;
+        assert_eq!(files(topic), (1, 1));
+        assert_eq!(files(pending_topic), (2, 1));
+        assert!(Tombstones::load(store.dir()).covers(topic, first));
+        assert!(!Tombstones::load(store.dir()).covers(pending_topic, 0));
+        assert!(verify(store.dir()).unwrap().is_empty());
+
+        // 已拉取与未拉取的消费者组都从剩余的第一个文件开始消费
+        let remain = 20 - 14;
+        assert_eq!(
+            ready_queue::pull(&store, topic, "g1", "c1", 10, &filter).len(),
+            remain
+        );
+        assert_eq!(
+            ready_queue::pull(&store, topic, "g2", "c1", 10, &filter).len(),
+            remain
+        );
+        assert_eq!(
+            ready_queue::pull(&store, pending_topic, "g1", "c1", 20, &filter).len(),
+            13 - 7
+        );
+
+        // 重启后不会重新生成已删除的索引
+        let dir = store.dir().to_path_buf();
+        drop(store);
+        let store = Store::open(Config::new().unwrap(), &dir).await.unwrap();
+        assert_eq!(
+            file_count(&dir.join(consume_queue::BASE_DIR_NAME).join(topic).join("0")),
+            1
+        );
+        assert_eq!(
+            ready_queue::pull(&store, topic, "g3", "c1", 10, &filter).len(),
+            remain
+        );
+    }
+}